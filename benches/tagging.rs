@@ -0,0 +1,55 @@
+//! Per-record cost of `Tagger::tag` with reused buffers.
+//!
+//! Run with `cargo bench --bench tagging`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rust_htslib::bam;
+use tagbam::read_structure::ReadStructure;
+use tagbam::tag::{QualFailAction, QualMetric, TagBuffers, Tagger};
+
+const NAME: &[u8] = b"2efc6b85-aa0d-4c1d-ab33-bf5f442fe47c_TTGGCTCC-GGTCGGCG-ACTTGA_GAAGCAGT";
+
+fn record() -> bam::Record {
+    let bases = [b'A', b'C', b'G', b'T'];
+    let seq: Vec<u8> = (0..90).map(|i| bases[i % 4]).collect();
+    let qual = vec![30u8; seq.len()];
+    let mut record = bam::Record::new();
+    record.set(NAME, None, &seq, &qual);
+    record
+}
+
+fn tagger(read_structure: Option<&ReadStructure>) -> Tagger<'_> {
+    Tagger {
+        bq_map: None,
+        read_structure,
+        read_starts: None,
+        trim: None,
+        min_cb_qual: Some(20),
+        min_umi_qual: Some(20),
+        qual_metric: QualMetric::Min,
+        qual_fail: QualFailAction::Untag,
+        skip_unparseable: false,
+    }
+}
+
+fn bench_tag(c: &mut Criterion) {
+    let template = record();
+    let structure: ReadStructure = "6C8M+T".parse().unwrap();
+
+    for (label, tagger) in [
+        ("perfect_quality", tagger(None)),
+        ("read_structure", tagger(Some(&structure))),
+    ] {
+        let mut buffers = TagBuffers::default();
+        c.bench_function(&format!("tag/{}", label), |b| {
+            b.iter_batched_ref(
+                || template.clone(),
+                |record| tagger.tag(record, &mut buffers).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+criterion_group!(benches, bench_tag);
+criterion_main!(benches);
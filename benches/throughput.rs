@@ -0,0 +1,69 @@
+//! Tagging throughput (reads/sec) for increasing `--tag-threads`.
+//!
+//! Run with `cargo bench --bench throughput`; set `TAGBAM_BENCH_READS` to
+//! change the number of synthetic reads (default 2,000,000).
+
+use rust_htslib::bam;
+use std::path::Path;
+use std::process::Command;
+use std::time::Instant;
+
+fn write_input(path: &Path, n_reads: usize) {
+    let mut header = bam::Header::new();
+    let mut hd = bam::header::HeaderRecord::new(b"HD");
+    hd.push_tag(b"VN", "1.6").push_tag(b"SO", "unsorted");
+    header.push_record(&hd);
+    let mut sq = bam::header::HeaderRecord::new(b"SQ");
+    sq.push_tag(b"SN", "chr1").push_tag(b"LN", "100000000");
+    header.push_record(&sq);
+
+    let mut writer = bam::Writer::from_path(path, &header, bam::Format::Bam).unwrap();
+    writer.set_threads(4).unwrap();
+    let bases = [b'A', b'C', b'G', b'T'];
+    let seq: Vec<u8> = (0..90).map(|i| bases[i % 4]).collect();
+    let qual = vec![30u8; seq.len()];
+    for i in 0..n_reads {
+        let barcode: String = (0..6).map(|j| bases[(i >> (2 * j)) % 4] as char).collect();
+        let name = format!(
+            "{:08x}-aa0d-4c1d-ab33-bf5f442fe47c_TTGGCTCC-GGTCGGCG-{}_GAAGCAGT",
+            i, barcode
+        );
+        let mut record = bam::Record::new();
+        record.set(name.as_bytes(), None, &seq, &qual);
+        writer.write(&record).unwrap();
+    }
+}
+
+fn main() {
+    let n_reads: usize = std::env::var("TAGBAM_BENCH_READS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(2_000_000);
+    let dir = tempfile::TempDir::new().unwrap();
+    let input = dir.path().join("input.bam");
+    let output = dir.path().join("output.bam");
+    write_input(&input, n_reads);
+
+    let max_threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let mut thread_counts = vec![1];
+    while thread_counts.last().unwrap() * 2 <= max_threads {
+        thread_counts.push(thread_counts.last().unwrap() * 2);
+    }
+
+    println!("{:>12} {:>14}", "tag-threads", "reads/sec");
+    for threads in thread_counts {
+        let start = Instant::now();
+        let status = Command::new(env!("CARGO_BIN_EXE_tagbam"))
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .args(["--threads", "4", "--tag-threads", &threads.to_string()])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "tagbam failed with {} threads", threads);
+        let rate = n_reads as f64 / start.elapsed().as_secs_f64();
+        println!("{:>12} {:>14.0}", threads, rate);
+    }
+}
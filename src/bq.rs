@@ -0,0 +1,1421 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::Read as BamRead;
+use rust_htslib::tpool::ThreadPool;
+use rust_htslib::{bam, bgzf};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io::{BufRead, BufReader, BufWriter, Read as IoRead, Write as IoWrite};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::{fs::File, str};
+
+use crate::cache::{
+    read_optional_string, read_string, read_u64, write_bytes, write_optional_string, write_u64,
+};
+
+pub(crate) const BQ_CACHE_MAGIC: &[u8; 8] = b"TBQMAP03";
+
+/// Bytes of FASTQ handed to a parse worker at a time.
+const CHUNK_SIZE: usize = 4 << 20;
+
+/// Independently locked parts of the map while it is built.
+const SHARDS: usize = 64;
+
+/// Parsed barcode/UMI qualities from a BQ token.
+///
+/// Stored as validated strings so tags can be written without re-checking UTF-8.
+#[derive(Debug, Clone)]
+pub struct BqQuals {
+    pub cb: String,
+    pub umi: Option<String>,
+    /// Qualities for [`BqSchema::extra`], in the same order
+    pub extra: Box<[Option<String>]>,
+}
+
+/// A `|BQ:` token label whose qualities are written to their own BAM tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraQual {
+    pub label: String,
+    pub tag: [u8; 2],
+}
+
+impl FromStr for ExtraQual {
+    type Err = String;
+
+    /// Parse `LABEL:TAG`, e.g. `R2BC:QX`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (label, tag) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected LABEL:TAG, found '{}'", s))?;
+        if label.is_empty() || label.contains(';') {
+            return Err(format!("Invalid BQ label '{}'", label));
+        }
+        Ok(Self {
+            label: label.to_string(),
+            tag: crate::tag::parse_tag_name(tag)?,
+        })
+    }
+}
+
+/// Labels of the `|BQ:` token used for CY, UY and any extra tags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BqSchema {
+    /// Labels concatenated, in order, into CY
+    pub cy_labels: Vec<String>,
+    pub umi_label: String,
+    /// Build CY from the labels present instead of skipping tokens that lack some
+    pub partial: bool,
+    pub extra: Vec<ExtraQual>,
+}
+
+impl Default for BqSchema {
+    fn default() -> Self {
+        Self {
+            cy_labels: vec!["i7".to_string(), "i5".to_string(), "CBC".to_string()],
+            umi_label: "UMI".to_string(),
+            partial: false,
+            extra: Vec::new(),
+        }
+    }
+}
+
+/// File type of a BQ source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BqFormat {
+    /// FASTQ with `|BQ:` tokens in the headers
+    Fastq,
+    /// `read_name<TAB>cb_qual[<TAB>umi_qual]` lines
+    Tsv,
+    /// BAM/uBAM with CY and UY (or QX/BZ) tags
+    Bam,
+}
+
+impl BqFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Fastq => "FASTQ",
+            Self::Tsv => "TSV",
+            Self::Bam => "BAM",
+        }
+    }
+
+    /// What positions in [`MalformedRecords`] and errors count.
+    pub fn position_unit(self) -> &'static str {
+        match self {
+            Self::Fastq | Self::Tsv => "line",
+            Self::Bam => "record",
+        }
+    }
+
+    /// Guess the format from the first decompressed bytes of `path`.
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut reader = bgzf::Reader::from_path(path)
+            .with_context(|| format!("Failed to open BQ source: {:?}", path))?;
+        let mut start = [0u8; 4];
+        let mut len = 0;
+        while len < start.len() {
+            let n = reader
+                .read(&mut start[len..])
+                .with_context(|| format!("Failed to read BQ source: {:?}", path))?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+        Ok(match &start[..len] {
+            b"BAM\x01" => Self::Bam,
+            [b'@', ..] => Self::Fastq,
+            _ => Self::Tsv,
+        })
+    }
+}
+
+impl BqSchema {
+    /// Stored in caches, which are only valid for the schema they were built with.
+    fn fingerprint(&self) -> String {
+        let extra: Vec<String> = self
+            .extra
+            .iter()
+            .map(|e| format!("{}:{}", e.label, String::from_utf8_lossy(&e.tag)))
+            .collect();
+        format!(
+            "cy={};umi={};partial={};extra={}",
+            self.cy_labels.join(","),
+            self.umi_label,
+            self.partial,
+            extra.join(",")
+        )
+    }
+
+    /// Refuse a non-default schema for sources without `|BQ:` labels.
+    fn check_format(&self, format: BqFormat) -> Result<()> {
+        if format != BqFormat::Fastq && *self != Self::default() {
+            anyhow::bail!(
+                "--bq-cy-labels, --bq-umi-label, --bq-partial and --bq-tag only apply to FASTQ BQ sources, not {}",
+                format.name()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Which record wins when a read name occurs more than once in the FASTQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicateNames {
+    /// Keep the first record
+    First,
+    /// Keep the last record
+    Last,
+    /// Stop with an error
+    Error,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Header line of the record (or position in a cache), ordering duplicates
+    line: u64,
+    quals: BqQuals,
+}
+
+type Shard = HashMap<String, Entry>;
+
+/// Source records skipped because they could not be used.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MalformedRecords {
+    pub count: u64,
+    /// Position (FASTQ header line, TSV line or BAM record) and reason of the earliest one
+    pub first: Option<(u64, String)>,
+}
+
+impl MalformedRecords {
+    fn add(&mut self, line: u64, reason: String) {
+        self.count += 1;
+        self.keep_earliest(line, reason);
+    }
+
+    fn merge(&mut self, other: MalformedRecords) {
+        self.count += other.count;
+        if let Some((line, reason)) = other.first {
+            self.keep_earliest(line, reason);
+        }
+    }
+
+    fn keep_earliest(&mut self, line: u64, reason: String) {
+        if self.first.as_ref().is_none_or(|(first, _)| line < *first) {
+            self.first = Some((line, reason));
+        }
+    }
+}
+
+/// Read name -> barcode/UMI qualities, split into shards by name hash so
+/// parse workers can insert concurrently.
+#[derive(Debug)]
+pub struct BqMap {
+    format: BqFormat,
+    schema: BqSchema,
+    /// How repeated names were resolved
+    policy: DuplicateNames,
+    hasher: RandomState,
+    shards: Vec<Shard>,
+    malformed: MalformedRecords,
+    duplicates: u64,
+}
+
+impl BqMap {
+    fn new(format: BqFormat, schema: BqSchema, policy: DuplicateNames) -> Self {
+        Self {
+            format,
+            schema,
+            policy,
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| HashMap::new()).collect(),
+            malformed: MalformedRecords::default(),
+            duplicates: 0,
+        }
+    }
+
+    fn shard_of(&self, name: &[u8]) -> usize {
+        (self.hasher.hash_one(name) % SHARDS as u64) as usize
+    }
+
+    /// Qualities for a BAM read name, ignoring a `/1` or `/2` mate suffix.
+    pub fn get(&self, name: &str) -> Option<&BqQuals> {
+        let name = &name[..strip_mate_suffix(name.as_bytes()).len()];
+        self.shards[self.shard_of(name.as_bytes())]
+            .get(name)
+            .map(|entry| &entry.quals)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(HashMap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(HashMap::is_empty)
+    }
+
+    pub fn format(&self) -> BqFormat {
+        self.format
+    }
+
+    /// Tags for [`BqQuals::extra`], in the same order.
+    pub fn extra_tags(&self) -> impl Iterator<Item = [u8; 2]> + '_ {
+        self.schema.extra.iter().map(|extra| extra.tag)
+    }
+
+    /// Records skipped while parsing the FASTQ; always empty when loaded from a cache.
+    pub fn malformed(&self) -> &MalformedRecords {
+        &self.malformed
+    }
+
+    /// FASTQ records whose read name had already been seen; zero when loaded from a cache.
+    pub fn n_duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&str, &BqQuals)> {
+        self.shards
+            .iter()
+            .flatten()
+            .map(|(name, entry)| (name.as_str(), &entry.quals))
+    }
+
+    fn insert(&mut self, name: String, line: u64, quals: BqQuals) {
+        let shard = self.shard_of(name.as_bytes());
+        self.shards[shard].insert(name, Entry { line, quals });
+    }
+
+    /// Insert from a serial loader, resolving repeated names by `policy`.
+    fn insert_with(
+        &mut self,
+        name: String,
+        line: u64,
+        quals: BqQuals,
+        policy: DuplicateNames,
+    ) -> Result<()> {
+        let shard = self.shard_of(name.as_bytes());
+        let entry = Entry { line, quals };
+        if insert_entry(&mut self.shards[shard], name, entry, policy, self.format)? {
+            self.duplicates += 1;
+        }
+        Ok(())
+    }
+
+    /// Written to caches, which are only valid for the source format, schema
+    /// and duplicate policy they were built with.
+    fn fingerprint(&self) -> String {
+        cache_fingerprint(self.format, &self.schema, self.policy)
+    }
+}
+
+fn cache_fingerprint(format: BqFormat, schema: &BqSchema, policy: DuplicateNames) -> String {
+    format!(
+        "format={:?};duplicates={:?};{}",
+        format,
+        policy,
+        schema.fingerprint()
+    )
+}
+
+/// Add `entry` to `shard`, resolving a repeated name by `policy`.
+///
+/// Returns whether the name was a duplicate. Entries may arrive out of order, so
+/// first and last are decided by line number.
+fn insert_entry(
+    shard: &mut Shard,
+    name: String,
+    entry: Entry,
+    policy: DuplicateNames,
+    format: BqFormat,
+) -> Result<bool> {
+    let Some(existing) = shard.get_mut(&name) else {
+        shard.insert(name, entry);
+        return Ok(false);
+    };
+    let keep_new = match policy {
+        DuplicateNames::First => entry.line < existing.line,
+        DuplicateNames::Last => entry.line > existing.line,
+        DuplicateNames::Error => anyhow::bail!(
+            "Read name '{}' occurs more than once in the BQ {} ({}s {} and {})",
+            name,
+            format.name(),
+            format.position_unit(),
+            existing.line.min(entry.line),
+            existing.line.max(entry.line)
+        ),
+    };
+    if keep_new {
+        *existing = entry;
+    }
+    Ok(true)
+}
+
+struct FastqReader {
+    reader: bgzf::Reader,
+    _tpool: Option<ThreadPool>,
+}
+
+impl FastqReader {
+    fn from_path(path: &Path, threads: usize) -> Result<Self> {
+        let mut reader = bgzf::Reader::from_path(path)
+            .with_context(|| format!("Failed to open FASTQ: {:?}", path))?;
+        let tpool = if threads > 1 {
+            let is_bgzip = bgzf::is_bgzip(path)
+                .with_context(|| format!("Failed to detect bgzip FASTQ: {:?}", path))?;
+            if is_bgzip {
+                let thread_count =
+                    u32::try_from(threads).context("FASTQ thread count exceeds u32")?;
+                let tpool =
+                    ThreadPool::new(thread_count).context("Failed to create FASTQ thread pool")?;
+                reader
+                    .set_thread_pool(&tpool)
+                    .context("Failed to set FASTQ thread pool")?;
+                Some(tpool)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        Ok(Self {
+            reader,
+            _tpool: tpool,
+        })
+    }
+}
+
+impl IoRead for FastqReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Parse `|BQ:` token from a FASTQ header: CY qualities concatenated from the
+/// schema's labels, plus the UMI and extra qualities if present.
+///
+/// Returns `Ok(None)` without a token and an error for a token that cannot be used.
+fn parse_bq_token(header: &[u8], schema: &BqSchema) -> Result<Option<BqQuals>, String> {
+    let Some(bq_start) = find(header, b"|BQ:") else {
+        return Ok(None);
+    };
+    let token = &header[bq_start + 4..];
+    let token_end = token
+        .iter()
+        .position(u8::is_ascii_whitespace)
+        .unwrap_or(token.len());
+    let token = &token[..token_end];
+
+    let as_str =
+        |qual| str::from_utf8(qual).map_err(|_| "BQ token qualities are not ASCII".to_string());
+    let mut cb = String::new();
+    let mut n_present = 0;
+    for label in &schema.cy_labels {
+        match label_quals(token, label) {
+            Some(qual) => {
+                cb.push_str(as_str(qual)?);
+                n_present += 1;
+            }
+            None if schema.partial => {}
+            None => return Err(format!("BQ token lacks '{}' qualities", label)),
+        }
+    }
+    if n_present == 0 {
+        return Err("BQ token has none of the CY labels".to_string());
+    }
+
+    let umi = match label_quals(token, &schema.umi_label) {
+        Some(qual) => Some(as_str(qual)?.to_string()),
+        None => None,
+    };
+    let extra = schema
+        .extra
+        .iter()
+        .map(|extra| match label_quals(token, &extra.label) {
+            Some(qual) => Ok(Some(as_str(qual)?.to_string())),
+            None => Ok(None),
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(Some(BqQuals { cb, umi, extra }))
+}
+
+/// Qualities of the first `label:qual` part of a BQ token with this label.
+fn label_quals<'a>(token: &'a [u8], label: &str) -> Option<&'a [u8]> {
+    token
+        .split(|&b| b == b';')
+        .find_map(|part| part.strip_prefix(label.as_bytes())?.strip_prefix(b":"))
+}
+
+/// Read name of a FASTQ header line: the first word, without `@` or a `/1`/`/2`
+/// mate suffix. Casava comments such as ` 1:N:0:ACGT` follow the first word and
+/// are dropped with it.
+fn header_name(header: &[u8]) -> &[u8] {
+    let header = header.strip_prefix(b"@").unwrap_or(header);
+    let start = header
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(header.len());
+    let header = &header[start..];
+    let end = header
+        .iter()
+        .position(u8::is_ascii_whitespace)
+        .unwrap_or(header.len());
+    strip_mate_suffix(&header[..end])
+}
+
+fn strip_mate_suffix(name: &[u8]) -> &[u8] {
+    name.strip_suffix(b"/1")
+        .or_else(|| name.strip_suffix(b"/2"))
+        .unwrap_or(name)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// A FASTQ record whose sequence and quality may each span several lines.
+struct RawRecord<'a> {
+    /// 1-based line number of the header
+    line: u64,
+    header: &'a [u8],
+    seq_len: usize,
+    qual_len: usize,
+}
+
+/// Splits FASTQ bytes into records, checking their structure and counting lines
+/// so errors can point at the offending line.
+struct FastqScanner<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Lines consumed so far, including those before `data`
+    line: u64,
+    /// Whether `data` runs to the end of the file, so a partial record is truncation
+    eof: bool,
+}
+
+impl<'a> FastqScanner<'a> {
+    fn new(data: &'a [u8], first_line: u64, eof: bool) -> Self {
+        Self {
+            data,
+            pos: 0,
+            line: first_line,
+            eof,
+        }
+    }
+
+    /// Next line without its terminator; a final line without `\n` only counts at EOF.
+    fn next_line(&mut self) -> Option<&'a [u8]> {
+        let rest = self.data.get(self.pos..).filter(|rest| !rest.is_empty())?;
+        let (line, advance) = match rest.iter().position(|&b| b == b'\n') {
+            Some(i) => (&rest[..i], i + 1),
+            None if self.eof => (rest, rest.len()),
+            None => return None,
+        };
+        self.pos += advance;
+        self.line += 1;
+        Some(line.strip_suffix(b"\r").unwrap_or(line))
+    }
+
+    /// Next complete record, or `None` at the end of the data. Before EOF an
+    /// incomplete trailing record is left unconsumed for the next read.
+    fn next_record(&mut self) -> Result<Option<RawRecord<'a>>> {
+        let (pos, line) = (self.pos, self.line);
+        let record = self.scan_record()?;
+        if record.is_none() {
+            (self.pos, self.line) = (pos, line);
+        }
+        Ok(record)
+    }
+
+    fn scan_record(&mut self) -> Result<Option<RawRecord<'a>>> {
+        // Blank lines between records are ignored
+        let header = loop {
+            match self.next_line() {
+                Some([]) => continue,
+                Some(line) => break line,
+                None => return Ok(None),
+            }
+        };
+        let line = self.line;
+        if header[0] != b'@' {
+            anyhow::bail!(
+                "FASTQ line {}: expected a header starting with '@', found '{}'",
+                line,
+                String::from_utf8_lossy(&header[..header.len().min(40)])
+            );
+        }
+
+        // Sequence lines run up to the '+' separator
+        let mut seq_len = 0;
+        loop {
+            match self.next_line() {
+                Some([b'+', ..]) => break,
+                Some([b'@', ..]) => anyhow::bail!(
+                    "FASTQ line {}: expected a '+' separator for the record at line {}",
+                    self.line,
+                    line
+                ),
+                Some(bases) => seq_len += bases.len(),
+                None => return self.incomplete(line),
+            }
+        }
+
+        // Quality lines run until they cover the sequence; at least one is always read
+        let mut qual_len = 0;
+        loop {
+            match self.next_line() {
+                Some(quals) => qual_len += quals.len(),
+                None => return self.incomplete(line),
+            }
+            if qual_len >= seq_len {
+                break;
+            }
+        }
+
+        Ok(Some(RawRecord {
+            line,
+            header,
+            seq_len,
+            qual_len,
+        }))
+    }
+
+    fn incomplete(&self, line: u64) -> Result<Option<RawRecord<'a>>> {
+        if self.eof {
+            anyhow::bail!(
+                "FASTQ is truncated: the record at line {} is incomplete",
+                line
+            );
+        }
+        Ok(None)
+    }
+}
+
+/// A run of whole FASTQ records and the number of lines before it.
+struct Chunk {
+    first_line: u64,
+    data: Vec<u8>,
+}
+
+/// Entries parsed from one chunk, grouped by shard.
+type ChunkEntries = Vec<Vec<(String, Entry)>>;
+
+/// Parse every record of `chunk`, grouping entries by shard.
+fn parse_chunk(chunk: &Chunk, map: &BqMap) -> Result<(ChunkEntries, MalformedRecords)> {
+    let mut by_shard: ChunkEntries = (0..SHARDS).map(|_| Vec::new()).collect();
+    let mut malformed = MalformedRecords::default();
+    let mut scanner = FastqScanner::new(&chunk.data, chunk.first_line, true);
+    while let Some(raw) = scanner.next_record()? {
+        match parse_record(&raw, &map.schema) {
+            Ok(Some((name, quals))) => {
+                let entry = Entry {
+                    line: raw.line,
+                    quals,
+                };
+                by_shard[map.shard_of(name.as_bytes())].push((name, entry));
+            }
+            Ok(None) => {}
+            Err(reason) => malformed.add(raw.line, reason),
+        }
+    }
+    Ok((by_shard, malformed))
+}
+
+/// Name and qualities of a record with a `|BQ:` token, or why it cannot be used.
+fn parse_record(raw: &RawRecord, schema: &BqSchema) -> Result<Option<(String, BqQuals)>, String> {
+    if raw.seq_len != raw.qual_len {
+        return Err(format!(
+            "quality length {} differs from sequence length {}",
+            raw.qual_len, raw.seq_len
+        ));
+    }
+    let Some(quals) = parse_bq_token(raw.header, schema)? else {
+        return Ok(None);
+    };
+    let name = str::from_utf8(header_name(raw.header))
+        .map_err(|_| "read name is not valid UTF-8".to_string())?;
+    if name.is_empty() {
+        return Err("empty read name".to_string());
+    }
+    Ok(Some((name.to_string(), quals)))
+}
+
+/// End of the last complete record in `data`, found by resyncing on `\n@`.
+///
+/// A candidate header counts only if it starts a four-line record: the line
+/// after its sequence starts with '+' and the quality line has the sequence's
+/// length, so quality lines starting with '@' are skipped. Only sound for
+/// four-line FASTQ, since wrapped quality lines may start with '@' and '+'.
+fn last_record_end(data: &[u8]) -> Option<usize> {
+    let mut end = data.len();
+    while let Some(at) = data[..end].iter().rposition(|&b| b == b'@') {
+        end = at;
+        if at > 0 && data[at - 1] != b'\n' {
+            continue;
+        }
+        let mut lines = data[at..].split(|&b| b == b'\n');
+        let (Some(header), Some(seq), Some(plus), Some(qual), Some(_)) = (
+            lines.next(),
+            lines.next(),
+            lines.next(),
+            lines.next(),
+            lines.next(),
+        ) else {
+            continue;
+        };
+        let trim = |line: &'_ [u8]| line.strip_suffix(b"\r").unwrap_or(line).len();
+        if plus.first() == Some(&b'+') && trim(seq) == trim(qual) {
+            return Some(at + header.len() + seq.len() + plus.len() + qual.len() + 4);
+        }
+    }
+    None
+}
+
+/// Whether every record in `data` takes exactly four lines, or `None` if it
+/// holds no complete record.
+fn is_four_line(data: &[u8], eof: bool) -> Result<Option<bool>> {
+    let mut scanner = FastqScanner::new(data, 0, eof);
+    let mut any = false;
+    while let Some(raw) = scanner.next_record()? {
+        if scanner.line != raw.line + 3 {
+            return Ok(Some(false));
+        }
+        any = true;
+    }
+    Ok(any.then_some(true))
+}
+
+/// Length of the prefix of `data` holding complete records and the line count
+/// after them.
+///
+/// With `resync`, boundaries come from a cheap resync and workers validate the
+/// structure; otherwise, e.g. for wrapped input, the records are scanned here.
+fn complete_records(data: &[u8], first_line: u64, eof: bool, resync: bool) -> Result<(usize, u64)> {
+    let end = if eof {
+        Some(data.len())
+    } else if resync {
+        last_record_end(data)
+    } else {
+        None
+    };
+    if let Some(end) = end {
+        let lines = data[..end].iter().filter(|&&b| b == b'\n').count() as u64;
+        return Ok((end, first_line + lines));
+    }
+    let mut scanner = FastqScanner::new(data, first_line, eof);
+    while scanner.next_record()?.is_some() {}
+    Ok((scanner.pos, scanner.line))
+}
+
+/// Read `reader` into chunks of whole records and send them to `chunks`.
+fn read_chunks<R: IoRead>(
+    mut reader: R,
+    chunk_size: usize,
+    chunks: mpsc::SyncSender<Chunk>,
+) -> Result<()> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(chunk_size);
+    let mut first_line = 0;
+    let mut eof = false;
+    // Decided from the first buffer: resyncing is only used for four-line FASTQ
+    let mut resync = None;
+    while !eof {
+        let filled = buffer.len();
+        buffer.resize(filled.max(chunk_size / 2) * 2, 0);
+        let mut len = filled;
+        while len < buffer.len() {
+            let n = reader
+                .read(&mut buffer[len..])
+                .context("Failed reading FASTQ")?;
+            if n == 0 {
+                eof = true;
+                break;
+            }
+            len += n;
+        }
+        buffer.truncate(len);
+
+        if resync.is_none() {
+            resync = is_four_line(&buffer, eof)?;
+        }
+        let (end, n_lines) = complete_records(&buffer, first_line, eof, resync == Some(true))?;
+        // A single record larger than the buffer: read more before cutting
+        if end == 0 && !eof {
+            continue;
+        }
+        let rest = buffer.split_off(end);
+        let data = std::mem::replace(&mut buffer, rest);
+        let chunk = Chunk { first_line, data };
+        if chunks.send(chunk).is_err() {
+            // Workers stopped early; their error is reported by the caller
+            return Ok(());
+        }
+        first_line = n_lines;
+    }
+    Ok(())
+}
+
+/// Load FASTQ into memory and build a map: read name -> parsed barcode/UMI qualities.
+///
+/// Decompression uses the bgzf thread pool for bgzip input, while whole-record
+/// chunks are parsed on `threads` workers that insert into the map's shards.
+pub fn load_bq_map(
+    fastq_path: &Path,
+    schema: &BqSchema,
+    threads: usize,
+    duplicates: DuplicateNames,
+) -> Result<BqMap> {
+    load_bq_map_chunked(fastq_path, schema, threads, duplicates, CHUNK_SIZE)
+}
+
+/// Load BQ qualities from a TSV (plain, gzip or bgzip) of
+/// `read_name<TAB>cb_qual[<TAB>umi_qual]` lines.
+///
+/// Blank lines, `#` comments and a `read_name` header line are skipped.
+pub fn load_bq_tsv(path: &Path, schema: &BqSchema, duplicates: DuplicateNames) -> Result<BqMap> {
+    let reader = bgzf::Reader::from_path(path)
+        .with_context(|| format!("Failed to open BQ TSV: {:?}", path))?;
+    let mut reader = BufReader::new(reader);
+    let mut map = BqMap::new(BqFormat::Tsv, schema.clone(), duplicates);
+    let mut line = Vec::new();
+    let mut line_no = 0;
+    loop {
+        line.clear();
+        let n = reader
+            .read_until(b'\n', &mut line)
+            .with_context(|| format!("Failed to read BQ TSV: {:?}", path))?;
+        if n == 0 {
+            break;
+        }
+        line_no += 1;
+        let text = line.strip_suffix(b"\n").unwrap_or(&line);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        if text.is_empty() || text.starts_with(b"#") || text.starts_with(b"read_name\t") {
+            continue;
+        }
+        match parse_tsv_line(text, schema) {
+            Ok((name, quals)) => map.insert_with(name, line_no, quals, duplicates)?,
+            Err(reason) => map.malformed.add(line_no, reason),
+        }
+    }
+    Ok(map)
+}
+
+fn parse_tsv_line(line: &[u8], schema: &BqSchema) -> Result<(String, BqQuals), String> {
+    let line = str::from_utf8(line).map_err(|_| "line is not valid UTF-8".to_string())?;
+    let mut fields = line.split('\t');
+    let (Some(name), Some(cb)) = (fields.next(), fields.next()) else {
+        return Err("expected read_name and cb_qual columns".to_string());
+    };
+    let name = &name[..strip_mate_suffix(name.as_bytes()).len()];
+    if name.is_empty() || cb.is_empty() {
+        return Err("empty read name or cb_qual".to_string());
+    }
+    let umi = fields.next().filter(|umi| !umi.is_empty());
+    Ok((
+        name.to_string(),
+        BqQuals {
+            cb: cb.to_string(),
+            umi: umi.map(str::to_string),
+            extra: vec![None; schema.extra.len()].into(),
+        },
+    ))
+}
+
+/// Load BQ qualities from the CY and UY tags of a BAM or unaligned BAM.
+///
+/// UMI qualities fall back to QX or BZ, which the SAM spec defines for the RX
+/// and OX UMI sequences; records without CY are skipped.
+///
+/// Only primary records are read, and of a pair only the first mate, since
+/// mates carry the same barcode qualities.
+pub fn load_bq_bam(
+    path: &Path,
+    schema: &BqSchema,
+    threads: usize,
+    duplicates: DuplicateNames,
+) -> Result<BqMap> {
+    let mut reader = bam::Reader::from_path(path)
+        .with_context(|| format!("Failed to open BQ BAM: {:?}", path))?;
+    reader.set_threads(threads.max(1))?;
+    let mut map = BqMap::new(BqFormat::Bam, schema.clone(), duplicates);
+    let mut record = bam::Record::new();
+    let mut record_no = 0;
+    while let Some(result) = reader.read(&mut record) {
+        result.with_context(|| format!("Failed to read BQ BAM: {:?}", path))?;
+        record_no += 1;
+        if record.is_secondary()
+            || record.is_supplementary()
+            || (record.is_paired() && record.is_last_in_template())
+        {
+            continue;
+        }
+        let string_tag = |tags: &[&[u8; 2]]| {
+            tags.iter().find_map(|tag| match record.aux(*tag) {
+                Ok(Aux::String(value)) => Some(value.to_string()),
+                _ => None,
+            })
+        };
+        let Some(cb) = string_tag(&[b"CY"]) else {
+            continue;
+        };
+        let umi = string_tag(&[b"UY", b"QX", b"BZ"]);
+        let Ok(name) = str::from_utf8(record.qname()) else {
+            map.malformed
+                .add(record_no, "read name is not valid UTF-8".to_string());
+            continue;
+        };
+        let quals = BqQuals {
+            cb,
+            umi,
+            extra: vec![None; schema.extra.len()].into(),
+        };
+        map.insert_with(name.to_string(), record_no, quals, duplicates)?;
+    }
+    Ok(map)
+}
+
+fn load_bq_map_chunked(
+    fastq_path: &Path,
+    schema: &BqSchema,
+    threads: usize,
+    policy: DuplicateNames,
+    chunk_size: usize,
+) -> Result<BqMap> {
+    let reader = FastqReader::from_path(fastq_path, threads)?;
+    let workers = threads.max(1);
+    let map = BqMap::new(BqFormat::Fastq, schema.clone(), policy);
+    let shards: Vec<Mutex<Shard>> = map
+        .shards
+        .iter()
+        .map(|_| Mutex::new(HashMap::new()))
+        .collect();
+    let malformed = Mutex::new(MalformedRecords::default());
+    let duplicates = AtomicU64::new(0);
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<Chunk>(workers * 2);
+    // Taken by the first worker that fails, so the reader stops sending
+    let chunk_rx = Mutex::new(Some(chunk_rx));
+
+    std::thread::scope(|scope| -> Result<()> {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                let (chunk_rx, shards, malformed, duplicates, map) =
+                    (&chunk_rx, &shards, &malformed, &duplicates, &map);
+                let work = move || -> Result<()> {
+                    loop {
+                        let queue = chunk_rx.lock().expect("chunk queue lock poisoned");
+                        let Some(Ok(chunk)) = queue.as_ref().map(mpsc::Receiver::recv) else {
+                            return Ok(());
+                        };
+                        drop(queue);
+                        let (by_shard, chunk_malformed) = parse_chunk(&chunk, map)?;
+                        if chunk_malformed.count > 0 {
+                            malformed
+                                .lock()
+                                .expect("malformed count lock poisoned")
+                                .merge(chunk_malformed);
+                        }
+                        for (shard, entries) in by_shard.into_iter().enumerate() {
+                            if entries.is_empty() {
+                                continue;
+                            }
+                            let mut shard = shards[shard].lock().expect("shard lock poisoned");
+                            let mut n_duplicates = 0;
+                            for (name, entry) in entries {
+                                n_duplicates += u64::from(insert_entry(
+                                    &mut shard,
+                                    name,
+                                    entry,
+                                    policy,
+                                    BqFormat::Fastq,
+                                )?);
+                            }
+                            duplicates.fetch_add(n_duplicates, Ordering::Relaxed);
+                        }
+                    }
+                };
+                scope.spawn(move || {
+                    let result = work();
+                    if result.is_err() {
+                        chunk_rx.lock().expect("chunk queue lock poisoned").take();
+                    }
+                    result
+                })
+            })
+            .collect();
+
+        let read_result = read_chunks(reader, chunk_size, chunk_tx);
+        for handle in handles {
+            handle.join().expect("FASTQ parse worker panicked")?;
+        }
+        read_result
+    })?;
+
+    Ok(BqMap {
+        shards: shards
+            .into_iter()
+            .map(|shard| shard.into_inner().expect("shard lock poisoned"))
+            .collect(),
+        malformed: malformed
+            .into_inner()
+            .expect("malformed count lock poisoned"),
+        duplicates: duplicates.into_inner(),
+        ..map
+    })
+}
+
+/// Parse a BQ source of the given format.
+pub fn load_bq_source(
+    source_path: &Path,
+    format: BqFormat,
+    schema: &BqSchema,
+    threads: usize,
+    duplicates: DuplicateNames,
+) -> Result<BqMap> {
+    schema.check_format(format)?;
+    match format {
+        BqFormat::Fastq => load_bq_map(source_path, schema, threads, duplicates),
+        BqFormat::Tsv => load_bq_tsv(source_path, schema, duplicates),
+        BqFormat::Bam => load_bq_bam(source_path, schema, threads, duplicates),
+    }
+}
+
+/// Load the BQ map from `cache_path` if it exists and was built from the same
+/// format and schema; otherwise parse `source_path` and (re)write the cache.
+pub fn load_bq_map_with_cache(
+    source_path: &Path,
+    cache_path: Option<&Path>,
+    format: BqFormat,
+    schema: &BqSchema,
+    threads: usize,
+    duplicates: DuplicateNames,
+) -> Result<BqMap> {
+    schema.check_format(format)?;
+    if let Some(cache_path) = cache_path {
+        if cache_path.exists() {
+            let cached = read_bq_cache(cache_path, format, schema, duplicates)
+                .with_context(|| format!("Failed to read BQ cache: {:?}", cache_path))?;
+            match cached {
+                Some(map) => return Ok(map),
+                None => eprintln!(
+                    "Warning: BQ cache {:?} was built by another version, source format, BQ schema or --bq-duplicates policy; rebuilding it",
+                    cache_path
+                ),
+            }
+        }
+    }
+
+    let map = load_bq_source(source_path, format, schema, threads, duplicates)?;
+    if let Some(cache_path) = cache_path {
+        write_bq_cache(cache_path, &map)
+            .with_context(|| format!("Failed to write BQ cache: {:?}", cache_path))?;
+    }
+    Ok(map)
+}
+
+/// Read a cache, or `None` if it is from another version, format, schema or
+/// duplicate policy.
+fn read_bq_cache(
+    cache_path: &Path,
+    format: BqFormat,
+    schema: &BqSchema,
+    policy: DuplicateNames,
+) -> Result<Option<BqMap>> {
+    let file = File::open(cache_path)
+        .with_context(|| format!("Failed to open BQ cache: {:?}", cache_path))?;
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .context("Failed to read BQ cache header")?;
+    if !magic.starts_with(b"TBQMAP") {
+        anyhow::bail!("BQ cache has invalid header");
+    }
+    if &magic != BQ_CACHE_MAGIC {
+        return Ok(None);
+    }
+    let fingerprint = read_string(&mut reader).context("Failed to read BQ cache schema")?;
+    if fingerprint != cache_fingerprint(format, schema, policy) {
+        return Ok(None);
+    }
+
+    let count = read_u64(&mut reader).context("Failed to read BQ cache entry count")?;
+    let mut map = BqMap::new(format, schema.clone(), policy);
+
+    for position in 0..count {
+        let name = read_string(&mut reader).context("Failed to read BQ cache name")?;
+        let cb = read_string(&mut reader).context("Failed to read BQ cache CB qualities")?;
+        let umi =
+            read_optional_string(&mut reader).context("Failed to read BQ cache UMI qualities")?;
+        let extra = schema
+            .extra
+            .iter()
+            .map(|_| read_optional_string(&mut reader))
+            .collect::<Result<_>>()
+            .context("Failed to read BQ cache extra qualities")?;
+
+        map.insert(name, position, BqQuals { cb, umi, extra });
+    }
+
+    Ok(Some(map))
+}
+
+/// Write `map` to `cache_path`, replacing any existing file.
+pub fn write_bq_cache(cache_path: &Path, map: &BqMap) -> Result<()> {
+    let file = File::create(cache_path)
+        .with_context(|| format!("Failed to create BQ cache: {:?}", cache_path))?;
+    let mut writer = BufWriter::new(file);
+
+    writer
+        .write_all(BQ_CACHE_MAGIC)
+        .context("Failed to write BQ cache header")?;
+    write_bytes(&mut writer, map.fingerprint().as_bytes())
+        .context("Failed to write BQ cache schema")?;
+    let count = u64::try_from(map.len()).context("BQ cache entry count exceeds u64")?;
+    write_u64(&mut writer, count).context("Failed to write BQ cache entry count")?;
+
+    for (name, quals) in map.iter() {
+        write_bytes(&mut writer, name.as_bytes()).context("Failed to write BQ cache name")?;
+        write_bytes(&mut writer, quals.cb.as_bytes())
+            .context("Failed to write BQ cache CB qualities")?;
+        write_optional_string(&mut writer, quals.umi.as_deref())
+            .context("Failed to write BQ cache UMI qualities")?;
+        for extra in quals.extra.iter() {
+            write_optional_string(&mut writer, extra.as_deref())
+                .context("Failed to write BQ cache extra qualities")?;
+        }
+    }
+
+    writer.flush().context("Failed to flush BQ cache")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn schema_with_extra(extra: &str) -> BqSchema {
+        BqSchema {
+            extra: vec![extra.parse().unwrap()],
+            ..BqSchema::default()
+        }
+    }
+
+    #[test]
+    fn bq_cache_roundtrip() {
+        let schema = schema_with_extra("R2:QX");
+        let mut map = BqMap::new(BqFormat::Fastq, schema.clone(), DuplicateNames::Last);
+        map.insert(
+            "read1".to_string(),
+            0,
+            BqQuals {
+                cb: "ABC".to_string(),
+                umi: Some("XYZ".to_string()),
+                extra: Box::new([Some("RR".to_string())]),
+            },
+        );
+        map.insert(
+            "read2".to_string(),
+            1,
+            BqQuals {
+                cb: "QQ".to_string(),
+                umi: None,
+                extra: Box::new([None]),
+            },
+        );
+
+        let file = NamedTempFile::new().unwrap();
+        write_bq_cache(file.path(), &map).unwrap();
+        let loaded = read_bq_cache(file.path(), BqFormat::Fastq, &schema, DuplicateNames::Last)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(loaded.len(), map.len());
+        assert_eq!(loaded.get("read1").unwrap().cb, "ABC");
+        assert_eq!(loaded.get("read1").unwrap().umi.as_ref().unwrap(), "XYZ");
+        assert_eq!(loaded.get("read1").unwrap().extra[0].as_deref(), Some("RR"));
+        assert_eq!(loaded.get("read2").unwrap().cb, "QQ");
+        assert!(loaded.get("read2").unwrap().umi.is_none());
+        assert!(loaded.get("read2").unwrap().extra[0].is_none());
+
+        // A cache built with another schema is stale
+        let other = schema_with_extra("R2:QY");
+        assert!(
+            read_bq_cache(file.path(), BqFormat::Fastq, &other, DuplicateNames::Last)
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            read_bq_cache(file.path(), BqFormat::Tsv, &schema, DuplicateNames::Last)
+                .unwrap()
+                .is_none()
+        );
+        // So is one that resolved repeated names differently
+        assert!(
+            read_bq_cache(file.path(), BqFormat::Fastq, &schema, DuplicateNames::First)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn parses_headers_without_allocating_lines() {
+        let header = b"@read1 extra|BQ:i7:AB;i5:CD;CBC:EF;UMI:GH more";
+        assert_eq!(header_name(header), b"read1");
+        let schema = BqSchema::default();
+        let quals = parse_bq_token(header, &schema).unwrap().unwrap();
+        assert_eq!(quals.cb, "ABCDEF");
+        assert_eq!(quals.umi.unwrap(), "GH");
+        assert_eq!(
+            parse_bq_token(b"@read1 |BQ:i7:AB;CBC:EF", &schema).unwrap_err(),
+            "BQ token lacks 'i5' qualities"
+        );
+        assert!(parse_bq_token(b"@read1 plain", &schema).unwrap().is_none());
+    }
+
+    #[test]
+    fn schema_selects_and_orders_labels() {
+        let header = b"@r |BQ:i7:AB;i5i:XX;i5:CD;CBC:EF;UMI:GH;R2BC:JK";
+        let schema = BqSchema {
+            cy_labels: vec!["CBC".to_string(), "i7".to_string()],
+            umi_label: "R2BC".to_string(),
+            extra: vec!["UMI:QX".parse().unwrap(), "S1:QY".parse().unwrap()],
+            ..BqSchema::default()
+        };
+        let quals = parse_bq_token(header, &schema).unwrap().unwrap();
+        assert_eq!(quals.cb, "EFAB");
+        assert_eq!(quals.umi.as_deref(), Some("JK"));
+        assert_eq!(&*quals.extra, [Some("GH".to_string()), None]);
+
+        let partial = BqSchema {
+            cy_labels: vec!["i7".to_string(), "i5".to_string(), "CBC".to_string()],
+            partial: true,
+            ..BqSchema::default()
+        };
+        let quals = parse_bq_token(b"@r |BQ:i5i:XX;i5:CD;CBC:EF", &partial)
+            .unwrap()
+            .unwrap();
+        assert_eq!(quals.cb, "CDEF");
+        assert!(parse_bq_token(b"@r |BQ:UMI:GH", &partial).is_err());
+
+        assert!("R2BC".parse::<ExtraQual>().is_err());
+        assert!("R2BC:Q".parse::<ExtraQual>().is_err());
+    }
+
+    #[test]
+    fn chunked_parse_matches_record_order() {
+        let mut fastq = Vec::new();
+        for i in 0..20_000 {
+            // Later duplicates must win regardless of which worker parses them
+            let name = i % 15_000;
+            let q = if i >= 15_000 { "LATE" } else { "EARLY" };
+            fastq.extend_from_slice(
+                format!("@r{} x|BQ:i7:{};i5:I;CBC:I\nACGT\n+\nIIII\n", name, q).as_bytes(),
+            );
+        }
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &fastq).unwrap();
+
+        for threads in [1, 4] {
+            // Small chunks so records are spread over many workers
+            let map = load_bq_map_chunked(
+                file.path(),
+                &BqSchema::default(),
+                threads,
+                DuplicateNames::Last,
+                4096,
+            )
+            .unwrap();
+            assert_eq!(map.len(), 15_000);
+            assert_eq!(map.get("r0").unwrap().cb, "LATEII");
+            assert_eq!(map.get("r14999").unwrap().cb, "EARLYII");
+            assert_eq!(map.n_duplicates(), 5_000);
+
+            let map = load_bq_map_chunked(
+                file.path(),
+                &BqSchema::default(),
+                threads,
+                DuplicateNames::First,
+                4096,
+            )
+            .unwrap();
+            assert_eq!(map.get("r0").unwrap().cb, "EARLYII");
+        }
+        assert_eq!(
+            complete_records(b"@a\nA\n+\nI\n@b\nC", 0, false, true).unwrap(),
+            (9, 4)
+        );
+    }
+
+    #[test]
+    fn resyncs_past_quality_lines_starting_with_at() {
+        // The quality line "@@" must not be taken for a header
+        assert_eq!(last_record_end(b"@a\nAC\n+\n@@\n@b\nAC\n+\n"), Some(11));
+        assert_eq!(last_record_end(b"@a\nACGT\nAC\n+\nIIIIII\n"), None);
+
+        let mut fastq = String::new();
+        for i in 0..200 {
+            fastq.push_str(&format!("@r{} |BQ:i7:A;i5:B;CBC:{}\nACGT\n+\n@@@@\n", i, i));
+        }
+        for chunk_size in [16, 50, 97, 4096] {
+            let map = load(&fastq, chunk_size).unwrap();
+            assert_eq!(map.len(), 200);
+            assert_eq!(map.get("r199").unwrap().cb, "AB199");
+            assert_eq!(map.malformed().count, 0);
+        }
+    }
+
+    fn load(fastq: &str, chunk_size: usize) -> Result<BqMap> {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), fastq).unwrap();
+        load_bq_map_chunked(
+            file.path(),
+            &BqSchema::default(),
+            1,
+            DuplicateNames::Last,
+            chunk_size,
+        )
+    }
+
+    #[test]
+    fn worker_errors_stop_the_reader() {
+        let good = "@r |BQ:i7:A;i5:B;CBC:C\nACGT\n+\nIIII\n";
+        let mut fastq = good.repeat(10);
+        fastq.push_str("bad\nACGT\n+\nIIII\n");
+        fastq.push_str(&good.repeat(2_000));
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &fastq).unwrap();
+        // With one worker, a failed chunk used to leave the reader blocked on a full queue
+        let err = load_bq_map_chunked(
+            file.path(),
+            &BqSchema::default(),
+            1,
+            DuplicateNames::Last,
+            64,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "FASTQ line 41: expected a header starting with '@', found 'bad'"
+        );
+    }
+
+    #[test]
+    fn wrapped_quality_lines_do_not_resync() {
+        // Wrapped quality lines starting with '@' and '+' look like record boundaries
+        let record = |i| {
+            format!(
+                "@r{} |BQ:i7:A;i5:B;CBC:{}\nAC\nGT\nAC\nGT\nAC\n+\n@I\nII\n+I\nII\nII\n",
+                i, i
+            )
+        };
+        let fastq: String = (0..300).map(record).collect();
+        for chunk_size in [16, 50, 97, 4096] {
+            let map = load(&fastq, chunk_size).unwrap();
+            assert_eq!(map.len(), 300);
+            assert_eq!(map.get("r299").unwrap().cb, "AB299");
+            assert_eq!(map.malformed().count, 0);
+        }
+    }
+
+    #[test]
+    fn parses_wrapped_records() {
+        let fastq =
+            "@r1 |BQ:i7:A;i5:B;CBC:C\nACGT\nAC\n+\n@III\nII\n\n@r2 |BQ:i7:D;i5:E;CBC:F\nA\n+r2\nI";
+        for chunk_size in [8, 4096] {
+            let map = load(fastq, chunk_size).unwrap();
+            assert_eq!(map.len(), 2);
+            assert_eq!(map.get("r2").unwrap().cb, "DEF");
+            assert_eq!(map.malformed().count, 0);
+        }
+    }
+
+    #[test]
+    fn reports_structural_errors_with_line_numbers() {
+        let good = "@r1 |BQ:i7:A;i5:B;CBC:C\nACGT\n+\nIIII\n";
+        let err = load(&format!("{}r2\nACGT\n+\nIIII\n", good), 4096).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "FASTQ line 5: expected a header starting with '@', found 'r2'"
+        );
+
+        let err = load(&format!("{}@r2\nACGT\n@r3\n", good), 4096).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "FASTQ line 7: expected a '+' separator for the record at line 5"
+        );
+
+        for chunk_size in [8, 4096] {
+            let err = load(&format!("{}@r2\nACGT\n+\n", good), chunk_size).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "FASTQ is truncated: the record at line 5 is incomplete"
+            );
+        }
+    }
+
+    #[test]
+    fn counts_malformed_records() {
+        let fastq = "@r1 |BQ:i7:A;i5:B;CBC:C\nACGT\n+\nIIIII\n\
+                     @r2 |BQ:i7:A\nACGT\n+\nIIII\n\
+                     @r3 |BQ:i7:A;i5:B;CBC:C\nACGT\n+\nIIII\n";
+        let map = load(fastq, 4096).unwrap();
+        assert_eq!(map.len(), 1);
+        assert!(map.get("r3").is_some());
+        assert_eq!(
+            map.malformed(),
+            &MalformedRecords {
+                count: 2,
+                first: Some((
+                    1,
+                    "quality length 5 differs from sequence length 4".to_string()
+                )),
+            }
+        );
+    }
+
+    #[test]
+    fn strips_mate_suffixes_and_rejects_duplicates_on_request() {
+        let fastq = "@r1/1 1:N:0:ACGT|BQ:i7:A;i5:B;CBC:C\nACGT\n+\nIIII\n\
+                     @r1/2 2:N:0:ACGT|BQ:i7:D;i5:E;CBC:F\nACGT\n+\nIIII\n";
+        let map = load(fastq, 4096).unwrap();
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.n_duplicates(), 1);
+        assert_eq!(map.get("r1").unwrap().cb, "DEF");
+        assert_eq!(map.get("r1/1").unwrap().cb, "DEF");
+
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), fastq).unwrap();
+        let err =
+            load_bq_map(file.path(), &BqSchema::default(), 1, DuplicateNames::Error).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Read name 'r1' occurs more than once in the BQ FASTQ (lines 1 and 5)"
+        );
+    }
+
+    #[test]
+    fn loads_tsv_sources() {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            "read_name\tcb_qual\tumi_qual\n# comment\nr1/1\tABC\tXY\nr2\tDEF\n\nr3\n",
+        )
+        .unwrap();
+        assert_eq!(BqFormat::detect(file.path()).unwrap(), BqFormat::Tsv);
+
+        let map = load_bq_tsv(file.path(), &BqSchema::default(), DuplicateNames::Last).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("r1").unwrap().cb, "ABC");
+        assert_eq!(map.get("r1").unwrap().umi.as_deref(), Some("XY"));
+        assert!(map.get("r2").unwrap().umi.is_none());
+        assert_eq!(map.malformed().count, 1);
+        assert_eq!(map.malformed().first.as_ref().unwrap().0, 6);
+    }
+}
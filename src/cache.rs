@@ -0,0 +1,165 @@
+//! Little-endian, length-prefixed primitives shared by the on-disk caches.
+//!
+//! Every cache starts with an 8-byte magic, the options it was built with and
+//! its entry count.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use crate::bq::BQ_CACHE_MAGIC;
+use crate::transfer::TAG_CACHE_MAGIC;
+
+/// Which option a cache belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    /// `--bq-cache`
+    Bq,
+    /// `--tags-cache`
+    Tags,
+}
+
+/// A cache's header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheInfo {
+    pub kind: CacheKind,
+    /// False for caches written by another version, which are rebuilt on use
+    pub current: bool,
+    /// Source format and options the cache was built with, if current
+    pub built_with: Option<String>,
+    pub entries: Option<u64>,
+}
+
+/// Read the header of a BQ or tag cache.
+pub fn inspect(path: &Path) -> Result<CacheInfo> {
+    let file = File::open(path).with_context(|| format!("Failed to open cache: {:?}", path))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .with_context(|| format!("Failed to read cache header: {:?}", path))?;
+
+    let (kind, current) = match &magic[..6] {
+        b"TBQMAP" => (CacheKind::Bq, &magic == BQ_CACHE_MAGIC),
+        b"TBQTAG" => (CacheKind::Tags, &magic == TAG_CACHE_MAGIC),
+        _ => anyhow::bail!("{:?} is not a tagbam cache", path),
+    };
+    if !current {
+        return Ok(CacheInfo {
+            kind,
+            current,
+            built_with: None,
+            entries: None,
+        });
+    }
+
+    let options = read_bytes(&mut reader).context("Failed to read cache options")?;
+    let built_with = match kind {
+        CacheKind::Bq => String::from_utf8(options).context("Cache contains non-UTF8 text")?,
+        CacheKind::Tags => {
+            let tags: Vec<String> = options
+                .chunks(2)
+                .map(|tag| String::from_utf8_lossy(tag).into_owned())
+                .collect();
+            format!("tags={}", tags.join(","))
+        }
+    };
+    let entries = read_u64(&mut reader).context("Failed to read cache entry count")?;
+    Ok(CacheInfo {
+        kind,
+        current,
+        built_with: Some(built_with),
+        entries: Some(entries),
+    })
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub(crate) fn write_u64<W: Write>(writer: &mut W, value: u64) -> Result<()> {
+    writer.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub(crate) fn write_u8<W: Write>(writer: &mut W, value: u8) -> Result<()> {
+    writer.write_all(&[value])?;
+    Ok(())
+}
+
+pub(crate) fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = read_u64(reader)?;
+    let len_usize = usize::try_from(len).context("Cache length exceeds usize")?;
+    let mut buf = vec![0u8; len_usize];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub(crate) fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    String::from_utf8(read_bytes(reader)?).context("Cache contains non-UTF8 text")
+}
+
+/// A presence byte, followed by the string if present.
+pub(crate) fn read_optional_string<R: Read>(reader: &mut R) -> Result<Option<String>> {
+    match read_u8(reader)? {
+        1 => Ok(Some(read_string(reader)?)),
+        _ => Ok(None),
+    }
+}
+
+pub(crate) fn write_optional_string<W: Write>(writer: &mut W, value: Option<&str>) -> Result<()> {
+    match value {
+        Some(value) => {
+            write_u8(writer, 1)?;
+            write_bytes(writer, value.as_bytes())
+        }
+        None => write_u8(writer, 0),
+    }
+}
+
+pub(crate) fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    let len = u64::try_from(bytes.len()).context("Cache length exceeds u64")?;
+    write_u64(writer, len)?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn inspects_cache_headers() {
+        let cache = NamedTempFile::new().unwrap();
+        let mut data = TAG_CACHE_MAGIC.to_vec();
+        write_bytes(&mut data, b"CBUB").unwrap();
+        write_u64(&mut data, 12).unwrap();
+        std::fs::write(cache.path(), &data).unwrap();
+        assert_eq!(
+            inspect(cache.path()).unwrap(),
+            CacheInfo {
+                kind: CacheKind::Tags,
+                current: true,
+                built_with: Some("tags=CB,UB".to_string()),
+                entries: Some(12),
+            }
+        );
+
+        std::fs::write(cache.path(), b"TBQMAP01rest").unwrap();
+        let info = inspect(cache.path()).unwrap();
+        assert_eq!((info.kind, info.current), (CacheKind::Bq, false));
+
+        std::fs::write(cache.path(), b"@read1\nACGT\n").unwrap();
+        assert!(inspect(cache.path()).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::molecule::Molecule;
+use crate::umi::aux_string;
+
+/// How duplicates are reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicateMode {
+    /// Set the duplicate flag (0x400)
+    Flag,
+    /// Write an integer tag (1 = duplicate, 0 = not) and leave flags untouched
+    Tag,
+}
+
+#[derive(Debug, Default)]
+struct CellCounts {
+    reads: u64,
+    duplicates: u64,
+}
+
+/// Streaming duplicate marker.
+///
+/// The first template seen for each molecule is kept and later ones are marked;
+/// see [`crate::molecule::MoleculeGrouper`] for how molecules are formed.
+pub struct DuplicateMarker {
+    mode: DuplicateMode,
+    tag: [u8; 2],
+    per_cell: BTreeMap<Vec<u8>, CellCounts>,
+    n_examined: u64,
+    n_duplicates: u64,
+}
+
+impl DuplicateMarker {
+    pub fn new(mode: DuplicateMode, tag: [u8; 2]) -> Self {
+        Self {
+            mode,
+            tag,
+            per_cell: BTreeMap::new(),
+            n_examined: 0,
+            n_duplicates: 0,
+        }
+    }
+
+    /// Number of primary reads with `CB` and `UB` that were considered.
+    pub fn n_examined(&self) -> u64 {
+        self.n_examined
+    }
+
+    /// Number of reads marked as duplicates.
+    pub fn n_duplicates(&self) -> u64 {
+        self.n_duplicates
+    }
+
+    /// Mark `record` according to the molecule it was assigned to.
+    pub fn mark(&mut self, record: &mut bam::Record, molecule: Option<Molecule>) -> Result<()> {
+        let Some(molecule) = molecule else {
+            return Ok(());
+        };
+        let duplicate = !molecule.first;
+
+        self.n_examined += 1;
+        let cb = aux_string(record, b"CB").unwrap_or_default().to_vec();
+        let cell = self.per_cell.entry(cb).or_default();
+        cell.reads += 1;
+        if duplicate {
+            self.n_duplicates += 1;
+            cell.duplicates += 1;
+        }
+
+        match self.mode {
+            DuplicateMode::Flag if duplicate => record.set_duplicate(),
+            // Clear a flag left by an earlier run or another tool
+            DuplicateMode::Flag => record.unset_duplicate(),
+            DuplicateMode::Tag => {
+                // Replace the tag left by an earlier run
+                if record.aux(&self.tag).is_ok() {
+                    record
+                        .remove_aux(&self.tag)
+                        .context("Failed to remove duplicate tag")?;
+                }
+                record
+                    .push_aux(&self.tag, bam::record::Aux::U8(u8::from(duplicate)))
+                    .context("Failed to add duplicate tag")?
+            }
+        }
+        Ok(())
+    }
+
+    /// Write per-cell read and duplicate counts as TSV.
+    pub fn write_stats(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create duplicate stats: {:?}", path))?;
+        let mut writer = std::io::BufWriter::new(file);
+        writeln!(writer, "cell\treads\tduplicates\tduplication_rate")?;
+        for (cell, counts) in &self.per_cell {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{:.4}",
+                String::from_utf8_lossy(cell),
+                counts.reads,
+                counts.duplicates,
+                counts.duplicates as f64 / counts.reads as f64
+            )?;
+        }
+        writer.flush().context("Failed to flush duplicate stats")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+
+    #[test]
+    fn tag_mode_replaces_existing_tag() {
+        let mut record = bam::Record::new();
+        record.set(b"r1", None, b"ACGT", &[30; 4]);
+        record.push_aux(b"CB", Aux::String("AACC")).unwrap();
+        record.push_aux(b"DT", Aux::U8(1)).unwrap();
+
+        let mut marker = DuplicateMarker::new(DuplicateMode::Tag, *b"DT");
+        let molecule = Molecule { id: 0, first: true };
+        marker.mark(&mut record, Some(molecule)).unwrap();
+        assert_eq!(record.aux(b"DT").unwrap(), Aux::U8(0));
+        assert_eq!(record.aux_iter().count(), 2);
+        assert_eq!(marker.n_duplicates(), 0);
+    }
+}
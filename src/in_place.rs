@@ -0,0 +1,304 @@
+//! Replacing the input BAM with the tagged output.
+//!
+//! Output goes to a hidden temp file next to the input, which is removed if the
+//! run fails, panics or is stopped by SIGINT/SIGTERM, and is synced to disk
+//! before it is renamed over the input.
+
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// Extra space, as a fraction of the input size, assumed for added tags.
+const GROWTH_ALLOWANCE: f64 = 0.2;
+
+/// Temp file removed by the signal handler; a C string owned through this
+/// pointer, as the handler cannot take locks. Null while no run is pending.
+static SIGNAL_TEMP_PATH: AtomicPtr<libc::c_char> = AtomicPtr::new(std::ptr::null_mut());
+
+/// An in-place run in progress; dropping it before [`InPlace::commit`]
+/// removes the temp file.
+#[derive(Debug)]
+pub struct InPlace {
+    input: PathBuf,
+    temp: PathBuf,
+    backup: Option<PathBuf>,
+    committed: bool,
+}
+
+impl InPlace {
+    /// Check that `input` can be replaced safely and reserve its temp file path.
+    ///
+    /// Symlinked or hard-linked inputs and a lack of free space are refused
+    /// unless `force` is set.
+    pub fn prepare(input: &Path, backup_suffix: Option<&str>, force: bool) -> Result<Self> {
+        let metadata = fs::symlink_metadata(input)
+            .with_context(|| format!("Failed to read input file: {:?}", input))?;
+        if !force {
+            if metadata.file_type().is_symlink() {
+                anyhow::bail!(
+                    "Input {:?} is a symlink; in-place tagging would replace the link with a regular file and leave its target untouched (use --force to proceed)",
+                    input
+                );
+            }
+            if metadata.nlink() > 1 {
+                anyhow::bail!(
+                    "Input {:?} has {} hard links; in-place tagging would leave the other links with the old contents (use --force to proceed)",
+                    input,
+                    metadata.nlink()
+                );
+            }
+        }
+
+        let file_name = input
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Cannot determine input file name"))?
+            .to_string_lossy();
+        let dir = match input.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let temp = dir.join(format!(".{}.tmp", file_name));
+        let backup = backup_suffix.map(|suffix| dir.join(format!("{}{}", file_name, suffix)));
+        if let Some(backup) = backup.as_ref() {
+            if backup.exists() {
+                anyhow::bail!("Backup {:?} already exists", backup);
+            }
+        }
+
+        let needed = estimated_output_size(fs::metadata(input)?.len());
+        let available = available_space(dir)?;
+        if needed > available && !force {
+            anyhow::bail!(
+                "Not enough free space in {:?} for in-place tagging: about {} needed, {} available (use --force to proceed)",
+                dir,
+                format_bytes(needed),
+                format_bytes(available)
+            );
+        }
+
+        remove_temp_on_signal(&temp)?;
+        Ok(Self {
+            input: input.to_path_buf(),
+            temp,
+            backup,
+            committed: false,
+        })
+    }
+
+    /// Where the tagged output is written until [`InPlace::commit`].
+    pub fn temp_path(&self) -> &Path {
+        &self.temp
+    }
+
+    /// Sync the finished temp file, keep the original as the backup if one
+    /// was requested, and rename the temp file over the input.
+    pub fn commit(mut self) -> Result<()> {
+        File::open(&self.temp)
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("Failed to sync {:?} to disk", self.temp))?;
+
+        if let Some(backup) = self.backup.as_ref() {
+            // A hard link keeps the original without copying it
+            if fs::hard_link(&self.input, backup).is_err() {
+                fs::copy(&self.input, backup).with_context(|| {
+                    format!("Failed to back up {:?} to {:?}", self.input, backup)
+                })?;
+            }
+        }
+        fs::rename(&self.temp, &self.input)
+            .context("Failed to replace input file with tagged version")?;
+        self.committed = true;
+        clear_signal_handler();
+
+        // Make the rename itself durable
+        if let Some(dir) = self.input.parent().filter(|d| !d.as_os_str().is_empty()) {
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("Failed to sync directory {:?}", dir))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for InPlace {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+        clear_signal_handler();
+    }
+}
+
+/// Input size plus an allowance for the added tags.
+fn estimated_output_size(input_size: u64) -> u64 {
+    input_size + (input_size as f64 * GROWTH_ALLOWANCE) as u64
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `dir`.
+fn available_space(dir: &Path) -> Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid directory path: {:?}", dir))?;
+    // SAFETY: `path` is NUL-terminated and `stat` is a valid out-pointer
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to query free space in {:?}", dir));
+    }
+    #[allow(clippy::unnecessary_cast)] // The field types differ between platforms
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Remove `temp` and re-raise when SIGINT or SIGTERM arrives.
+fn remove_temp_on_signal(temp: &Path) -> Result<()> {
+    let path = CString::new(temp.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid temp file path: {:?}", temp))?;
+    let previous = SIGNAL_TEMP_PATH.swap(path.into_raw(), Ordering::SeqCst);
+    free_signal_path(previous);
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only calls async-signal-safe functions
+        unsafe {
+            libc::signal(
+                signal,
+                handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Restore the default SIGINT/SIGTERM actions and free the registered path.
+fn clear_signal_handler() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: resetting to the default action has no preconditions
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+    free_signal_path(SIGNAL_TEMP_PATH.swap(std::ptr::null_mut(), Ordering::SeqCst));
+}
+
+fn free_signal_path(path: *mut libc::c_char) {
+    if !path.is_null() {
+        // SAFETY: non-null paths come from `CString::into_raw` and were swapped out
+        // of `SIGNAL_TEMP_PATH`, so nothing else frees them
+        drop(unsafe { CString::from_raw(path) });
+    }
+}
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    let path = SIGNAL_TEMP_PATH.load(Ordering::SeqCst);
+    if !path.is_null() {
+        // SAFETY: `path` is the registered C string; unlink, signal and raise are async-signal-safe
+        unsafe {
+            libc::unlink(path);
+        }
+    }
+    // Restore the default action so the process exits with the usual status
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    /// Held by tests that register or clear the process-wide signal handler.
+    static SIGNALS: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn refuses_linked_inputs_unless_forced() {
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("in.bam");
+        fs::write(&input, b"data").unwrap();
+
+        let link = dir.path().join("link.bam");
+        std::os::unix::fs::symlink(&input, &link).unwrap();
+        let err = InPlace::prepare(&link, None, false).unwrap_err();
+        assert!(err.to_string().contains("is a symlink"));
+
+        let hard = dir.path().join("hard.bam");
+        fs::hard_link(&input, &hard).unwrap();
+        let err = InPlace::prepare(&input, None, false).unwrap_err();
+        assert!(err.to_string().contains("has 2 hard links"));
+    }
+
+    #[test]
+    fn commits_with_backup_and_cleans_up_otherwise() {
+        let _signals = SIGNALS.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("in.bam");
+        fs::write(&input, b"old").unwrap();
+
+        let in_place = InPlace::prepare(&input, Some(".orig"), false).unwrap();
+        let temp = in_place.temp_path().to_path_buf();
+        assert_eq!(temp, dir.path().join(".in.bam.tmp"));
+        fs::write(&temp, b"new").unwrap();
+        in_place.commit().unwrap();
+        assert_eq!(fs::read(&input).unwrap(), b"new");
+        assert_eq!(fs::read(dir.path().join("in.bam.orig")).unwrap(), b"old");
+        assert!(!temp.exists());
+
+        // Dropped without committing, e.g. after an error
+        let abandoned = InPlace {
+            input: input.clone(),
+            temp: temp.clone(),
+            backup: None,
+            committed: false,
+        };
+        fs::write(&temp, b"partial").unwrap();
+        drop(abandoned);
+        assert!(!temp.exists());
+        assert_eq!(fs::read(&input).unwrap(), b"new");
+    }
+
+    #[test]
+    fn commit_and_drop_restore_default_signal_actions() {
+        let _signals = SIGNALS.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("in.bam");
+        fs::write(&input, b"old").unwrap();
+        let current = || unsafe {
+            let action = libc::signal(libc::SIGTERM, libc::SIG_DFL);
+            libc::signal(libc::SIGTERM, action);
+            action
+        };
+
+        let in_place = InPlace::prepare(&input, None, false).unwrap();
+        assert_ne!(current(), libc::SIG_DFL);
+        assert!(!SIGNAL_TEMP_PATH.load(Ordering::SeqCst).is_null());
+        fs::write(in_place.temp_path(), b"new").unwrap();
+        in_place.commit().unwrap();
+        assert_eq!(current(), libc::SIG_DFL);
+        assert!(SIGNAL_TEMP_PATH.load(Ordering::SeqCst).is_null());
+
+        drop(InPlace::prepare(&input, None, false).unwrap());
+        assert_eq!(current(), libc::SIG_DFL);
+        assert!(SIGNAL_TEMP_PATH.load(Ordering::SeqCst).is_null());
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(estimated_output_size(1000), 1200);
+        assert_eq!(format_bytes(512), "512.0 B");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use std::path::{Path, PathBuf};
+
+/// htslib's default minimum shift for CSI indices.
+const CSI_MIN_SHIFT: u32 = 14;
+
+/// Index format for a coordinate-sorted BAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    Bai,
+    Csi,
+}
+
+impl IndexKind {
+    fn extension(self) -> &'static str {
+        match self {
+            IndexKind::Bai => "bai",
+            IndexKind::Csi => "csi",
+        }
+    }
+}
+
+/// Build an index for `bam_path` at `{bam_path}.bai` or `{bam_path}.csi`.
+fn build_index(bam_path: &Path, kind: IndexKind, threads: usize) -> Result<PathBuf> {
+    let index_path = append_extension(bam_path, kind.extension());
+    build_index_at(bam_path, &index_path, kind, threads)?;
+    Ok(index_path)
+}
+
+/// Build an index for `bam_path` at `index_path`, replacing any existing file.
+fn build_index_at(
+    bam_path: &Path,
+    index_path: &Path,
+    kind: IndexKind,
+    threads: usize,
+) -> Result<()> {
+    let idx_type = match kind {
+        IndexKind::Bai => bam::index::Type::Bai,
+        IndexKind::Csi => bam::index::Type::Csi(CSI_MIN_SHIFT),
+    };
+    let thread_count = u32::try_from(threads).context("Thread count exceeds u32")?;
+    bam::index::build(bam_path, Some(index_path), idx_type, thread_count)
+        .with_context(|| format!("Failed to build index for {:?}", bam_path))
+}
+
+/// Index files htslib would pair with `bam_path`: `x.bam.bai`, `x.bai` and `x.bam.csi`.
+fn existing_indices(bam_path: &Path) -> Vec<(PathBuf, IndexKind)> {
+    [
+        (append_extension(bam_path, "bai"), IndexKind::Bai),
+        (bam_path.with_extension("bai"), IndexKind::Bai),
+        (append_extension(bam_path, "csi"), IndexKind::Csi),
+    ]
+    .into_iter()
+    .filter(|(path, _)| path != bam_path && path.is_file())
+    .collect()
+}
+
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Index files written or removed by [`refresh_indices`].
+#[derive(Debug, Default)]
+pub struct IndexUpdate {
+    pub built: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+/// Build the `requested` index for a freshly written `bam_path` and rebuild any
+/// index already next to it, so an old index is never paired with new records.
+///
+/// Old indices cannot be rebuilt for unsorted output and are removed instead.
+pub fn refresh_indices(
+    bam_path: &Path,
+    requested: Option<IndexKind>,
+    coordinate_sorted: bool,
+    threads: usize,
+) -> Result<IndexUpdate> {
+    let mut update = IndexUpdate::default();
+    for (index_path, kind) in existing_indices(bam_path) {
+        if coordinate_sorted {
+            build_index_at(bam_path, &index_path, kind, threads)?;
+            update.built.push(index_path);
+        } else {
+            std::fs::remove_file(&index_path)
+                .with_context(|| format!("Failed to remove stale index: {:?}", index_path))?;
+            update.removed.push(index_path);
+        }
+    }
+    if let Some(kind) = requested {
+        let index_path = append_extension(bam_path, kind.extension());
+        if !update.built.contains(&index_path) {
+            update.built.push(build_index(bam_path, kind, threads)?);
+        }
+    }
+    Ok(update)
+}
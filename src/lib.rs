@@ -0,0 +1,31 @@
+//! Re-tag BAM files by parsing cell barcodes and UMIs from read names.
+//!
+//! The `tagbam` binary is built on these modules; they are exposed as a library
+//! so the tagging hot path can be benchmarked directly.
+//!
+//! Only unix platforms are supported: in-place runs rely on POSIX signals and
+//! `statvfs`.
+
+#[cfg(not(unix))]
+compile_error!("tagbam supports unix platforms (Linux, macOS) only");
+
+pub mod bq;
+pub mod cache;
+pub mod dedup;
+pub mod in_place;
+pub mod index;
+pub mod molecule;
+pub mod pipeline;
+pub mod position;
+pub mod program;
+pub mod read_structure;
+pub mod region;
+pub mod rename;
+pub mod sample_sheet;
+pub mod split;
+pub mod stats;
+pub mod tag;
+pub mod transfer;
+pub mod umi;
+pub mod untag;
+pub mod validate;
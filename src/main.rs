@@ -1,26 +1,68 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Args, CommandFactory, Parser, Subcommand};
 use rust_htslib::bam;
 use rust_htslib::bam::Read;
-use rust_htslib::bgzf;
-use rust_htslib::tpool::ThreadPool;
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, BufWriter, Read as IoRead, Write as IoWrite};
+use std::ffi::OsString;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::{fs::File, str};
+use std::str;
+
+use tagbam::bq::{BqFormat, BqSchema, DuplicateNames, ExtraQual};
+use tagbam::cache::CacheKind;
+use tagbam::dedup::{DuplicateMarker, DuplicateMode};
+use tagbam::in_place::InPlace;
+use tagbam::index::IndexKind;
+use tagbam::molecule::{set_molecule_id, MoleculeGrouper};
+use tagbam::position::{is_coordinate_sorted, mark_unsorted};
+use tagbam::read_structure::{self, ReadStructure, TrimMode};
+use tagbam::region::RegionReader;
+use tagbam::rename::{RenameMode, Renamer};
+use tagbam::sample_sheet::SampleSheet;
+use tagbam::split::{SplitBy, SplitWriter};
+use tagbam::stats::Stats;
+use tagbam::tag::{parse_tag_name, QualFailAction, QualMetric, TagBuffers, TagStatus, Tagger};
+use tagbam::transfer::{TagMap, TagStream, TagTransfer};
+use tagbam::umi::UmiCorrector;
+use tagbam::untag::{IndexLengths, Untagger};
+use tagbam::validate::{Validator, Violation};
+use tagbam::{bq, cache, index, pipeline, program, region, split, tag, transfer};
 
 #[derive(Parser, Debug)]
 #[command(
     name = "tagbam",
     version,
     about = "Re-tag BAM files by parsing cell barcodes and UMIs from read names",
-    long_about = "Parses read names in format {uuid}_{i7}-{i5}-{CBC}_{UMI} and adds BAM tags:\n\
-                  - CB:Z (cell barcode: i7+i5+CBC concatenated)\n\
-                  - CY:Z (cell barcode quality: all 'I' for perfect quality)\n\
-                  - UB:Z (UMI sequence)\n\
-                  - UY:Z (UMI quality: all 'I' for perfect quality)"
+    after_help = "Without a subcommand, arguments are passed to `tag`, e.g. `tagbam -i in.bam -o out.bam`."
 )]
 struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Add CB/CY/UB/UY tags parsed from read names (the default)
+    #[command(
+        long_about = "Parses read names in format {uuid}_{i7}-{i5}-{CBC}_{UMI} and adds BAM tags:\n\
+                      - CB:Z (cell barcode: i7+i5+CBC concatenated)\n\
+                      - CY:Z (cell barcode quality: all 'I' for perfect quality)\n\
+                      - UB:Z (UMI sequence)\n\
+                      - UY:Z (UMI quality: all 'I' for perfect quality)"
+    )]
+    Tag(Box<TagArgs>),
+    /// Remove barcode/UMI tags, optionally moving them back into read names
+    Untag(UntagArgs),
+    /// Report parse rate, barcode/UMI composition and qualities, top barcodes and UMI collisions without writing a BAM
+    Stats(StatsArgs),
+    /// Check that CB/CY/UB/UY agree with read names, each other and mates; exits non-zero on violations
+    Validate(ValidateArgs),
+    /// Build or inspect --bq-cache and --tags-cache files
+    #[command(subcommand)]
+    Cache(CacheCommand),
+}
+
+#[derive(Args, Debug)]
+struct TagArgs {
     /// Input BAM file
     #[arg(short, long, value_name = "FILE")]
     input: PathBuf,
@@ -33,448 +75,1205 @@ struct Cli {
     #[arg(long, conflicts_with = "output")]
     in_place: bool,
 
+    /// Keep the original input as FILE+SUFFIX after --in-place
+    #[arg(long, value_name = "SUFFIX", requires = "in_place")]
+    backup: Option<String>,
+
+    /// Replace symlinked or hard-linked inputs, or proceed without enough free space, in --in-place mode
+    #[arg(long, requires = "in_place")]
+    force: bool,
+
     /// Skip reads with unparseable names instead of erroring
     #[arg(long)]
     skip_unparseable: bool,
 
-    /// Optional FASTQ (plain, gzip, or bgzip) with BQ tag in header for barcode qualities (loads into memory)
-    #[arg(long, value_name = "FASTQ")]
-    fastq_bq: Option<PathBuf>,
+    #[command(flatten)]
+    bq: BqArgs,
+
+    /// Optional cache file for --bq-source (loads if present, otherwise created)
+    #[arg(
+        long,
+        alias = "fastq-bq-cache",
+        value_name = "CACHE",
+        requires = "bq_source"
+    )]
+    bq_cache: Option<PathBuf>,
+
+    /// Copy --copy-tags from the records of this (unaligned) BAM with the same read name instead of parsing read names
+    #[arg(
+        long,
+        value_name = "BAM",
+        conflicts_with_all = [
+            "bq_source",
+            "read_structure",
+            "sample_sheet",
+            "min_cb_qual",
+            "min_umi_qual",
+            "skip_unparseable",
+        ]
+    )]
+    tags_from: Option<PathBuf>,
+
+    /// Tags copied by --tags-from; existing values are replaced
+    #[arg(
+        long,
+        value_name = "TAGS",
+        value_delimiter = ',',
+        default_value = "CB,CR,CY,UB,UR,UY,RG",
+        value_parser = parse_tag_name,
+        requires = "tags_from"
+    )]
+    copy_tags: Vec<[u8; 2]>,
+
+    /// Optional cache file for --tags-from (loads if present, otherwise created)
+    #[arg(long, value_name = "CACHE", requires = "tags_from")]
+    tags_cache: Option<PathBuf>,
+
+    /// Read --tags-from alongside the input instead of loading it into memory; both BAMs must list reads in the same order
+    #[arg(long, requires = "tags_from", conflicts_with = "tags_cache")]
+    tags_stream: bool,
+
+    /// Read structure of barcode/UMI bases at the start of read 1 (e.g. 6C8M+T); CY/UY use the record's own base qualities for those segments
+    #[arg(long, value_name = "STRUCTURE")]
+    read_structure: Option<ReadStructure>,
+
+    /// Trim the in-read barcode/UMI bases after tagging: soft-clip them in aligned records or remove them from unaligned records
+    #[arg(long, value_enum, value_name = "MODE", requires = "read_structure")]
+    trim_in_read: Option<TrimMode>,
+
+    /// Minimum cell barcode quality (Phred) computed from CY
+    #[arg(long, value_name = "QUAL")]
+    min_cb_qual: Option<u8>,
+
+    /// Minimum UMI quality (Phred) computed from UY
+    #[arg(long, value_name = "QUAL")]
+    min_umi_qual: Option<u8>,
+
+    /// How barcode/UMI qualities are summarised for --min-cb-qual/--min-umi-qual
+    #[arg(long, value_enum, default_value = "min")]
+    qual_metric: QualMetric,
+
+    /// What to do with reads failing --min-cb-qual/--min-umi-qual
+    #[arg(long, value_enum, default_value = "untag")]
+    qual_fail: QualFailAction,
+
+    /// BAM file receiving reads rejected by --qual-fail reject
+    #[arg(long, value_name = "FILE")]
+    rejected: Option<PathBuf>,
+
+    /// Correct UMIs with directional-adjacency clustering per cell and template 5' position (coordinate-sorted input); raw UMIs are kept in UR
+    #[arg(long)]
+    correct_umis: bool,
+
+    /// Mark duplicates by cell barcode, UMI and 5' position/strand (coordinate-sorted input): `flag` sets 0x400, `tag` writes --duplicate-tag
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        default_missing_value = "flag"
+    )]
+    mark_duplicates: Option<DuplicateMode>,
+
+    /// Tag written by --mark-duplicates tag (1 = duplicate, 0 = not)
+    #[arg(long, value_name = "TAG", default_value = "xd", value_parser = parse_tag_name)]
+    duplicate_tag: [u8; 2],
+
+    /// Write per-cell read and duplicate counts (TSV) to this file
+    #[arg(long, value_name = "FILE", requires = "mark_duplicates")]
+    duplicate_stats: Option<PathBuf>,
+
+    /// Assign MI:Z molecule identifiers from cell barcode, UMI and template coordinates (coordinate-sorted input); mates share one MI
+    #[arg(long)]
+    assign_mi: bool,
+
+    /// Rewrite read names after tagging: `uuid` keeps the part before the barcodes, `counter` numbers templates; mates get identical names
+    #[arg(long, value_enum, value_name = "MODE", default_value = "keep")]
+    rename: RenameMode,
+
+    /// Write one BAM per cell barcode or sample index into --split-dir instead of a single output
+    #[arg(long, value_enum, value_name = "KEY", requires = "split_dir")]
+    split_by: Option<SplitBy>,
+
+    /// Output directory for --split-by
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "split_by",
+        conflicts_with_all = ["output", "in_place"]
+    )]
+    split_dir: Option<PathBuf>,
+
+    /// Barcodes (one per line) that get their own BAM with --split-by; all others go to other.bam
+    #[arg(long, value_name = "FILE", requires = "split_by")]
+    split_allowlist: Option<PathBuf>,
+
+    /// Maximum number of split BAMs kept open at once; records of further buckets are buffered and written at the end
+    #[arg(long, value_name = "N", default_value = "256")]
+    max_open_files: usize,
+
+    /// CSV mapping i7/i5 indices to sample names (columns: sample, i7, optional i5 and library); adds @RG header lines and RG:Z tags
+    #[arg(long, value_name = "CSV")]
+    sample_sheet: Option<PathBuf>,
+
+    /// Mismatches tolerated per index when matching --sample-sheet
+    #[arg(long, value_name = "N", default_value = "1", requires = "sample_sheet")]
+    sample_mismatches: usize,
+
+    /// Only tag records overlapping this region (chr, chr:start or chr:start-end; repeatable); requires an indexed input
+    #[arg(long, value_name = "REGION", conflicts_with = "in_place")]
+    region: Vec<String>,
+
+    /// Only tag records overlapping the intervals in this BED file; requires an indexed input
+    #[arg(long, value_name = "BED", conflicts_with = "in_place")]
+    regions_bed: Option<PathBuf>,
+
+    /// Index the output (BAI) after writing; requires coordinate-sorted input
+    #[arg(long)]
+    index: bool,
+
+    /// Index the output with CSI instead of BAI (implies --index)
+    #[arg(long)]
+    csi: bool,
 
-    /// Optional cache file for --fastq-bq (loads if present, otherwise created)
-    #[arg(long, value_name = "CACHE", requires = "fastq_bq")]
-    fastq_bq_cache: Option<PathBuf>,
+    /// Add @CO header lines describing the tags written and the barcode quality source
+    #[arg(long)]
+    header_comments: bool,
+
+    /// Print the tags the first --head records would get, without writing any output
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Records previewed by --dry-run
+    #[arg(long, value_name = "N", default_value = "10", requires = "dry_run")]
+    head: usize,
+
+    /// Worker threads for parsing names and tagging records; reading and writing stay ordered
+    #[arg(long, value_name = "N", default_value = "1")]
+    tag_threads: usize,
 
     /// Number of threads for BAM compression/decompression
     #[arg(short = 't', long, default_value = "4")]
     threads: usize,
 }
 
-/// Parsed components from read name: {uuid}_{i7}-{i5}-{CBC}_{UMI}
-#[derive(Debug, PartialEq)]
-struct ReadNameComponents {
-    i7: String,
-    i5: String,
-    cbc: String,
-    umi: String,
+/// Barcode/UMI quality source, shared by `tag` and `cache build`.
+#[derive(Args, Debug)]
+struct BqArgs {
+    /// Barcode/UMI qualities to load into memory: FASTQ with BQ tokens in headers, TSV (read_name, cb_qual, umi_qual) or BAM with CY and UY (or QX/BZ) tags; plain, gzip or bgzip
+    #[arg(long, alias = "fastq-bq", value_name = "PATH")]
+    bq_source: Option<PathBuf>,
+
+    /// Format of --bq-source (detected from its contents by default)
+    #[arg(long, value_enum, requires = "bq_source")]
+    bq_format: Option<BqFormat>,
+
+    /// Which --bq-source record to keep when a read name repeats (after removing /1 and /2 suffixes)
+    #[arg(long, value_enum, default_value = "last", requires = "bq_source")]
+    bq_duplicates: DuplicateNames,
+
+    /// BQ token labels concatenated, in this order, into CY (FASTQ sources only)
+    #[arg(
+        long,
+        value_name = "LABELS",
+        value_delimiter = ',',
+        default_value = "i7,i5,CBC",
+        requires = "bq_source"
+    )]
+    bq_cy_labels: Vec<String>,
+
+    /// BQ token label holding the UMI qualities for UY (FASTQ sources only)
+    #[arg(
+        long,
+        value_name = "LABEL",
+        default_value = "UMI",
+        requires = "bq_source"
+    )]
+    bq_umi_label: String,
+
+    /// Build CY from whichever --bq-cy-labels a token has instead of ignoring tokens that lack some (FASTQ sources only)
+    #[arg(long, requires = "bq_source")]
+    bq_partial: bool,
+
+    /// Write the qualities of another BQ token label to a BAM tag, e.g. R2BC:QX (repeatable; FASTQ sources only)
+    #[arg(long, value_name = "LABEL:TAG", requires = "bq_source")]
+    bq_tag: Vec<ExtraQual>,
 }
 
-/// Parse read name in format: {uuid}_{i7}-{i5}-{CBC}_{UMI}
-///
-/// Example: 2efc6b85-aa0d-4c1d-ab33-bf5f442fe47c_TTGGCTCC-GGTCGGCG-ACTTGA_GAAGCAGT
-/// Returns: ReadNameComponents { i7: "TTGGCTCC", i5: "GGTCGGCG", cbc: "ACTTGA", umi: "GAAGCAGT" }
-fn parse_read_name(name: &str) -> Result<ReadNameComponents> {
-    let parts: Vec<&str> = name.split('_').collect();
+impl BqArgs {
+    fn schema(&self) -> BqSchema {
+        BqSchema {
+            cy_labels: self.bq_cy_labels.clone(),
+            umi_label: self.bq_umi_label.clone(),
+            partial: self.bq_partial,
+            extra: self.bq_tag.clone(),
+        }
+    }
 
-    if parts.len() != 3 {
-        anyhow::bail!(
-            "Expected 3 underscore-separated parts in read name, found {}: '{}'",
-            parts.len(),
-            name
-        );
+    /// `--bq-format`, or the format detected from `source`.
+    fn format(&self, source: &Path) -> Result<BqFormat> {
+        match self.bq_format {
+            Some(format) => Ok(format),
+            None => BqFormat::detect(source),
+        }
     }
+}
 
-    // parts[0] = uuid (ignored)
-    // parts[1] = i7-i5-CBC
-    // parts[2] = UMI
+#[derive(Args, Debug)]
+struct UntagArgs {
+    /// Input BAM file
+    #[arg(short, long, value_name = "FILE")]
+    input: PathBuf,
 
-    let barcode_parts: Vec<&str> = parts[1].split('-').collect();
-    if barcode_parts.len() != 3 {
-        anyhow::bail!(
-            "Expected 3 hyphen-separated barcode parts, found {}: '{}'",
-            barcode_parts.len(),
-            parts[1]
-        );
-    }
+    /// Output BAM file
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+
+    /// Tags to remove
+    #[arg(
+        long,
+        value_name = "TAGS",
+        value_delimiter = ',',
+        default_value = "CB,CY,UB,UY",
+        value_parser = parse_tag_name
+    )]
+    tags: Vec<[u8; 2]>,
+
+    /// Rewrite read names as {uuid}_{i7}-{i5}-{CBC}_{UMI} from CB and UB, splitting CB after these i7,i5 lengths (e.g. 8,8)
+    #[arg(long, value_name = "I7,I5")]
+    restore_names: Option<IndexLengths>,
+
+    /// Number of threads for BAM compression/decompression
+    #[arg(short = 't', long, default_value = "4")]
+    threads: usize,
+}
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    /// Input BAM file
+    #[arg(short, long, value_name = "FILE")]
+    input: PathBuf,
+
+    /// Write the report here instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
 
-    Ok(ReadNameComponents {
-        i7: barcode_parts[0].to_string(),
-        i5: barcode_parts[1].to_string(),
-        cbc: barcode_parts[2].to_string(),
-        umi: parts[2].to_string(),
-    })
+    /// Number of most frequent cell barcodes listed
+    #[arg(long, value_name = "N", default_value = "20")]
+    top_n: usize,
+
+    #[command(flatten)]
+    bq: BqArgs,
+
+    /// Optional cache file for --bq-source (loads if present, otherwise created)
+    #[arg(long, value_name = "CACHE", requires = "bq_source")]
+    bq_cache: Option<PathBuf>,
+
+    /// Number of threads for BAM decompression and --bq-source parsing
+    #[arg(short = 't', long, default_value = "4")]
+    threads: usize,
 }
 
-/// Create perfect quality string: 'I' repeated n times (Phred Q40, ASCII 73)
-fn perfect_quality(length: usize) -> Vec<u8> {
-    vec![b'I'; length]
+#[derive(Args, Debug)]
+struct ValidateArgs {
+    /// Input BAM file
+    #[arg(short, long, value_name = "FILE")]
+    input: PathBuf,
+
+    /// Do not compare CB/UB with read names (e.g. after --rename)
+    #[arg(long)]
+    no_name_check: bool,
+
+    /// Violations listed individually in the report
+    #[arg(long, value_name = "N", default_value = "10")]
+    max_examples: usize,
+
+    /// Number of threads for BAM decompression
+    #[arg(short = 't', long, default_value = "4")]
+    threads: usize,
 }
 
-/// Parsed barcode/UMI qualities from a BQ token.
-#[derive(Debug, Clone)]
-struct BqQuals {
-    cb: Vec<u8>,
-    umi: Option<Vec<u8>>,
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Parse a --bq-source or --tags-from BAM and write its cache, replacing any existing one
+    Build(CacheBuildArgs),
+    /// Print what a cache was built from and how many reads it holds
+    Inspect {
+        /// Cache file
+        cache: PathBuf,
+    },
 }
 
-const BQ_CACHE_MAGIC: &[u8; 8] = b"TBQMAP01";
+#[derive(Args, Debug)]
+#[command(group(
+    clap::ArgGroup::new("source")
+        .args(["bq_source", "tags_from"])
+        .required(true)
+))]
+struct CacheBuildArgs {
+    /// Cache file to write
+    #[arg(short, long, value_name = "CACHE")]
+    output: PathBuf,
+
+    #[command(flatten)]
+    bq: BqArgs,
+
+    /// Unaligned BAM to cache tags from, as with `tag --tags-from`
+    #[arg(long, value_name = "BAM", conflicts_with = "bq_source")]
+    tags_from: Option<PathBuf>,
+
+    /// Tags cached from --tags-from
+    #[arg(
+        long,
+        value_name = "TAGS",
+        value_delimiter = ',',
+        default_value = "CB,CR,CY,UB,UR,UY,RG",
+        value_parser = parse_tag_name,
+        requires = "tags_from"
+    )]
+    copy_tags: Vec<[u8; 2]>,
+
+    /// Number of threads for decompression and FASTQ parsing
+    #[arg(short = 't', long, default_value = "4")]
+    threads: usize,
+}
 
-struct FastqReader {
-    reader: bgzf::Reader,
-    _tpool: Option<ThreadPool>,
+/// Source of records: the whole BAM or only records in some regions.
+enum Input {
+    Bam(bam::Reader),
+    Regions(RegionReader),
 }
 
-impl FastqReader {
-    fn from_path(path: &Path, threads: usize) -> Result<Self> {
-        let mut reader = bgzf::Reader::from_path(path)
-            .with_context(|| format!("Failed to open FASTQ: {:?}", path))?;
-        let tpool = if threads > 1 {
-            let is_bgzip = bgzf::is_bgzip(path)
-                .with_context(|| format!("Failed to detect bgzip FASTQ: {:?}", path))?;
-            if is_bgzip {
-                let thread_count =
-                    u32::try_from(threads).context("FASTQ thread count exceeds u32")?;
-                let tpool =
-                    ThreadPool::new(thread_count).context("Failed to create FASTQ thread pool")?;
-                reader
-                    .set_thread_pool(&tpool)
-                    .context("Failed to set FASTQ thread pool")?;
-                Some(tpool)
-            } else {
-                None
-            }
-        } else {
-            None
-        };
-        Ok(Self {
-            reader,
-            _tpool: tpool,
-        })
+impl Input {
+    fn header(&self) -> &bam::HeaderView {
+        match self {
+            Input::Bam(reader) => reader.header(),
+            Input::Regions(reader) => reader.header(),
+        }
     }
-}
 
-impl IoRead for FastqReader {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.reader.read(buf)
+    fn read(&mut self, record: &mut bam::Record) -> Option<Result<()>> {
+        match self {
+            Input::Bam(reader) => reader
+                .read(record)
+                .map(|result| result.context("Failed to read BAM record")),
+            Input::Regions(reader) => reader.read(record),
+        }
     }
 }
 
-/// Parse `|BQ:` token from a FASTQ header and return concatenated i7+i5+CBC qualities and UMI qualities if present.
-fn parse_bq_token(header: &str) -> Option<BqQuals> {
-    let bq_start = header.find("|BQ:")?;
-    let token = &header[bq_start + 4..];
-    let token = token.split_whitespace().next().unwrap_or(token);
-
-    let mut i7 = None;
-    let mut i5 = None;
-    let mut cbc = None;
-    let mut umi = None;
-
-    for part in token.split(';') {
-        if let Some((label, qual)) = part.split_once(':') {
-            match label {
-                "i7" => i7 = Some(qual.to_string()),
-                "i5" => i5 = Some(qual.to_string()),
-                "CBC" => cbc = Some(qual.to_string()),
-                "UMI" => umi = Some(qual.to_string()),
-                _ => {}
-            }
+/// Destination of tagged records: a single BAM or one BAM per split key.
+enum Output {
+    Bam(bam::Writer),
+    Split(Box<SplitWriter>),
+}
+
+impl Output {
+    /// Split bucket of `record`, taken before renaming as i7/i5 keys come from its name.
+    fn bucket(&self, record: &bam::Record) -> Option<String> {
+        match self {
+            Output::Bam(_) => None,
+            Output::Split(split) => Some(split.bucket(record)),
         }
     }
 
-    match (i7, i5, cbc) {
-        (Some(a), Some(b), Some(c)) => Some(BqQuals {
-            cb: [a, b, c].concat().into_bytes(),
-            umi: umi.map(|u| u.into_bytes()),
-        }),
-        _ => None,
+    fn write(&mut self, record: &bam::Record, bucket: Option<String>) -> Result<()> {
+        match self {
+            Output::Bam(writer) => writer.write(record).context("Failed to write BAM record"),
+            Output::Split(split) => match bucket {
+                Some(bucket) => split.write_to(bucket, record),
+                None => split.write(record),
+            },
+        }
     }
 }
 
-/// Load FASTQ into memory and build a map: read name -> parsed barcode/UMI qualities.
-fn load_bq_map(fastq_path: &Path, threads: usize) -> Result<HashMap<String, BqQuals>> {
-    let mut reader = open_fastq_reader(fastq_path, threads)?.lines();
-    let mut map = HashMap::new();
+/// `@CO` lines describing the tags this run writes and where qualities come from.
+fn tag_descriptions(cli: &TagArgs) -> Vec<String> {
+    let quality_source = match (cli.read_structure.as_ref(), cli.bq.bq_source.as_ref()) {
+        (Some(structure), _) => format!(
+            "read 1 base qualities (read structure {}), i7/i5 {}",
+            structure,
+            match cli.bq.bq_source.as_ref() {
+                Some(source) => format!("from {}", source.display()),
+                None => "constant Q40 ('I')".to_string(),
+            }
+        ),
+        (None, Some(source)) => format!(
+            "{}, constant Q40 ('I') for reads absent from it",
+            source.display()
+        ),
+        (None, None) => "constant Q40 ('I')".to_string(),
+    };
 
-    loop {
-        let header = match reader.next() {
-            Some(Ok(h)) => h,
-            Some(Err(e)) => return Err(e).context("Failed reading FASTQ header"),
-            None => break,
-        };
+    if let Some(source) = cli.tags_from.as_ref() {
+        let tags: Vec<String> = cli
+            .copy_tags
+            .iter()
+            .map(|tag| String::from_utf8_lossy(tag).into_owned())
+            .collect();
+        return vec![format!(
+            "tagbam: {} copied from {} by read name",
+            tags.join(","),
+            source.display()
+        )];
+    }
 
-        // Skip seq, plus, qual
-        let _ = reader.next();
-        let _ = reader.next();
-        let _ = reader.next();
-
-        let name = header
-            .strip_prefix('@')
-            .unwrap_or(&header)
-            .split_whitespace()
-            .next()
-            .unwrap_or("")
-            .to_string();
-
-        if let Some(quals) = parse_bq_token(&header) {
-            map.insert(name, quals);
-        }
+    let mut comments = vec![
+        "tagbam: CB:Z cell barcode, i7+i5+CBC from read names {uuid}_{i7}-{i5}-{CBC}_{UMI}"
+            .to_string(),
+        "tagbam: UB:Z UMI from read names".to_string(),
+        format!(
+            "tagbam: CY:Z/UY:Z Phred+33 barcode/UMI qualities from {}",
+            quality_source
+        ),
+    ];
+    for extra in &cli.bq.bq_tag {
+        comments.push(format!(
+            "tagbam: {}:Z Phred+33 qualities of BQ token label {}",
+            String::from_utf8_lossy(&extra.tag),
+            extra.label
+        ));
+    }
+    if cli.correct_umis {
+        comments.push(
+            "tagbam: UB:Z corrected by directional adjacency per cell and template 5' position, raw UMI in UR:Z"
+                .to_string(),
+        );
+    }
+    match cli.mark_duplicates {
+        Some(DuplicateMode::Flag) => comments.push(
+            "tagbam: duplicates by CB, UB and template 5' ends marked with flag 0x400".to_string(),
+        ),
+        Some(DuplicateMode::Tag) => comments.push(format!(
+            "tagbam: duplicates by CB, UB and template 5' ends marked with {}:i:1",
+            String::from_utf8_lossy(&cli.duplicate_tag)
+        )),
+        None => {}
+    }
+    if cli.assign_mi {
+        comments
+            .push("tagbam: MI:Z molecule identifier by CB, UB and template 5' ends".to_string());
+    }
+    if let Some(sheet) = cli.sample_sheet.as_ref() {
+        comments.push(format!(
+            "tagbam: RG:Z from i7/i5 matched against {} with up to {} mismatches per index",
+            sheet.display(),
+            cli.sample_mismatches
+        ));
     }
+    comments
+}
 
-    Ok(map)
+/// Write `RG:Z`, replacing any read group assigned upstream.
+fn set_read_group(record: &mut bam::Record, read_group: &str) -> Result<()> {
+    if record.aux(b"RG").is_ok() {
+        record
+            .remove_aux(b"RG")
+            .context("Failed to remove RG tag")?;
+    }
+    record
+        .push_aux(b"RG", bam::record::Aux::String(read_group))
+        .context("Failed to add RG tag")
 }
 
-fn open_fastq_reader(fastq_path: &Path, threads: usize) -> Result<Box<dyn BufRead>> {
-    let reader = FastqReader::from_path(fastq_path, threads)?;
-    Ok(Box::new(BufReader::new(reader)))
+fn untag(cli: &UntagArgs) -> Result<()> {
+    let mut reader = bam::Reader::from_path(&cli.input)
+        .with_context(|| format!("Failed to open input BAM: {:?}", cli.input))?;
+    reader.set_threads(cli.threads)?;
+
+    let mut header = bam::Header::from_template(reader.header());
+    program::add_program_record(&mut header, &program::command_line());
+    let mut writer = bam::Writer::from_path(&cli.output, &header, bam::Format::Bam)
+        .with_context(|| format!("Failed to create output BAM: {:?}", cli.output))?;
+    writer.set_threads(cli.threads)?;
+
+    let untagger = Untagger {
+        tags: cli.tags.clone(),
+        restore_names: cli.restore_names,
+    };
+    let (mut n_total, mut n_removed, mut n_renamed) = (0u64, 0u64, 0u64);
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.read(&mut record) {
+        result.context("Failed to read BAM record")?;
+        let untagged = untagger.untag(&mut record)?;
+        n_total += 1;
+        n_removed += u64::from(untagged.removed);
+        n_renamed += u64::from(untagged.renamed == Some(true));
+        writer
+            .write(&record)
+            .context("Failed to write BAM record")?;
+    }
+
+    eprintln!(
+        "Processed {} reads: {} had tags removed",
+        n_total, n_removed
+    );
+    if cli.restore_names.is_some() {
+        eprintln!(
+            "{} read names restored, {} reads lacked CB/UB and kept their names",
+            n_renamed,
+            n_total - n_renamed
+        );
+    }
+    Ok(())
 }
 
-fn load_bq_map_with_cache(
-    fastq_path: &Path,
-    cache_path: Option<&Path>,
-    threads: usize,
-) -> Result<HashMap<String, BqQuals>> {
-    if let Some(cache_path) = cache_path {
-        if cache_path.exists() {
-            return read_bq_cache(cache_path)
-                .with_context(|| format!("Failed to read BQ cache: {:?}", cache_path));
+fn stats(cli: &StatsArgs) -> Result<()> {
+    let bq_map = match cli.bq.bq_source.as_ref() {
+        Some(source) => Some(bq::load_bq_map_with_cache(
+            source,
+            cli.bq_cache.as_deref(),
+            cli.bq.format(source)?,
+            &cli.bq.schema(),
+            cli.threads,
+            cli.bq.bq_duplicates,
+        )?),
+        None => None,
+    };
+
+    let mut reader = bam::Reader::from_path(&cli.input)
+        .with_context(|| format!("Failed to open input BAM: {:?}", cli.input))?;
+    reader.set_threads(cli.threads)?;
+
+    let mut stats = Stats::default();
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.read(&mut record) {
+        result.context("Failed to read BAM record")?;
+        // Count each template once
+        if record.is_secondary()
+            || record.is_supplementary()
+            || (record.is_paired() && record.is_last_in_template())
+        {
+            continue;
         }
+        let qname = str::from_utf8(record.qname()).context("Read name is not valid UTF-8")?;
+        stats.add(qname, bq_map.as_ref());
     }
 
-    let map = load_bq_map(fastq_path, threads)?;
-    if let Some(cache_path) = cache_path {
-        write_bq_cache(cache_path, &map)
-            .with_context(|| format!("Failed to write BQ cache: {:?}", cache_path))?;
+    match cli.output.as_ref() {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("Failed to create stats report: {:?}", path))?;
+            let mut out = std::io::BufWriter::new(file);
+            stats.write(&mut out, cli.top_n)?;
+            out.flush()
+                .with_context(|| format!("Failed to write stats report: {:?}", path))?;
+        }
+        None => stats.write(&mut std::io::stdout().lock(), cli.top_n)?,
     }
-    Ok(map)
+    Ok(())
 }
 
-fn read_bq_cache(cache_path: &Path) -> Result<HashMap<String, BqQuals>> {
-    let file = File::open(cache_path)
-        .with_context(|| format!("Failed to open BQ cache: {:?}", cache_path))?;
-    let mut reader = BufReader::new(file);
-
-    let mut magic = [0u8; 8];
-    reader
-        .read_exact(&mut magic)
-        .context("Failed to read BQ cache header")?;
-    if &magic != BQ_CACHE_MAGIC {
-        anyhow::bail!("BQ cache has invalid header");
-    }
-
-    let count = read_u64(&mut reader).context("Failed to read BQ cache entry count")?;
-    let count_usize = usize::try_from(count).context("BQ cache entry count exceeds usize")?;
-    let mut map = HashMap::with_capacity(count_usize);
-
-    for _ in 0..count_usize {
-        let name_bytes = read_bytes(&mut reader).context("Failed to read BQ cache name")?;
-        let cb = read_bytes(&mut reader).context("Failed to read BQ cache CB qualities")?;
-        let umi_present = read_u8(&mut reader).context("Failed to read BQ cache UMI presence")?;
-        let umi = if umi_present == 1 {
-            Some(read_bytes(&mut reader).context("Failed to read BQ cache UMI qualities")?)
-        } else {
-            None
-        };
+fn validate(cli: &ValidateArgs) -> Result<()> {
+    let mut reader = bam::Reader::from_path(&cli.input)
+        .with_context(|| format!("Failed to open input BAM: {:?}", cli.input))?;
+    reader.set_threads(cli.threads)?;
+
+    let mut validator = Validator::new(!cli.no_name_check, cli.max_examples);
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.read(&mut record) {
+        result.context("Failed to read BAM record")?;
+        validator.check(&record);
+    }
 
-        let name = String::from_utf8(name_bytes).context("BQ cache contains non-UTF8 read name")?;
-        map.insert(name, BqQuals { cb, umi });
+    println!("records\t{}", validator.n_records);
+    println!("tagged\t{}", validator.n_tagged);
+    if !cli.no_name_check {
+        println!("unparseable_names\t{}", validator.n_unparseable);
+    }
+    for violation in Violation::ALL {
+        println!("{}\t{}", violation.name(), validator.count(violation));
+    }
+    for example in validator.examples() {
+        println!(
+            "example\t{}\t{}\t{}",
+            example.violation.name(),
+            example.read,
+            example.detail
+        );
     }
 
-    Ok(map)
+    if validator.n_violations() > 0 {
+        anyhow::bail!(
+            "Found {} tag violations in {:?}",
+            validator.n_violations(),
+            cli.input
+        );
+    }
+    eprintln!("No tag violations in {} records", validator.n_records);
+    Ok(())
 }
 
-fn write_bq_cache(cache_path: &Path, map: &HashMap<String, BqQuals>) -> Result<()> {
-    let file = File::create(cache_path)
-        .with_context(|| format!("Failed to create BQ cache: {:?}", cache_path))?;
-    let mut writer = BufWriter::new(file);
-
-    writer
-        .write_all(BQ_CACHE_MAGIC)
-        .context("Failed to write BQ cache header")?;
-    let count = u64::try_from(map.len()).context("BQ cache entry count exceeds u64")?;
-    write_u64(&mut writer, count).context("Failed to write BQ cache entry count")?;
-
-    for (name, quals) in map {
-        write_bytes(&mut writer, name.as_bytes()).context("Failed to write BQ cache name")?;
-        write_bytes(&mut writer, &quals.cb).context("Failed to write BQ cache CB qualities")?;
-        match &quals.umi {
-            Some(umi) => {
-                write_u8(&mut writer, 1).context("Failed to write BQ cache UMI presence")?;
-                write_bytes(&mut writer, umi).context("Failed to write BQ cache UMI qualities")?;
+fn cache(command: &CacheCommand) -> Result<()> {
+    match command {
+        CacheCommand::Build(args) => {
+            if let Some(source) = args.tags_from.as_ref() {
+                let map = TagMap::load(source, &args.copy_tags, args.threads)?;
+                map.write_cache(&args.output)
+                    .with_context(|| format!("Failed to write tag cache: {:?}", args.output))?;
+                eprintln!("Cached tags of {} reads in {:?}", map.len(), args.output);
+            } else if let Some(source) = args.bq.bq_source.as_ref() {
+                let format = args.bq.format(source)?;
+                let map = bq::load_bq_source(
+                    source,
+                    format,
+                    &args.bq.schema(),
+                    args.threads,
+                    args.bq.bq_duplicates,
+                )?;
+                bq::write_bq_cache(&args.output, &map)
+                    .with_context(|| format!("Failed to write BQ cache: {:?}", args.output))?;
+                eprintln!(
+                    "Cached qualities of {} reads from {} in {:?}",
+                    map.len(),
+                    format.name(),
+                    args.output
+                );
             }
-            None => {
-                write_u8(&mut writer, 0).context("Failed to write BQ cache UMI presence")?;
+        }
+        CacheCommand::Inspect { cache: path } => {
+            let info = cache::inspect(path)?;
+            let kind = match info.kind {
+                CacheKind::Bq => "BQ cache (--bq-cache)",
+                CacheKind::Tags => "tag cache (--tags-cache)",
+            };
+            println!("{}\t{:?}", kind, path);
+            match (info.built_with, info.entries) {
+                (Some(built_with), Some(entries)) => {
+                    println!("built with\t{}", built_with);
+                    println!("reads\t{}", entries);
+                }
+                _ => {
+                    println!("written by another tagbam version; it will be rebuilt when next used")
+                }
             }
         }
     }
-
-    writer.flush().context("Failed to flush BQ cache")?;
     Ok(())
 }
 
-fn read_u64<R: IoRead>(reader: &mut R) -> Result<u64> {
-    let mut buf = [0u8; 8];
-    reader.read_exact(&mut buf)?;
-    Ok(u64::from_le_bytes(buf))
+/// Insert `tag` when no subcommand is given, so `tagbam -i in.bam -o out.bam` keeps working.
+fn with_default_command(mut args: Vec<OsString>) -> Vec<OsString> {
+    let explicit = args.get(1).is_none_or(|arg| {
+        ["-h", "--help", "-V", "--version", "help"].contains(&arg.to_str().unwrap_or(""))
+            || Cli::command()
+                .get_subcommands()
+                .any(|command| arg.to_str() == Some(command.get_name()))
+    });
+    if !explicit {
+        args.insert(1, "tag".into());
+    }
+    args
 }
 
-fn write_u64<W: IoWrite>(writer: &mut W, value: u64) -> Result<()> {
-    writer.write_all(&value.to_le_bytes())?;
-    Ok(())
+fn main() -> Result<()> {
+    let cli = Cli::parse_from(with_default_command(std::env::args_os().collect()));
+    match &cli.command {
+        Command::Tag(args) => tag(args),
+        Command::Untag(args) => untag(args),
+        Command::Stats(args) => stats(args),
+        Command::Validate(args) => validate(args),
+        Command::Cache(command) => cache(command),
+    }
 }
 
-fn read_u8<R: IoRead>(reader: &mut R) -> Result<u8> {
-    let mut buf = [0u8; 1];
-    reader.read_exact(&mut buf)?;
-    Ok(buf[0])
-}
+/// Print a table of the tags the first `head` records would get and the parse rate among them.
+fn dry_run(
+    reader: &mut Input,
+    mut tag_transfer: Option<&mut TagTransfer>,
+    tagger: Tagger,
+    head: usize,
+) -> Result<()> {
+    // Unparseable names are counted rather than stopping the preview
+    let tagger = Tagger {
+        skip_unparseable: true,
+        ..tagger
+    };
+    let mut buffers = TagBuffers::default();
+    let (mut n_total, mut n_matched, mut n_bq_looked_up, mut n_bq_found) = (0u64, 0u64, 0u64, 0u64);
+
+    println!("read\tstatus\tCB\tCY\tUB\tUY\tBQ");
+    let mut record = bam::Record::new();
+    while n_total < head as u64 {
+        let Some(result) = reader.read(&mut record) else {
+            break;
+        };
+        result?;
+        n_total += 1;
+        let qname = String::from_utf8_lossy(record.qname()).into_owned();
 
-fn write_u8<W: IoWrite>(writer: &mut W, value: u8) -> Result<()> {
-    writer.write_all(&[value])?;
-    Ok(())
-}
+        let (status, bq) = match tag_transfer.as_mut() {
+            Some(transfer) => {
+                let found = transfer.apply(&mut record)?;
+                n_matched += u64::from(found);
+                (if found { "copied" } else { "no_match" }, "-")
+            }
+            None => {
+                let tagged = tagger.tag(&mut record, &mut buffers)?;
+                n_matched += u64::from(tagged.status != TagStatus::Unparseable);
+                let status = match tagged.status {
+                    TagStatus::Tagged if tagged.qual_failed => "qual_failed",
+                    TagStatus::Tagged => "tagged",
+                    TagStatus::Skipped => "has_tags",
+                    TagStatus::Unparseable => "unparseable",
+                    TagStatus::Untagged => "qual_failed",
+                };
+                if let Some(found) = tagged.bq_found {
+                    n_bq_looked_up += 1;
+                    n_bq_found += u64::from(found);
+                }
+                let bq = match tagged.bq_found {
+                    Some(true) => "hit",
+                    Some(false) => "miss",
+                    None => "-",
+                };
+                (status, bq)
+            }
+        };
 
-fn read_bytes<R: IoRead>(reader: &mut R) -> Result<Vec<u8>> {
-    let len = read_u64(reader)?;
-    let len_usize = usize::try_from(len).context("BQ cache length exceeds usize")?;
-    let mut buf = vec![0u8; len_usize];
-    reader.read_exact(&mut buf)?;
-    Ok(buf)
-}
+        let tag = |tag: &[u8; 2]| match record.aux(tag) {
+            Ok(bam::record::Aux::String(value)) => value.to_string(),
+            _ => "-".to_string(),
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            qname,
+            status,
+            tag(b"CB"),
+            tag(b"CY"),
+            tag(b"UB"),
+            tag(b"UY"),
+            bq
+        );
+    }
 
-fn write_bytes<W: IoWrite>(writer: &mut W, bytes: &[u8]) -> Result<()> {
-    let len = u64::try_from(bytes.len()).context("BQ cache length exceeds u64")?;
-    write_u64(writer, len)?;
-    writer.write_all(bytes)?;
+    let percent = |n: u64| {
+        if n_total == 0 {
+            0.0
+        } else {
+            100.0 * n as f64 / n_total as f64
+        }
+    };
+    match tag_transfer {
+        Some(_) => eprintln!(
+            "Dry run: {} of {} previewed reads ({:.1}%) matched --tags-from; no output written",
+            n_matched,
+            n_total,
+            percent(n_matched)
+        ),
+        None => eprintln!(
+            "Dry run: {} of {} previewed reads ({:.1}%) have parseable names; no output written",
+            n_matched,
+            n_total,
+            percent(n_matched)
+        ),
+    }
+    if n_bq_looked_up > 0 {
+        eprintln!(
+            "{} of {} previewed reads found qualities in the BQ source",
+            n_bq_found, n_bq_looked_up
+        );
+    }
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-
+fn tag(cli: &TagArgs) -> Result<()> {
     // Validate that either output or in_place is specified
-    if cli.output.is_none() && !cli.in_place {
-        anyhow::bail!("Either --output or --in-place must be specified");
+    if cli.output.is_none() && !cli.in_place && cli.split_dir.is_none() && !cli.dry_run {
+        anyhow::bail!(
+            "Either --output or --in-place (or --split-by with --split-dir) must be specified"
+        );
+    }
+
+    let trim_len = match (cli.trim_in_read, cli.read_structure.as_ref()) {
+        (Some(_), Some(structure)) => Some(structure.barcode_prefix_len().ok_or_else(|| {
+            anyhow::anyhow!(
+                "--trim-in-read requires all barcode/UMI segments before the first template segment in '{}'",
+                structure
+            )
+        })?),
+        _ => None,
+    };
+    let soft_clip = cli.trim_in_read == Some(TrimMode::SoftClip);
+    if soft_clip
+        && (cli.index
+            || cli.csi
+            || cli.correct_umis
+            || cli.mark_duplicates.is_some()
+            || cli.assign_mi)
+    {
+        anyhow::bail!(
+            "--trim-in-read soft-clip moves read positions, so the output is not coordinate-sorted; it cannot be combined with --index, --csi, --correct-umis, --mark-duplicates or --assign-mi"
+        );
     }
 
-    let bq_map = if let Some(ref fastq) = cli.fastq_bq {
-        Some(load_bq_map_with_cache(
-            fastq,
-            cli.fastq_bq_cache.as_deref(),
+    match (cli.qual_fail, cli.rejected.is_some()) {
+        (QualFailAction::Reject, false) => anyhow::bail!("--qual-fail reject requires --rejected"),
+        (QualFailAction::Untag | QualFailAction::QcFail, true) => {
+            anyhow::bail!("--rejected is only used with --qual-fail reject")
+        }
+        _ => {}
+    }
+
+    // Check the input can be replaced before any slow loading
+    let in_place = if cli.in_place && !cli.dry_run {
+        Some(InPlace::prepare(
+            &cli.input,
+            cli.backup.as_deref(),
+            cli.force,
+        )?)
+    } else {
+        None
+    };
+
+    let bq_map = if let Some(ref source) = cli.bq.bq_source {
+        Some(bq::load_bq_map_with_cache(
+            source,
+            cli.bq_cache.as_deref(),
+            cli.bq.format(source)?,
+            &cli.bq.schema(),
             cli.threads,
+            cli.bq.bq_duplicates,
         )?)
     } else {
         None
     };
+    if let Some(bq_map) = bq_map.as_ref() {
+        let malformed = bq_map.malformed();
+        if let Some((line, reason)) = malformed.first.as_ref() {
+            eprintln!(
+                "Warning: Skipped {} malformed {} records (first at {} {}: {})",
+                malformed.count,
+                bq_map.format().name(),
+                bq_map.format().position_unit(),
+                line,
+                reason
+            );
+        }
+        if bq_map.n_duplicates() > 0 {
+            eprintln!(
+                "Warning: {} {} records repeated an earlier read name",
+                bq_map.n_duplicates(),
+                bq_map.format().name()
+            );
+        }
+    }
 
-    let mut reader = bam::Reader::from_path(&cli.input)
-        .with_context(|| format!("Failed to open input BAM: {:?}", cli.input))?;
+    let mut tag_transfer = match cli.tags_from.as_ref() {
+        Some(source) if cli.tags_stream => Some(TagTransfer::Stream(TagStream::new(
+            source,
+            &cli.copy_tags,
+            cli.threads,
+        )?)),
+        Some(source) => {
+            let map = TagMap::load_with_cache(
+                source,
+                cli.tags_cache.as_deref(),
+                &cli.copy_tags,
+                cli.threads,
+            )?;
+            eprintln!("Loaded tags for {} reads from {:?}", map.len(), source);
+            Some(TagTransfer::Memory(map))
+        }
+        None => None,
+    };
 
-    // Enable multi-threaded decompression
-    reader.set_threads(cli.threads)?;
+    let mut reader = if cli.region.is_empty() && cli.regions_bed.is_none() {
+        let mut reader = bam::Reader::from_path(&cli.input)
+            .with_context(|| format!("Failed to open input BAM: {:?}", cli.input))?;
+
+        // Enable multi-threaded decompression
+        reader.set_threads(cli.threads)?;
+        Input::Bam(reader)
+    } else {
+        let indexed = bam::IndexedReader::from_path(&cli.input).with_context(|| {
+            format!(
+                "--region/--regions-bed require an indexed input BAM (.bai/.csi): {:?}",
+                cli.input
+            )
+        })?;
+        let regions =
+            region::load_regions(&cli.region, cli.regions_bed.as_deref(), indexed.header())?;
+        let mut reader = RegionReader::new(indexed, regions);
+        reader.set_threads(cli.threads)?;
+        Input::Regions(reader)
+    };
+
+    // Soft-clipping moves forward reads' POS, so the output is no longer sorted
+    let input_sorted = is_coordinate_sorted(reader.header());
+    let mut header = if soft_clip && input_sorted {
+        bam::Header::from_template(&mark_unsorted(reader.header()))
+    } else {
+        bam::Header::from_template(reader.header())
+    };
+    let coordinate_sorted = input_sorted && !soft_clip;
+
+    let index_kind = match (cli.index, cli.csi) {
+        (_, true) => Some(IndexKind::Csi),
+        (true, false) => Some(IndexKind::Bai),
+        (false, false) => None,
+    };
+    if index_kind.is_some() && !coordinate_sorted {
+        anyhow::bail!("--index/--csi require a coordinate-sorted BAM (@HD SO:coordinate)");
+    }
+
+    if let Some(source) = cli.tags_from.as_ref() {
+        if cli.copy_tags.contains(b"RG") {
+            let source_reader = bam::Reader::from_path(source)
+                .with_context(|| format!("Failed to open --tags-from BAM: {:?}", source))?;
+            transfer::add_missing_read_groups(&mut header, source_reader.header());
+        }
+    }
+
+    let mut sample_sheet = match cli.sample_sheet.as_ref() {
+        Some(path) => {
+            let sheet = SampleSheet::from_path(path, cli.sample_mismatches)?;
+            sheet.add_read_groups(&mut header)?;
+            Some(sheet)
+        }
+        None => None,
+    };
+
+    program::add_program_record(&mut header, &program::command_line());
+    if cli.header_comments {
+        program::add_comments(&mut header, &tag_descriptions(cli));
+    }
+
+    let mut umi_corrector = if cli.correct_umis {
+        if !is_coordinate_sorted(reader.header()) {
+            anyhow::bail!("--correct-umis requires a coordinate-sorted BAM (@HD SO:coordinate)");
+        }
+        Some(UmiCorrector::new())
+    } else {
+        None
+    };
+
+    let mut duplicate_marker = cli
+        .mark_duplicates
+        .map(|mode| DuplicateMarker::new(mode, cli.duplicate_tag));
+
+    // Duplicate marking and MI assignment share one molecule grouping
+    let mut molecule_grouper = if cli.mark_duplicates.is_some() || cli.assign_mi {
+        if !is_coordinate_sorted(reader.header()) {
+            anyhow::bail!(
+                "--mark-duplicates and --assign-mi require a coordinate-sorted BAM (@HD SO:coordinate)"
+            );
+        }
+        Some(MoleculeGrouper::new("--mark-duplicates/--assign-mi"))
+    } else {
+        None
+    };
+
+    // Mates may be far from read 1 in sorted input, so its qualities are collected first
+    let read_starts = match cli.read_structure.as_ref() {
+        Some(structure) if !cli.dry_run => Some(read_structure::load_read_starts(
+            &cli.input,
+            structure,
+            trim_len.filter(|_| soft_clip),
+            cli.threads,
+        )?),
+        _ => None,
+    };
 
-    let header = bam::Header::from_template(reader.header());
+    let tagger = Tagger {
+        bq_map: bq_map.as_ref(),
+        read_structure: cli.read_structure.as_ref(),
+        read_starts: read_starts.as_ref(),
+        trim: cli.trim_in_read.zip(trim_len),
+        min_cb_qual: cli.min_cb_qual,
+        min_umi_qual: cli.min_umi_qual,
+        qual_metric: cli.qual_metric,
+        qual_fail: cli.qual_fail,
+        skip_unparseable: cli.skip_unparseable,
+    };
+
+    if cli.dry_run {
+        return dry_run(&mut reader, tag_transfer.as_mut(), tagger, cli.head);
+    }
 
     // Determine output path: either specified output, or a temp file for in-place mode
-    let output_path = if let Some(ref out) = cli.output {
-        out.clone()
+    let output_path = match (cli.output.as_ref(), in_place.as_ref()) {
+        (Some(out), _) => out.clone(),
+        (None, Some(in_place)) => in_place.temp_path().to_path_buf(),
+        (None, None) => PathBuf::new(),
+    };
+
+    let mut output = if let (Some(by), Some(dir)) = (cli.split_by, cli.split_dir.as_ref()) {
+        Output::Split(Box::new(SplitWriter::new(
+            dir,
+            &header,
+            by,
+            cli.split_allowlist.as_deref(),
+            cli.max_open_files,
+            cli.threads,
+        )?))
     } else {
-        // In-place mode: create a temp file in the same directory
-        let input_dir = cli
-            .input
-            .parent()
-            .ok_or_else(|| anyhow::anyhow!("Cannot determine parent directory of input file"))?;
-        input_dir.join(format!(
-            ".{}.tmp",
-            cli.input
-                .file_name()
-                .ok_or_else(|| anyhow::anyhow!("Cannot determine input file name"))?
-                .to_string_lossy()
-        ))
+        let mut writer = bam::Writer::from_path(&output_path, &header, bam::Format::Bam)
+            .with_context(|| format!("Failed to create output BAM: {:?}", output_path))?;
+
+        // Enable multi-threaded compression
+        writer.set_threads(cli.threads)?;
+        Output::Bam(writer)
+    };
+
+    let mut rejected_writer = match cli.rejected.as_ref() {
+        Some(path) => {
+            let mut w = bam::Writer::from_path(path, &header, bam::Format::Bam)
+                .with_context(|| format!("Failed to create rejected BAM: {:?}", path))?;
+            w.set_threads(cli.threads)?;
+            Some(w)
+        }
+        None => None,
     };
 
-    let mut writer = bam::Writer::from_path(&output_path, &header, bam::Format::Bam)
-        .with_context(|| format!("Failed to create output BAM: {:?}", output_path))?;
+    let mut renamer = Renamer::new(cli.rename);
 
-    // Enable multi-threaded compression
-    writer.set_threads(cli.threads)?;
+    // Records leaving UMI correction are grouped into molecules, renamed, then written
+    let mut write = |mut record: bam::Record| -> Result<()> {
+        if let Some(grouper) = molecule_grouper.as_mut() {
+            let molecule = grouper.assign(&record)?;
+            if let Some(marker) = duplicate_marker.as_mut() {
+                marker.mark(&mut record, molecule)?;
+            }
+            if let (true, Some(molecule)) = (cli.assign_mi, molecule) {
+                set_molecule_id(&mut record, molecule)?;
+            }
+        }
+        let bucket = output.bucket(&record);
+        renamer.rename(&mut record)?;
+        output.write(&record, bucket)
+    };
 
     let mut n_total: u64 = 0;
     let mut n_tagged: u64 = 0;
     let mut n_skipped: u64 = 0;
-
-    for result in reader.records() {
-        let mut record = result.context("Failed to read BAM record")?;
-        n_total += 1;
-
-        let qname = str::from_utf8(record.qname()).context("Read name is not valid UTF-8")?;
-
-        match parse_read_name(qname) {
-            Ok(components) => {
-                // Check if any of our tags already exist
-                let has_existing_tags = record.aux(b"CB").is_ok()
-                    || record.aux(b"CY").is_ok()
-                    || record.aux(b"UB").is_ok()
-                    || record.aux(b"UY").is_ok();
-
-                if has_existing_tags {
-                    eprintln!(
-                        "Warning: Read '{}' already has CB/CY/UB/UY tags, skipping",
-                        qname
-                    );
-                    n_skipped += 1;
-                } else {
-                    // Concatenate cell barcode: i7 + i5 + CBC
-                    let cell_barcode =
-                        format!("{}{}{}", components.i7, components.i5, components.cbc);
-                    let (cell_barcode_qual, umi_qual) = if let Some(bq_map) = bq_map.as_ref() {
-                        if let Some(quals) = bq_map.get(qname) {
-                            let cbq = quals.cb.clone();
-                            let umi_q = quals
-                                .umi
-                                .clone()
-                                .unwrap_or_else(|| perfect_quality(components.umi.len()));
-                            (cbq, umi_q)
-                        } else {
-                            (
-                                perfect_quality(cell_barcode.len()),
-                                perfect_quality(components.umi.len()),
-                            )
-                        }
-                    } else {
-                        (
-                            perfect_quality(cell_barcode.len()),
-                            perfect_quality(components.umi.len()),
-                        )
-                    };
-
-                    // Add tags to BAM record
-                    record.push_aux(b"CB", bam::record::Aux::String(&cell_barcode))?;
-                    record.push_aux(
-                        b"CY",
-                        bam::record::Aux::String(str::from_utf8(&cell_barcode_qual).unwrap()),
-                    )?;
-                    record.push_aux(b"UB", bam::record::Aux::String(&components.umi))?;
-                    record.push_aux(
-                        b"UY",
-                        bam::record::Aux::String(std::str::from_utf8(&umi_qual).unwrap()),
-                    )?;
-
-                    n_tagged += 1;
+    let mut n_in_read: u64 = 0;
+    let mut n_qual_failed: u64 = 0;
+    let mut n_bq_looked_up: u64 = 0;
+    let mut n_bq_found: u64 = 0;
+    let mut n_transferred: u64 = 0;
+
+    // Tags are copied while reading, as a streamed --tags-from must follow input order
+    pipeline::process_ordered(
+        cli.tag_threads,
+        || {
+            let mut record = bam::Record::new();
+            match reader.read(&mut record)? {
+                Ok(()) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            if let Some(transfer) = tag_transfer.as_mut() {
+                match transfer.apply(&mut record) {
+                    Ok(found) => n_transferred += u64::from(found),
+                    Err(e) => return Some(Err(e)),
                 }
             }
-            Err(e) => {
-                if cli.skip_unparseable {
-                    eprintln!("Warning: Skipping unparseable read name '{}': {}", qname, e);
-                    n_skipped += 1;
-                } else {
-                    return Err(e).context(format!("Failed to parse read name '{}'", qname));
+            Some(Ok(record))
+        },
+        TagBuffers::default,
+        |buffers, record| match cli.tags_from {
+            Some(_) => Ok(None),
+            None => tagger.tag(record, buffers).map(Some),
+        },
+        |mut record, tagged| {
+            n_total += 1;
+            let Some(tagged) = tagged else {
+                return match umi_corrector.as_mut() {
+                    Some(corrector) => corrector.push(record, &mut write),
+                    None => write(record),
+                };
+            };
+            if let Some(warning) = tagged.warning.as_ref() {
+                eprintln!("Warning: {}", warning);
+            }
+            match tagged.status {
+                TagStatus::Tagged => n_tagged += 1,
+                TagStatus::Skipped | TagStatus::Unparseable => n_skipped += 1,
+                TagStatus::Untagged => {}
+            }
+            n_in_read += u64::from(tagged.in_read);
+            n_qual_failed += u64::from(tagged.qual_failed);
+            if let Some(found) = tagged.bq_found {
+                n_bq_looked_up += 1;
+                n_bq_found += u64::from(found);
+            }
+
+            if let (Some(sheet), false) = (
+                sample_sheet.as_mut(),
+                tagged.status == TagStatus::Unparseable,
+            ) {
+                let qname =
+                    str::from_utf8(record.qname()).context("Read name is not valid UTF-8")?;
+                let name = tag::parse_read_name(qname)?;
+                if let Some(read_group) = sheet.assign(name.i7, name.i5) {
+                    set_read_group(&mut record, read_group)?;
                 }
             }
-        }
 
-        writer
-            .write(&record)
-            .context("Failed to write BAM record")?;
+            match (rejected_writer.as_mut(), umi_corrector.as_mut()) {
+                (Some(rejected), _) if tagged.reject => rejected
+                    .write(&record)
+                    .context("Failed to write rejected BAM record"),
+                (_, Some(corrector)) => corrector.push(record, &mut write),
+                _ => write(record),
+            }
+        },
+    )?;
+
+    if let Some(corrector) = umi_corrector.as_mut() {
+        corrector.finish(&mut write)?;
     }
 
-    // Ensure writer is flushed and closed before moving the file
-    drop(writer);
+    // Ensure writers are flushed and closed before moving the file
+    let written = match output {
+        Output::Bam(writer) => {
+            drop(writer);
+            vec![if cli.in_place {
+                cli.input.clone()
+            } else {
+                output_path.clone()
+            }]
+        }
+        Output::Split(split) => {
+            let dir = cli.split_dir.as_deref().unwrap_or(Path::new("."));
+            let counts = split.finish()?;
+            eprintln!("Split output into {} BAMs in {:?}", counts.len(), dir);
+            counts
+                .keys()
+                .map(|key| split::bucket_file(dir, key))
+                .collect()
+        }
+    };
+    drop(rejected_writer);
 
     // If in-place mode, replace the original file with the temp file
-    if cli.in_place {
-        std::fs::rename(&output_path, &cli.input)
-            .with_context(|| "Failed to replace input file with tagged version".to_string())?;
+    if let Some(in_place) = in_place {
+        in_place.commit()?;
+    }
+
+    for path in &written {
+        let update = index::refresh_indices(path, index_kind, coordinate_sorted, cli.threads)?;
+        for index_path in &update.built {
+            eprintln!("Indexed {:?}", index_path);
+        }
+        for index_path in &update.removed {
+            eprintln!(
+                "Warning: Removed stale index {:?} (output is not coordinate-sorted)",
+                index_path
+            );
+        }
+    }
+
+    if let Some(source) = cli.tags_from.as_ref() {
+        eprintln!(
+            "Processed {} reads: {} received tags from {:?}, {} had no match",
+            n_total,
+            n_transferred,
+            source,
+            n_total - n_transferred
+        );
+    } else if cli.in_place {
         eprintln!(
             "In-place tagging complete: {} reads processed, {} tagged, {} skipped",
             n_total, n_tagged, n_skipped
@@ -485,93 +1284,50 @@ fn main() -> Result<()> {
             n_total, n_tagged, n_skipped
         );
     }
-
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
-
-    #[test]
-    fn parse_valid_read_name() {
-        let name = "2efc6b85-aa0d-4c1d-ab33-bf5f442fe47c_TTGGCTCC-GGTCGGCG-ACTTGA_GAAGCAGT";
-        let result = parse_read_name(name).unwrap();
-
-        assert_eq!(
-            result,
-            ReadNameComponents {
-                i7: "TTGGCTCC".to_string(),
-                i5: "GGTCGGCG".to_string(),
-                cbc: "ACTTGA".to_string(),
-                umi: "GAAGCAGT".to_string(),
-            }
+    if bq_map.is_some() {
+        eprintln!(
+            "{} of {} reads found qualities in the BQ source",
+            n_bq_found, n_bq_looked_up
         );
+        if n_bq_looked_up > 0 && n_bq_found == 0 {
+            eprintln!("Warning: No read names matched the BQ source; check that both use the same read names");
+        }
     }
-
-    #[test]
-    fn parse_different_lengths() {
-        let name = "uuid_AAA-BB-CCCCCC_UUUU";
-        let result = parse_read_name(name).unwrap();
-
-        assert_eq!(result.i7, "AAA");
-        assert_eq!(result.i5, "BB");
-        assert_eq!(result.cbc, "CCCCCC");
-        assert_eq!(result.umi, "UUUU");
+    if cli.read_structure.is_some() {
+        eprintln!("{} reads used in-read barcode/UMI qualities", n_in_read);
     }
-
-    #[test]
-    fn parse_missing_underscore() {
-        let name = "uuid_TTGGCTCC-GGTCGGCG-ACTTGAGAAGCAGT"; // Missing underscore before UMI
-        assert!(parse_read_name(name).is_err());
+    if cli.min_cb_qual.is_some() || cli.min_umi_qual.is_some() {
+        eprintln!(
+            "{} reads failed barcode/UMI quality filters ({:?})",
+            n_qual_failed, cli.qual_fail
+        );
     }
-
-    #[test]
-    fn parse_missing_hyphen() {
-        let name = "uuid_TTGGCTCC-GGTCGGCGACTTGA_GAAGCAGT"; // Missing hyphen in barcodes
-        assert!(parse_read_name(name).is_err());
+    if let Some(sheet) = sample_sheet.as_ref() {
+        for (sample, n) in sheet.assigned() {
+            eprintln!("{} reads assigned to sample {}", n, sample);
+        }
+        eprintln!("{} reads matched no sample", sheet.n_unassigned());
     }
-
-    #[test]
-    fn perfect_quality_length() {
-        let qual = perfect_quality(8);
-        assert_eq!(qual.len(), 8);
-        assert!(qual.iter().all(|&b| b == b'I'));
+    if cli.rename != RenameMode::Keep {
+        eprintln!(
+            "{} read names rewritten ({:?})",
+            renamer.n_renamed(),
+            cli.rename
+        );
     }
-
-    #[test]
-    fn perfect_quality_ascii() {
-        let qual = perfect_quality(5);
-        assert_eq!(std::str::from_utf8(&qual).unwrap(), "IIIII");
+    if let Some(corrector) = umi_corrector.as_ref() {
+        eprintln!("{} reads had their UMI corrected", corrector.n_corrected());
     }
-
-    #[test]
-    fn bq_cache_roundtrip() {
-        let mut map = HashMap::new();
-        map.insert(
-            "read1".to_string(),
-            BqQuals {
-                cb: b"ABC".to_vec(),
-                umi: Some(b"XYZ".to_vec()),
-            },
-        );
-        map.insert(
-            "read2".to_string(),
-            BqQuals {
-                cb: b"QQ".to_vec(),
-                umi: None,
-            },
+    if let Some(marker) = duplicate_marker.as_ref() {
+        eprintln!(
+            "{} of {} reads with CB/UB marked as duplicates",
+            marker.n_duplicates(),
+            marker.n_examined()
         );
-
-        let file = NamedTempFile::new().unwrap();
-        write_bq_cache(file.path(), &map).unwrap();
-        let loaded = read_bq_cache(file.path()).unwrap();
-
-        assert_eq!(loaded.len(), map.len());
-        assert_eq!(loaded.get("read1").unwrap().cb, b"ABC".to_vec());
-        assert_eq!(loaded.get("read1").unwrap().umi.as_ref().unwrap(), b"XYZ");
-        assert_eq!(loaded.get("read2").unwrap().cb, b"QQ".to_vec());
-        assert!(loaded.get("read2").unwrap().umi.is_none());
+        if let Some(path) = cli.duplicate_stats.as_ref() {
+            marker.write_stats(path)?;
+        }
     }
+
+    Ok(())
 }
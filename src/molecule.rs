@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+use crate::position::{mate_unclipped_five_prime, unclipped_five_prime, CoordinateOrder, Position};
+use crate::umi::aux_string;
+
+/// Keys stay live until the sort position moves this far past where they were
+/// first seen (covers leading soft clips of forward-strand reads).
+const KEY_WINDOW: i64 = 1000;
+
+/// One end of a template: reference, unclipped 5' position and strand.
+type End = (i32, i64, bool);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct MoleculeKey {
+    cb: Vec<u8>,
+    umi: Vec<u8>,
+    ends: (End, Option<End>),
+}
+
+/// Source molecule assigned to a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Molecule {
+    /// Identifier shared by every template of the molecule
+    pub id: u64,
+    /// Whether this is the first template seen for the molecule
+    pub first: bool,
+}
+
+/// Groups coordinate-sorted records into source molecules by `CB`, `UB` and
+/// template ends.
+///
+/// Both mates of a pair are given the molecule assigned to whichever mate is
+/// seen first, so they always agree even if their own positions differ. An
+/// unmapped mate stands in for its mapped mate's template end if seen first.
+pub struct MoleculeGrouper {
+    option: &'static str,
+    order: CoordinateOrder,
+    ids: HashMap<MoleculeKey, u64>,
+    expiry: VecDeque<((i32, i64), MoleculeKey)>,
+    mates: HashMap<Vec<u8>, Molecule>,
+    /// Read names in `mates` by expected mate position, so mates that never
+    /// arrive (filtered, or outside a region) are forgotten
+    mate_expiry: BinaryHeap<Reverse<(Position, Vec<u8>)>>,
+    next_id: u64,
+}
+
+impl MoleculeGrouper {
+    /// `option` names the flag requiring sorted input, for error messages.
+    pub fn new(option: &'static str) -> Self {
+        Self {
+            option,
+            order: CoordinateOrder::default(),
+            ids: HashMap::new(),
+            expiry: VecDeque::new(),
+            mates: HashMap::new(),
+            mate_expiry: BinaryHeap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Assign `record` to a molecule.
+    ///
+    /// Secondary and supplementary records, records without `CB`/`UB` and
+    /// unmapped records without a mapped mate return `None`.
+    pub fn assign(&mut self, record: &bam::Record) -> Result<Option<Molecule>> {
+        self.order.observe(record, self.option)?;
+        self.expire();
+
+        if record.is_secondary() || record.is_supplementary() {
+            return Ok(None);
+        }
+        let Some(cb) = aux_string(record, b"CB") else {
+            return Ok(None);
+        };
+        let Some(umi) = aux_string(record, b"UB") else {
+            return Ok(None);
+        };
+
+        if let Some(molecule) = self.mates.remove(record.qname()) {
+            return Ok(Some(molecule));
+        }
+        let Some(ends) = template_ends(record) else {
+            return Ok(None);
+        };
+
+        let key = MoleculeKey {
+            cb: cb.to_vec(),
+            umi: umi.to_vec(),
+            ends,
+        };
+        let molecule = match self.ids.get(&key) {
+            Some(&id) => Molecule { id, first: false },
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.ids.insert(key.clone(), id);
+                self.expiry.push_back(((record.tid(), record.pos()), key));
+                Molecule { id, first: true }
+            }
+        };
+        if record.is_paired() && record.mtid() >= 0 {
+            let qname = record.qname().to_vec();
+            self.mate_expiry
+                .push(Reverse(((record.mtid(), record.mpos()), qname.clone())));
+            self.mates.insert(qname, molecule);
+        }
+        Ok(Some(molecule))
+    }
+
+    /// Forget keys, and mates not yet seen, the sort position has moved well past.
+    fn expire(&mut self) {
+        let Some((tid, pos)) = self.order.last() else {
+            return;
+        };
+        while let Some(Reverse(((mate_tid, mate_pos), _))) = self.mate_expiry.peek() {
+            if (tid, pos) <= (*mate_tid, mate_pos + KEY_WINDOW) {
+                break;
+            }
+            let Reverse((_, qname)) = self.mate_expiry.pop().expect("peeked");
+            self.mates.remove(&qname);
+        }
+        while let Some(&((key_tid, key_pos), _)) = self.expiry.front() {
+            if key_tid == tid && pos <= key_pos + KEY_WINDOW {
+                break;
+            }
+            let (_, key) = self.expiry.pop_front().expect("front exists");
+            self.ids.remove(&key);
+        }
+    }
+}
+
+/// Write `MI:Z` with the molecule identifier, replacing any existing value.
+pub fn set_molecule_id(record: &mut bam::Record, molecule: Molecule) -> Result<()> {
+    if record.aux(b"MI").is_ok() {
+        record
+            .remove_aux(b"MI")
+            .context("Failed to remove MI tag")?;
+    }
+    record
+        .push_aux(b"MI", bam::record::Aux::String(&molecule.id.to_string()))
+        .context("Failed to add MI tag")
+}
+
+/// Template ends in a mate-independent order.
+///
+/// An unmapped record with a mapped mate gets the mate's end, as the mate would
+/// compute it (exactly when the mate's `MC` is present); other unmapped records
+/// have none.
+fn template_ends(record: &bam::Record) -> Option<(End, Option<End>)> {
+    let mate = || {
+        (
+            record.mtid(),
+            mate_unclipped_five_prime(record),
+            record.is_mate_reverse(),
+        )
+    };
+    let has_mapped_mate = record.is_paired() && !record.is_mate_unmapped() && record.mtid() >= 0;
+    if record.is_unmapped() {
+        return has_mapped_mate.then(|| (mate(), None));
+    }
+
+    let own = (
+        record.tid(),
+        unclipped_five_prime(record),
+        record.is_reverse(),
+    );
+    if !has_mapped_mate {
+        return Some((own, None));
+    }
+    let mate = mate();
+    Some(if own <= mate {
+        (own, Some(mate))
+    } else {
+        (mate, Some(own))
+    })
+}
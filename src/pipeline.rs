@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::sync::Mutex;
+
+/// Records handed to a worker at a time.
+const BATCH_SIZE: usize = 1024;
+
+/// Batches kept in flight per worker, bounding memory use.
+const BATCHES_PER_WORKER: usize = 4;
+
+type Batch<T> = Vec<(bam::Record, Result<T>)>;
+
+/// Read records with `next`, run `process` on each and pass the results to
+/// `consume` in input order.
+///
+/// Each worker creates its own scratch state with `init` and passes it to every
+/// `process` call, so buffers can be reused without locking. With more than one
+/// worker, batches of records are processed on a pool of threads while reading
+/// and consuming stay on the calling thread, so `next` and `consume` need not
+/// be `Send`. The first error in input order is returned.
+///
+/// Records read from a BAM share the reader's header through a non-atomic `Rc`,
+/// so workers hand every record back and all of them are dropped on the calling
+/// thread, including after an error.
+pub fn process_ordered<S, T, N, I, P, C>(
+    workers: usize,
+    mut next: N,
+    init: I,
+    process: P,
+    mut consume: C,
+) -> Result<()>
+where
+    T: Send,
+    N: FnMut() -> Option<Result<bam::Record>>,
+    I: Fn() -> S + Sync,
+    P: Fn(&mut S, &mut bam::Record) -> Result<T> + Sync,
+    C: FnMut(bam::Record, T) -> Result<()>,
+{
+    if workers <= 1 {
+        let mut state = init();
+        while let Some(record) = next() {
+            let mut record = record?;
+            let value = process(&mut state, &mut record)?;
+            consume(record, value)?;
+        }
+        return Ok(());
+    }
+
+    let (job_tx, job_rx) = mpsc::channel::<(u64, Vec<bam::Record>)>();
+    let job_rx = Mutex::new(job_rx);
+    std::thread::scope(|scope| {
+        // Owned here so it can be dropped to close the queue and let the workers exit
+        let job_tx = job_tx;
+        let (done_tx, done_rx) = mpsc::channel::<(u64, Batch<T>)>();
+
+        for _ in 0..workers {
+            let done_tx = done_tx.clone();
+            let (job_rx, init, process) = (&job_rx, &init, &process);
+            scope.spawn(move || {
+                let mut state = init();
+                loop {
+                    let job = job_rx.lock().expect("job queue lock poisoned").recv();
+                    let Ok((seq, batch)) = job else {
+                        break;
+                    };
+                    let results = batch
+                        .into_iter()
+                        .map(|mut record| {
+                            let value = process(&mut state, &mut record);
+                            (record, value)
+                        })
+                        .collect();
+                    if let Err(mpsc::SendError((_, results))) = done_tx.send((seq, results)) {
+                        // Only reachable while the calling thread unwinds; leak
+                        // rather than touch the header's reference count here
+                        std::mem::forget(results);
+                        break;
+                    }
+                }
+            });
+        }
+        drop(done_tx);
+
+        let max_in_flight = workers * BATCHES_PER_WORKER;
+        let mut finished: BTreeMap<u64, Batch<T>> = BTreeMap::new();
+        let (mut next_seq, mut next_consumed) = (0u64, 0u64);
+        let mut eof = false;
+        let mut run = || -> Result<()> {
+            loop {
+                while !eof && next_seq - next_consumed < max_in_flight as u64 {
+                    let mut batch = Vec::with_capacity(BATCH_SIZE);
+                    while batch.len() < BATCH_SIZE {
+                        match next() {
+                            Some(record) => batch.push(record?),
+                            None => {
+                                eof = true;
+                                break;
+                            }
+                        }
+                    }
+                    if batch.is_empty() {
+                        break;
+                    }
+                    job_tx
+                        .send((next_seq, batch))
+                        .context("Record workers stopped unexpectedly")?;
+                    next_seq += 1;
+                }
+                if next_consumed == next_seq {
+                    return Ok(());
+                }
+
+                let (seq, results) = done_rx
+                    .recv()
+                    .context("Record workers stopped unexpectedly")?;
+                finished.insert(seq, results);
+                while let Some(results) = finished.remove(&next_consumed) {
+                    next_consumed += 1;
+                    for (record, value) in results {
+                        consume(record, value?)?;
+                    }
+                }
+            }
+        };
+        let result = run();
+
+        // Close the queue, drop batches no worker has started and collect the
+        // rest, so every record is dropped on this thread
+        drop(job_tx);
+        while job_rx
+            .lock()
+            .expect("job queue lock poisoned")
+            .try_recv()
+            .is_ok()
+        {}
+        for _ in done_rx.iter() {}
+        result
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(n: usize) -> Vec<bam::Record> {
+        (0..n)
+            .map(|i| {
+                let mut record = bam::Record::new();
+                record.set(format!("r{}", i).as_bytes(), None, b"A", b"I");
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn preserves_input_order_across_workers() {
+        for workers in [1, 4] {
+            let mut input = records(5000).into_iter();
+            let mut seen = Vec::new();
+            process_ordered(
+                workers,
+                || input.next().map(Ok),
+                || (),
+                |(), record| Ok(record.qname().len()),
+                |record, len| {
+                    assert_eq!(record.qname().len(), len);
+                    seen.push(String::from_utf8(record.qname().to_vec()).unwrap());
+                    Ok(())
+                },
+            )
+            .unwrap();
+            let expected: Vec<String> = (0..5000).map(|i| format!("r{}", i)).collect();
+            assert_eq!(seen, expected);
+        }
+    }
+
+    #[test]
+    fn returns_first_error_in_input_order() {
+        let mut input = records(3000).into_iter();
+        let mut consumed = 0;
+        let err = process_ordered(
+            4,
+            || input.next().map(Ok),
+            || (),
+            |(), record| match record.qname() {
+                b"r2500" | b"r1500" => {
+                    anyhow::bail!("bad {}", String::from_utf8_lossy(record.qname()))
+                }
+                _ => Ok(()),
+            },
+            |_, ()| {
+                consumed += 1;
+                Ok(())
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "bad r1500");
+        assert_eq!(consumed, 1500);
+    }
+
+    #[test]
+    fn drops_every_record_after_an_error() {
+        let header = std::rc::Rc::new(bam::HeaderView::from_header(&bam::Header::new()));
+        let mut input = records(20000).into_iter().map(|mut record| {
+            record.set_header(std::rc::Rc::clone(&header));
+            record
+        });
+        let err = process_ordered(
+            4,
+            || input.next().map(Ok),
+            || (),
+            |(), _| Ok(()),
+            |record, ()| match record.qname() {
+                b"r100" => anyhow::bail!("stop"),
+                _ => Ok(()),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "stop");
+        drop(input);
+        assert_eq!(std::rc::Rc::strong_count(&header), 1);
+    }
+}
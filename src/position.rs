@@ -0,0 +1,94 @@
+use anyhow::Result;
+use rust_htslib::bam;
+use rust_htslib::bam::record::{Aux, CigarString};
+
+/// Reference and leftmost position, in sort order.
+pub type Position = (i32, i64);
+
+/// Whether the `@HD` line declares `SO:coordinate`.
+pub fn is_coordinate_sorted(header: &bam::HeaderView) -> bool {
+    String::from_utf8_lossy(header.as_bytes())
+        .lines()
+        .find(|line| line.starts_with("@HD"))
+        .is_some_and(|hd| hd.split('\t').any(|field| field == "SO:coordinate"))
+}
+
+/// Copy of `header` whose `@HD` line declares `SO:unsorted` instead of `SO:coordinate`.
+pub fn mark_unsorted(header: &bam::HeaderView) -> bam::HeaderView {
+    let text: String = String::from_utf8_lossy(header.as_bytes())
+        .lines()
+        .map(|line| {
+            if line.starts_with("@HD") {
+                line.replace("\tSO:coordinate", "\tSO:unsorted") + "\n"
+            } else {
+                format!("{}\n", line)
+            }
+        })
+        .collect();
+    bam::HeaderView::from_bytes(text.as_bytes())
+}
+
+/// Unclipped 5' reference position of a mapped record.
+pub fn unclipped_five_prime(record: &bam::Record) -> i64 {
+    let cigar = record.cigar();
+    if record.is_reverse() {
+        cigar.end_pos() + cigar.trailing_softclips() - 1
+    } else {
+        record.pos() - cigar.leading_softclips()
+    }
+}
+
+/// Unclipped 5' reference position of the mate, using its `MC` CIGAR when present.
+///
+/// Falls back to the mate's leftmost position when `MC` is missing.
+pub fn mate_unclipped_five_prime(record: &bam::Record) -> i64 {
+    let mate_cigar = match record.aux(b"MC") {
+        Ok(Aux::String(mc)) => CigarString::try_from(mc).ok(),
+        _ => None,
+    };
+    match mate_cigar {
+        Some(cigar) => {
+            let view = cigar.into_view(record.mpos());
+            if record.is_mate_reverse() {
+                view.end_pos() + view.trailing_softclips() - 1
+            } else {
+                record.mpos() - view.leading_softclips()
+            }
+        }
+        None => record.mpos(),
+    }
+}
+
+/// Tracks the last mapped position to reject records that are not coordinate-sorted.
+#[derive(Debug, Default)]
+pub struct CoordinateOrder {
+    last: Option<(i32, i64)>,
+}
+
+impl CoordinateOrder {
+    /// Record the position of `record`, failing if it precedes the previous one.
+    ///
+    /// `option` names the flag requiring sorted input, for the error message.
+    pub fn observe(&mut self, record: &bam::Record, option: &str) -> Result<()> {
+        if record.is_unmapped() {
+            return Ok(());
+        }
+        let here = (record.tid(), record.pos());
+        if let Some(last) = self.last {
+            if here < last {
+                anyhow::bail!(
+                    "{} requires coordinate-sorted input; '{}' is out of order",
+                    option,
+                    String::from_utf8_lossy(record.qname())
+                );
+            }
+        }
+        self.last = Some(here);
+        Ok(())
+    }
+
+    /// Last mapped `(tid, pos)` seen, if any.
+    pub fn last(&self) -> Option<(i32, i64)> {
+        self.last
+    }
+}
@@ -0,0 +1,146 @@
+use rust_htslib::bam;
+use rust_htslib::bam::header::HeaderRecord;
+use std::collections::HashSet;
+use std::ffi::OsString;
+
+const PROGRAM_NAME: &str = "tagbam";
+
+/// Append a `@PG` line for this run, chained via `PP` to the input's last program.
+pub fn add_program_record(header: &mut bam::Header, command_line: &str) {
+    let programs = program_lines(header);
+    let id = unique_program_id(&programs);
+    let previous = last_program(&programs);
+
+    let mut pg = HeaderRecord::new(b"PG");
+    pg.push_tag(b"ID", &id)
+        .push_tag(b"PN", PROGRAM_NAME)
+        .push_tag(b"VN", env!("CARGO_PKG_VERSION"))
+        .push_tag(b"CL", command_line);
+    if let Some(previous) = previous {
+        pg.push_tag(b"PP", previous);
+    }
+    header.push_record(&pg);
+}
+
+/// Append one `@CO` line per description.
+pub fn add_comments<S: AsRef<str>>(header: &mut bam::Header, comments: &[S]) {
+    for comment in comments {
+        header.push_comment(sanitize(comment.as_ref()).as_bytes());
+    }
+}
+
+/// The process command line with arguments shell-quoted where needed.
+pub fn command_line() -> String {
+    join_args(std::env::args_os())
+}
+
+/// Quote and join `args`; bytes that are not UTF-8, e.g. in Latin-1 paths,
+/// are replaced rather than failing the run.
+fn join_args(args: impl IntoIterator<Item = OsString>) -> String {
+    let args: Vec<String> = args
+        .into_iter()
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect();
+    sanitize(&args.join(" "))
+}
+
+/// `ID` and `PP` of every `@PG` line, in header order.
+fn program_lines(header: &bam::Header) -> Vec<(String, Option<String>)> {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    text.lines()
+        .filter(|line| line.starts_with("@PG\t"))
+        .filter_map(|line| {
+            let field = |tag: &str| {
+                line.split('\t')
+                    .find_map(|f| f.strip_prefix(tag))
+                    .map(str::to_string)
+            };
+            Some((field("ID:")?, field("PP:")))
+        })
+        .collect()
+}
+
+/// `tagbam`, or `tagbam.N` with the smallest N not already used.
+fn unique_program_id(programs: &[(String, Option<String>)]) -> String {
+    let used: HashSet<&str> = programs.iter().map(|(id, _)| id.as_str()).collect();
+    if !used.contains(PROGRAM_NAME) {
+        return PROGRAM_NAME.to_string();
+    }
+    (1..)
+        .map(|n| format!("{}.{}", PROGRAM_NAME, n))
+        .find(|id| !used.contains(id.as_str()))
+        .expect("unbounded range")
+}
+
+/// The last program in header order that no other program names as `PP`.
+fn last_program(programs: &[(String, Option<String>)]) -> Option<&str> {
+    let parents: HashSet<&str> = programs
+        .iter()
+        .filter_map(|(_, pp)| pp.as_deref())
+        .collect();
+    programs
+        .iter()
+        .rev()
+        .map(|(id, _)| id.as_str())
+        .find(|id| !parents.contains(id))
+}
+
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_./=:,+@%".contains(&b));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Header values cannot contain tabs or newlines.
+fn sanitize(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn programs(entries: &[(&str, Option<&str>)]) -> Vec<(String, Option<String>)> {
+        entries
+            .iter()
+            .map(|&(id, pp)| (id.to_string(), pp.map(str::to_string)))
+            .collect()
+    }
+
+    #[test]
+    fn chains_to_last_program_with_unique_id() {
+        let chain = programs(&[
+            ("bwa", None),
+            ("tagbam", Some("bwa")),
+            ("samtools", Some("tagbam")),
+        ]);
+        assert_eq!(last_program(&chain), Some("samtools"));
+        assert_eq!(unique_program_id(&chain), "tagbam.1");
+        assert_eq!(last_program(&[]), None);
+        assert_eq!(unique_program_id(&[]), "tagbam");
+    }
+
+    #[test]
+    fn quotes_arguments_with_special_characters() {
+        assert_eq!(shell_quote("--input"), "--input");
+        assert_eq!(shell_quote("my file.bam"), "'my file.bam'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn joins_arguments_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+        let args = [
+            OsString::from("tagbam"),
+            OsString::from("--input"),
+            OsString::from_vec(b"caf\xe9.bam".to_vec()),
+        ];
+        assert_eq!(join_args(args), "tagbam --input 'caf\u{fffd}.bam'");
+    }
+}
@@ -0,0 +1,604 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam::record::{Cigar, CigarString};
+use rust_htslib::bam::{self, Read as _};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Segment types that can appear in a read structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    /// Cell barcode bases (`C`)
+    CellBarcode,
+    /// UMI bases (`M`)
+    Umi,
+    /// Bases to ignore (`S`)
+    Skip,
+    /// Template bases (`T`)
+    Template,
+}
+
+impl SegmentKind {
+    fn from_code(code: char) -> Option<Self> {
+        match code {
+            'C' => Some(Self::CellBarcode),
+            'M' => Some(Self::Umi),
+            'S' => Some(Self::Skip),
+            'T' => Some(Self::Template),
+            _ => None,
+        }
+    }
+
+    fn code(self) -> char {
+        match self {
+            Self::CellBarcode => 'C',
+            Self::Umi => 'M',
+            Self::Skip => 'S',
+            Self::Template => 'T',
+        }
+    }
+}
+
+/// A single read structure segment; `len` is `None` for a trailing `+` segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub len: Option<usize>,
+}
+
+/// Layout of barcode/UMI bases within read 1, e.g. `6C8M+T`.
+///
+/// Segments are `<length><kind>` with kinds `C` (cell barcode), `M` (UMI),
+/// `S` (skip) and `T` (template). The last segment may use `+` as its length
+/// to consume the rest of the read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadStructure {
+    segments: Vec<Segment>,
+}
+
+impl FromStr for ReadStructure {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(&c) = chars.peek() {
+            let len = if c == '+' {
+                chars.next();
+                None
+            } else {
+                let mut digits = String::new();
+                while let Some(&d) = chars.peek() {
+                    if !d.is_ascii_digit() {
+                        break;
+                    }
+                    digits.push(d);
+                    chars.next();
+                }
+                if digits.is_empty() {
+                    anyhow::bail!("Expected segment length or '+' at '{}' in '{}'", c, s);
+                }
+                let len: usize = digits
+                    .parse()
+                    .with_context(|| format!("Invalid segment length in '{}'", s))?;
+                if len == 0 {
+                    anyhow::bail!("Segment lengths must be positive in '{}'", s);
+                }
+                Some(len)
+            };
+
+            let code = chars
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Missing segment type at end of '{}'", s))?;
+            let kind = SegmentKind::from_code(code).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown segment type '{}' in '{}' (expected C, M, S or T)",
+                    code,
+                    s
+                )
+            })?;
+            segments.push(Segment { kind, len });
+        }
+
+        if segments.is_empty() {
+            anyhow::bail!("Read structure is empty");
+        }
+        if segments[..segments.len() - 1]
+            .iter()
+            .any(|seg| seg.len.is_none())
+        {
+            anyhow::bail!("Only the last segment may use '+' in '{}'", s);
+        }
+
+        Ok(Self { segments })
+    }
+}
+
+impl fmt::Display for ReadStructure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for seg in &self.segments {
+            match seg.len {
+                Some(len) => write!(f, "{}{}", len, seg.kind.code())?,
+                None => write!(f, "+{}", seg.kind.code())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Barcode/UMI qualities (Phred+33) sliced out of a read.
+///
+/// The bases themselves are not kept: CB/UB come from the read name.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct InReadBarcode {
+    pub cb_qual: Vec<u8>,
+    pub umi_qual: Vec<u8>,
+}
+
+impl InReadBarcode {
+    fn clear(&mut self) {
+        self.cb_qual.clear();
+        self.umi_qual.clear();
+    }
+}
+
+/// What the other records of a paired template need from read 1: its in-read
+/// qualities, and how far soft-clip trimming moves its start.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReadStart {
+    pub barcode: InReadBarcode,
+    /// Reference bases soft-clipped off the start of a forward read 1
+    pub shift: i64,
+    /// Read 1's CIGAR after soft-clip trimming, for its mates' `MC` tags
+    pub cigar: Option<String>,
+}
+
+/// Read 1 starts of paired templates, by read name.
+pub type ReadStarts = HashMap<Vec<u8>, ReadStart>;
+
+/// Collect the [`ReadStart`] of every paired template in `path`, so mates get
+/// the same CY/UY as read 1 wherever they are in the file.
+///
+/// `soft_clip` is the number of bases `--trim-in-read soft-clip` removes.
+pub fn load_read_starts(
+    path: &Path,
+    structure: &ReadStructure,
+    soft_clip: Option<usize>,
+    threads: usize,
+) -> Result<ReadStarts> {
+    let mut reader = bam::Reader::from_path(path)
+        .with_context(|| format!("Failed to open input BAM: {:?}", path))?;
+    reader.set_threads(threads.max(1))?;
+    let mut starts = ReadStarts::new();
+    let mut record = bam::Record::new();
+    let mut barcode = InReadBarcode::default();
+    while let Some(result) = reader.read(&mut record) {
+        result.with_context(|| format!("Failed to read input BAM: {:?}", path))?;
+        if !record.is_paired()
+            || record.is_secondary()
+            || record.is_supplementary()
+            || starts.contains_key(record.qname())
+            || !structure.extract_from_record(&record, &mut barcode)
+        {
+            continue;
+        }
+        let clipped = soft_clip
+            .filter(|_| !record.is_unmapped())
+            .and_then(|n| soft_clipped_cigar(&record, n));
+        starts.insert(
+            record.qname().to_vec(),
+            ReadStart {
+                barcode: barcode.clone(),
+                shift: clipped.as_ref().map_or(0, |(_, shift)| *shift),
+                cigar: clipped.map(|(cigar, _)| cigar.to_string()),
+            },
+        );
+    }
+    Ok(starts)
+}
+
+impl ReadStructure {
+    /// Number of leading non-template bases, if every `C`/`M`/`S` segment precedes the first `T`.
+    pub fn barcode_prefix_len(&self) -> Option<usize> {
+        let first_template = self
+            .segments
+            .iter()
+            .position(|seg| seg.kind == SegmentKind::Template)
+            .unwrap_or(self.segments.len());
+        if self.segments[first_template..]
+            .iter()
+            .any(|seg| seg.kind != SegmentKind::Template)
+        {
+            return None;
+        }
+        self.segments[..first_template]
+            .iter()
+            .map(|seg| seg.len)
+            .sum()
+    }
+
+    /// Extract barcode/UMI qualities from a record carrying read 1 (or an unpaired read)
+    /// into `barcode`, reusing its buffers. Returns whether anything was extracted.
+    ///
+    /// Records that are read 2 or hard-clipped return `false`, since the leading
+    /// bases of read 1 are not available in them; so do records shorter than the
+    /// fixed segments or without qualities.
+    pub fn extract_from_record(&self, record: &bam::Record, barcode: &mut InReadBarcode) -> bool {
+        if !carries_read_start(record) {
+            return false;
+        }
+
+        let qual = record.qual();
+        let len = qual.len();
+        if record.is_reverse() {
+            self.extract_with(len, |i| qual[len - 1 - i], barcode)
+        } else {
+            self.extract_with(len, |i| qual[i], barcode)
+        }
+    }
+
+    /// Core of [`ReadStructure::extract_from_record`], reading quality `i` (in
+    /// sequencing orientation) through an accessor so records need not be copied.
+    fn extract_with(
+        &self,
+        len: usize,
+        qual: impl Fn(usize) -> u8,
+        barcode: &mut InReadBarcode,
+    ) -> bool {
+        barcode.clear();
+        if len > 0 && qual(0) == 0xff {
+            return false;
+        }
+
+        let mut offset = 0;
+        for seg in &self.segments {
+            let end = match seg.len {
+                Some(seg_len) => offset + seg_len,
+                None => len,
+            };
+            if end > len {
+                return false;
+            }
+            let qual_out = match seg.kind {
+                SegmentKind::CellBarcode => &mut barcode.cb_qual,
+                SegmentKind::Umi => &mut barcode.umi_qual,
+                SegmentKind::Skip | SegmentKind::Template => {
+                    offset = end;
+                    continue;
+                }
+            };
+            qual_out.extend((offset..end).map(|i| qual(i).min(93) + 33));
+            offset = end;
+        }
+        true
+    }
+}
+
+/// How in-read barcode/UMI bases are trimmed after tagging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TrimMode {
+    /// Soft-clip the bases in aligned records
+    SoftClip,
+    /// Remove the bases from unaligned records
+    Remove,
+}
+
+/// Trim the first `n` bases (in sequencing orientation) of read 1 from `record`.
+///
+/// Returns `Ok(false)` if the record was left untouched.
+pub fn trim_read_start(record: &mut bam::Record, n: usize, mode: TrimMode) -> Result<bool> {
+    if n == 0 || !carries_read_start(record) {
+        return Ok(false);
+    }
+
+    match mode {
+        TrimMode::SoftClip => {
+            if record.is_unmapped() {
+                return Ok(false);
+            }
+            let Some((cigar, shift)) = soft_clipped_cigar(record, n) else {
+                return Ok(false);
+            };
+            let pos = record.pos() + shift;
+
+            let qname = record.qname().to_vec();
+            let seq = record.seq().as_bytes();
+            let qual = record.qual().to_vec();
+            record.set(&qname, Some(&cigar), &seq, &qual);
+            if shift != 0 && record.is_paired() && record.tid() == record.mtid() {
+                let tlen = shifted_tlen(
+                    record.insert_size(),
+                    record.pos(),
+                    record.mpos(),
+                    shift,
+                    true,
+                );
+                record.set_insert_size(tlen);
+            }
+            record.set_pos(pos);
+            let end = cigar.into_view(pos).end_pos();
+            record.set_bin(reg2bin(pos, end));
+            Ok(true)
+        }
+        TrimMode::Remove => {
+            if !record.is_unmapped() && record.cigar_len() > 0 {
+                anyhow::bail!(
+                    "--trim-in-read remove only supports unaligned records; use soft-clip for '{}'",
+                    String::from_utf8_lossy(record.qname())
+                );
+            }
+            let seq = record.seq().as_bytes();
+            let qual = record.qual().to_vec();
+            if seq.len() < n {
+                return Ok(false);
+            }
+            let range = if record.is_reverse() {
+                0..seq.len() - n
+            } else {
+                n..seq.len()
+            };
+            let qname = record.qname().to_vec();
+            record.set(&qname, None, &seq[range.clone()], &qual[range]);
+            Ok(true)
+        }
+    }
+}
+
+/// CIGAR of mapped `record` with its first `n` bases (in sequencing
+/// orientation) soft-clipped, and how far that moves its start.
+fn soft_clipped_cigar(record: &bam::Record, n: usize) -> Option<(CigarString, i64)> {
+    let mut ops: Vec<Cigar> = record.cigar().take().0;
+    if record.is_reverse() {
+        ops.reverse();
+    }
+    let (mut clipped, ref_shift) = soft_clip_prefix(&ops, n)?;
+    if record.is_reverse() {
+        clipped.reverse();
+        return Some((CigarString(clipped), 0));
+    }
+    Some((CigarString(clipped), ref_shift))
+}
+
+/// Update MPOS, TLEN and `MC` of a read 2 record after its read 1 was
+/// soft-clip trimmed as described by `start`.
+pub fn fix_mate_fields(record: &mut bam::Record, start: &ReadStart) -> Result<()> {
+    if !record.is_paired() || record.is_first_in_template() || record.mtid() < 0 {
+        return Ok(());
+    }
+    if start.shift != 0 {
+        if record.tid() == record.mtid() {
+            let tlen = shifted_tlen(
+                record.insert_size(),
+                record.mpos(),
+                record.pos(),
+                start.shift,
+                false,
+            );
+            record.set_insert_size(tlen);
+        }
+        record.set_mpos(record.mpos() + start.shift);
+    }
+    if let (Some(cigar), Ok(_)) = (start.cigar.as_deref(), record.aux(b"MC")) {
+        record
+            .remove_aux(b"MC")
+            .context("Failed to remove MC tag")?;
+        record
+            .push_aux(b"MC", bam::record::Aux::String(cigar))
+            .context("Failed to add MC tag")?;
+    }
+    Ok(())
+}
+
+/// TLEN of a read 1 (`is_r1`) or read 2 record once read 1's start moves
+/// right by `shift`; the template's right end does not move.
+fn shifted_tlen(tlen: i64, r1_start: i64, r2_start: i64, shift: i64, is_r1: bool) -> i64 {
+    if tlen == 0 {
+        return 0;
+    }
+    let right = r1_start.min(r2_start) + tlen.abs();
+    let r1_start = r1_start + shift;
+    let len = right - r1_start.min(r2_start);
+    if (r1_start <= r2_start) == is_r1 {
+        len
+    } else {
+        -len
+    }
+}
+
+/// Whether `record` holds the unclipped start of read 1.
+fn carries_read_start(record: &bam::Record) -> bool {
+    if record.is_paired() && !record.is_first_in_template() {
+        return false;
+    }
+    !record
+        .cigar()
+        .iter()
+        .any(|op| matches!(op, Cigar::HardClip(_)))
+}
+
+/// Soft-clip the first `n` query bases of `ops`, returning the new CIGAR and
+/// the number of reference bases no longer covered at its start.
+///
+/// Returns `None` if the clip would remove every aligned base.
+fn soft_clip_prefix(ops: &[Cigar], n: usize) -> Option<(Vec<Cigar>, i64)> {
+    let n = n as u32;
+    let mut clipped = 0u32;
+    let mut ref_shift = 0i64;
+    let mut rest = Vec::with_capacity(ops.len() + 1);
+
+    for &op in ops {
+        if clipped >= n {
+            rest.push(op);
+            continue;
+        }
+        let remaining = n - clipped;
+        match op {
+            Cigar::SoftClip(len) => clipped += len,
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                let take = len.min(remaining);
+                clipped += take;
+                ref_shift += i64::from(take);
+                if len > take {
+                    rest.push(match op {
+                        Cigar::Match(_) => Cigar::Match(len - take),
+                        Cigar::Equal(_) => Cigar::Equal(len - take),
+                        _ => Cigar::Diff(len - take),
+                    });
+                }
+            }
+            Cigar::Ins(len) => {
+                let take = len.min(remaining);
+                clipped += take;
+                if len > take {
+                    rest.push(Cigar::Ins(len - take));
+                }
+            }
+            Cigar::Del(len) | Cigar::RefSkip(len) => ref_shift += i64::from(len),
+            Cigar::Pad(_) => {}
+            Cigar::HardClip(_) => rest.push(op),
+        }
+    }
+
+    // A clip must not leave the read starting with a deletion, skip or insertion.
+    while let Some(&op) = rest.first() {
+        match op {
+            Cigar::Del(len) | Cigar::RefSkip(len) => ref_shift += i64::from(len),
+            Cigar::Ins(len) => clipped += len,
+            _ => break,
+        }
+        rest.remove(0);
+    }
+
+    let aligned = rest
+        .iter()
+        .any(|op| matches!(op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_)));
+    if !aligned {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(rest.len() + 1);
+    if clipped > 0 {
+        out.push(Cigar::SoftClip(clipped));
+    }
+    out.extend(rest);
+    Some((out, ref_shift))
+}
+
+/// BAI bin for a zero-based, half-open interval (SAM spec `reg2bin`).
+///
+/// Offsets are the first bin of each level, `((1 << (3 * level)) - 1) / 7`.
+fn reg2bin(beg: i64, end: i64) -> u16 {
+    let end = end - 1;
+    let bin = if beg >> 14 == end >> 14 {
+        4681 + (beg >> 14)
+    } else if beg >> 17 == end >> 17 {
+        585 + (beg >> 17)
+    } else if beg >> 20 == end >> 20 {
+        73 + (beg >> 20)
+    } else if beg >> 23 == end >> 23 {
+        9 + (beg >> 23)
+    } else if beg >> 26 == end >> 26 {
+        1 + (beg >> 26)
+    } else {
+        0
+    };
+    bin as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_read_structure() {
+        let rs: ReadStructure = "6C8M+T".parse().unwrap();
+        assert_eq!(rs.to_string(), "6C8M+T");
+        assert_eq!(rs.barcode_prefix_len(), Some(14));
+
+        let rs: ReadStructure = "4C10T2M".parse().unwrap();
+        assert_eq!(rs.barcode_prefix_len(), None);
+
+        assert!("".parse::<ReadStructure>().is_err());
+        assert!("6X+T".parse::<ReadStructure>().is_err());
+        assert!("+C8M".parse::<ReadStructure>().is_err());
+        assert!("0C+T".parse::<ReadStructure>().is_err());
+    }
+
+    fn unaligned(seq: &[u8], qual: &[u8], reverse: bool) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(b"r1", None, seq, qual);
+        if reverse {
+            record.set_reverse();
+        }
+        record
+    }
+
+    #[test]
+    fn extract_barcode_and_umi() {
+        let rs: ReadStructure = "3C1S2M+T".parse().unwrap();
+        let expected = InReadBarcode {
+            cb_qual: b"I?5".to_vec(),
+            umi_qual: b"+,".to_vec(),
+        };
+        let qual = [40, 30, 20, 0, 10, 11, 2, 2, 2, 2];
+        let mut barcode = InReadBarcode::default();
+        assert!(rs.extract_from_record(&unaligned(b"ACGTTTGGGG", &qual, false), &mut barcode));
+        assert_eq!(barcode, expected);
+
+        // Reverse-strand records are read from the end
+        let reversed: Vec<u8> = qual.iter().rev().copied().collect();
+        assert!(rs.extract_from_record(&unaligned(b"CCCCAAACGT", &reversed, true), &mut barcode));
+        assert_eq!(barcode, expected);
+
+        assert!(!rs.extract_from_record(&unaligned(b"ACGT", &[40; 4], false), &mut barcode));
+        assert!(!rs.extract_from_record(&unaligned(b"ACGTTTG", &[0xff; 7], false), &mut barcode));
+    }
+
+    #[test]
+    fn soft_clip_prefix_shifts_alignment() {
+        let (ops, shift) = soft_clip_prefix(&[Cigar::Match(10)], 4).unwrap();
+        assert_eq!(ops, vec![Cigar::SoftClip(4), Cigar::Match(6)]);
+        assert_eq!(shift, 4);
+
+        let (ops, shift) = soft_clip_prefix(
+            &[
+                Cigar::SoftClip(2),
+                Cigar::Match(3),
+                Cigar::Del(2),
+                Cigar::Match(5),
+            ],
+            4,
+        )
+        .unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Cigar::SoftClip(4),
+                Cigar::Match(1),
+                Cigar::Del(2),
+                Cigar::Match(5)
+            ]
+        );
+        assert_eq!(shift, 2);
+
+        let (ops, shift) =
+            soft_clip_prefix(&[Cigar::Match(4), Cigar::Del(3), Cigar::Match(6)], 4).unwrap();
+        assert_eq!(ops, vec![Cigar::SoftClip(4), Cigar::Match(6)]);
+        assert_eq!(shift, 7);
+
+        assert!(soft_clip_prefix(&[Cigar::Match(4)], 4).is_none());
+    }
+
+    #[test]
+    fn shifted_tlen_follows_the_new_leftmost_start() {
+        // Read 1 at 100 and read 2 at 200 span 100..310
+        assert_eq!(shifted_tlen(210, 100, 200, 6, true), 204);
+        assert_eq!(shifted_tlen(-210, 100, 200, 6, false), -204);
+        // Overlapping mates: read 1 moves past read 2's start
+        assert_eq!(shifted_tlen(10, 100, 103, 6, true), -7);
+        assert_eq!(shifted_tlen(-10, 100, 103, 6, false), 7);
+        assert_eq!(shifted_tlen(0, 100, 103, 6, true), 0);
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use rust_htslib::bam::{FetchDefinition, Read};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// A reference interval, 0-based and half-open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Region {
+    tid: u32,
+    start: i64,
+    end: i64,
+}
+
+/// Regions from `--region` strings and a BED file, sorted in header order and
+/// merged where they overlap or touch.
+pub fn load_regions(
+    specs: &[String],
+    bed: Option<&Path>,
+    header: &bam::HeaderView,
+) -> Result<Vec<Region>> {
+    let mut regions = specs
+        .iter()
+        .map(|spec| parse_region(spec, header))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(bed) = bed {
+        regions.extend(read_bed(bed, header)?);
+    }
+    Ok(merge(regions))
+}
+
+/// Parse `chr`, `chr:start` or `chr:start-end` (1-based, inclusive; commas allowed).
+fn parse_region(spec: &str, header: &bam::HeaderView) -> Result<Region> {
+    // Contig names may contain ':', so prefer the whole string as a name
+    if let Some(tid) = header.tid(spec.as_bytes()) {
+        return Ok(whole_contig(tid, header));
+    }
+    let (name, range) = spec
+        .rsplit_once(':')
+        .with_context(|| format!("Unknown reference in region '{}'", spec))?;
+    let tid = header
+        .tid(name.as_bytes())
+        .with_context(|| format!("Unknown reference in region '{}'", spec))?;
+    let contig = whole_contig(tid, header);
+
+    let position = |s: &str| -> Result<i64> {
+        s.replace(',', "")
+            .parse::<i64>()
+            .ok()
+            .filter(|&p| p >= 1)
+            .with_context(|| format!("Invalid position '{}' in region '{}'", s, spec))
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (position(start)? - 1, position(end)?),
+        None => (position(range)? - 1, contig.end),
+    };
+    if start >= end {
+        anyhow::bail!("Region '{}' is empty", spec);
+    }
+    Ok(Region {
+        start,
+        end,
+        ..contig
+    })
+}
+
+/// Read BED intervals (0-based, half-open), skipping headers and comments.
+fn read_bed(path: &Path, header: &bam::HeaderView) -> Result<Vec<Region>> {
+    let file = File::open(path).with_context(|| format!("Failed to open BED: {:?}", path))?;
+    let mut regions = Vec::new();
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.context("Failed to read BED")?;
+        if line.trim().is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let (Some(name), Some(start), Some(end)) = (fields.next(), fields.next(), fields.next())
+        else {
+            anyhow::bail!("BED line {} has fewer than 3 columns", line_no + 1);
+        };
+        let tid = header
+            .tid(name.as_bytes())
+            .with_context(|| format!("BED line {}: unknown reference '{}'", line_no + 1, name))?;
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<i64>()
+                .ok()
+                .filter(|&p| p >= 0)
+                .with_context(|| format!("BED line {}: invalid position '{}'", line_no + 1, s))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start < end {
+            regions.push(Region { tid, start, end });
+        }
+    }
+    Ok(regions)
+}
+
+fn whole_contig(tid: u32, header: &bam::HeaderView) -> Region {
+    let len = header.target_len(tid).unwrap_or(0);
+    Region {
+        tid,
+        start: 0,
+        end: i64::try_from(len).unwrap_or(i64::MAX),
+    }
+}
+
+fn merge(mut regions: Vec<Region>) -> Vec<Region> {
+    regions.sort();
+    let mut merged: Vec<Region> = Vec::with_capacity(regions.len());
+    for region in regions {
+        match merged.last_mut() {
+            Some(last) if last.tid == region.tid && region.start <= last.end => {
+                last.end = last.end.max(region.end);
+            }
+            _ => merged.push(region),
+        }
+    }
+    merged
+}
+
+/// Reads records overlapping a list of merged regions through the BAM index.
+///
+/// A record overlapping two regions is returned once, and records stay in
+/// coordinate order.
+pub struct RegionReader {
+    reader: bam::IndexedReader,
+    regions: Vec<Region>,
+    next: usize,
+    /// End of the previous region on the same reference as the current one
+    previous_end: Option<i64>,
+    fetched: bool,
+}
+
+impl RegionReader {
+    pub fn new(reader: bam::IndexedReader, regions: Vec<Region>) -> Self {
+        Self {
+            reader,
+            regions,
+            next: 0,
+            previous_end: None,
+            fetched: false,
+        }
+    }
+
+    pub fn header(&self) -> &bam::HeaderView {
+        self.reader.header()
+    }
+
+    pub fn set_threads(&mut self, threads: usize) -> Result<()> {
+        self.reader
+            .set_threads(threads)
+            .context("Failed to set reader threads")
+    }
+
+    /// Read the next record, like [`bam::Read::read`].
+    pub fn read(&mut self, record: &mut bam::Record) -> Option<Result<()>> {
+        loop {
+            if !self.fetched {
+                let region = *self.regions.get(self.next)?;
+                self.previous_end = match self.next.checked_sub(1).map(|i| self.regions[i]) {
+                    Some(previous) if previous.tid == region.tid => Some(previous.end),
+                    _ => None,
+                };
+                let tid = i32::try_from(region.tid).expect("tid fits in i32");
+                if let Err(e) =
+                    self.reader
+                        .fetch(FetchDefinition::Region(tid, region.start, region.end))
+                {
+                    return Some(Err(e).context("Failed to fetch region"));
+                }
+                self.fetched = true;
+            }
+
+            match self.reader.read(record) {
+                // Already returned for the previous region
+                Some(Ok(())) if self.previous_end.is_some_and(|end| record.pos() < end) => {}
+                Some(result) => return Some(result.context("Failed to read BAM record")),
+                None => {
+                    self.next += 1;
+                    self.fetched = false;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> bam::HeaderView {
+        let mut header = bam::Header::new();
+        for (name, len) in [("chr1", "1000"), ("HLA-A*01:01", "500")] {
+            let mut sq = bam::header::HeaderRecord::new(b"SQ");
+            sq.push_tag(b"SN", name).push_tag(b"LN", len);
+            header.push_record(&sq);
+        }
+        bam::HeaderView::from_header(&header)
+    }
+
+    #[test]
+    fn parses_region_strings() {
+        let header = header();
+        let region = |spec| parse_region(spec, &header).unwrap();
+        assert_eq!(
+            region("chr1:1-1,000"),
+            Region {
+                tid: 0,
+                start: 0,
+                end: 1000
+            }
+        );
+        assert_eq!(region("chr1:101").start, 100);
+        assert_eq!(region("chr1:101").end, 1000);
+        assert_eq!(region("HLA-A*01:01").end, 500);
+        assert_eq!(region("HLA-A*01:01:11-20").start, 10);
+        assert!(parse_region("chr2:1-10", &header).is_err());
+        assert!(parse_region("chr1:10-5", &header).is_err());
+    }
+
+    #[test]
+    fn merges_overlapping_regions_in_header_order() {
+        let region = |tid, start, end| Region { tid, start, end };
+        assert_eq!(
+            merge(vec![
+                region(1, 0, 10),
+                region(0, 50, 60),
+                region(0, 0, 20),
+                region(0, 20, 30),
+            ]),
+            [region(0, 0, 30), region(0, 50, 60), region(1, 0, 10)]
+        );
+    }
+}
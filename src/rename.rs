@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use std::collections::HashMap;
+use std::str;
+
+use crate::tag::parse_read_name;
+
+/// How read names are rewritten once tags are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RenameMode {
+    /// Leave read names unchanged
+    Keep,
+    /// Keep only the uuid, the part before the barcodes
+    Uuid,
+    /// Number templates 1, 2, ... in order of first appearance
+    Counter,
+}
+
+/// Rewrites read names so that every record of a template gets the same new name.
+#[derive(Debug)]
+pub struct Renamer {
+    mode: RenameMode,
+    next_id: u64,
+    /// Counter mode: number of every template seen, by original name
+    ids: HashMap<Vec<u8>, u64>,
+    name: String,
+    n_renamed: u64,
+}
+
+impl Renamer {
+    pub fn new(mode: RenameMode) -> Self {
+        Self {
+            mode,
+            next_id: 1,
+            ids: HashMap::new(),
+            name: String::new(),
+            n_renamed: 0,
+        }
+    }
+
+    /// Number of records given a new name.
+    pub fn n_renamed(&self) -> u64 {
+        self.n_renamed
+    }
+
+    pub fn rename(&mut self, record: &mut bam::Record) -> Result<()> {
+        self.name.clear();
+        match self.mode {
+            RenameMode::Keep => return Ok(()),
+            RenameMode::Uuid => {
+                let qname =
+                    str::from_utf8(record.qname()).context("Read name is not valid UTF-8")?;
+                // Unparseable names were not tagged, so there is nothing to strip
+                if parse_read_name(qname).is_err() {
+                    return Ok(());
+                }
+                self.name.push_str(qname.split('_').next().unwrap_or(qname));
+            }
+            RenameMode::Counter => {
+                let id = self.template_id(record);
+                self.name.push_str(&id.to_string());
+            }
+        }
+        record.set_qname(self.name.as_bytes());
+        self.n_renamed += 1;
+        Ok(())
+    }
+
+    /// Number of `record`'s template; kept for the whole run, as secondary
+    /// alignments may come any time after their primaries.
+    fn template_id(&mut self, record: &bam::Record) -> u64 {
+        if let Some(&id) = self.ids.get(record.qname()) {
+            return id;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(record.qname().to_vec(), id);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+
+    fn record(qname: &str, flags: u16) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(qname.as_bytes(), None, b"A", b"I");
+        record.set_flags(flags);
+        record
+    }
+
+    fn renamed(renamer: &mut Renamer, mut record: bam::Record) -> String {
+        renamer.rename(&mut record).unwrap();
+        String::from_utf8(record.qname().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn uuid_mode_keeps_first_part() {
+        let mut renamer = Renamer::new(RenameMode::Uuid);
+        assert_eq!(
+            renamed(&mut renamer, record("2efc6b85-aa0d_AA-CC-GG_TT", 0)),
+            "2efc6b85-aa0d"
+        );
+        assert_eq!(renamed(&mut renamer, record("plain", 0)), "plain");
+        assert_eq!(renamer.n_renamed(), 1);
+    }
+
+    #[test]
+    fn counter_mode_gives_mates_one_number() {
+        let mut renamer = Renamer::new(RenameMode::Counter);
+        let mut with_sa = record("b", 0x1 | 0x40);
+        with_sa
+            .push_aux(b"SA", Aux::String("chr1,100,+,4M,60,0;"))
+            .unwrap();
+        let names: Vec<String> = [
+            record("a", 0x1 | 0x40),
+            with_sa,
+            record("c", 0),
+            record("b", 0x1 | 0x80),
+            record("a", 0x1 | 0x80),
+            record("b", 0x1 | 0x40 | 0x800),
+            record("a", 0x1 | 0x100),
+        ]
+        .into_iter()
+        .map(|r| renamed(&mut renamer, r))
+        .collect();
+        // A secondary alignment after both mates keeps the template's number
+        assert_eq!(names, ["1", "2", "3", "2", "1", "2", "1"]);
+        assert_eq!(renamer.ids.len(), 3);
+    }
+}
@@ -0,0 +1,272 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use rust_htslib::bam::header::HeaderRecord;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// One sample sheet row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Sample {
+    name: String,
+    library: Option<String>,
+    i7: String,
+    /// Empty when the sample is identified by i7 alone
+    i5: String,
+}
+
+/// Maps i7/i5 sample indices to read groups, tolerating sequencing errors.
+///
+/// The sheet is a CSV with a header row naming at least the `sample` and `i7`
+/// columns, plus optional `i5` and `library` columns. Each sample becomes one
+/// `@RG` line with `ID` and `SM` set to the sample name.
+pub struct SampleSheet {
+    samples: Vec<Sample>,
+    max_mismatches: usize,
+    /// Lookup results per observed (i7, i5), since few distinct pairs occur
+    cache: HashMap<(String, String), Option<usize>>,
+    assigned: Vec<u64>,
+    n_unassigned: u64,
+}
+
+impl SampleSheet {
+    pub fn from_path(path: &Path, max_mismatches: usize) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open sample sheet: {:?}", path))?;
+        let samples = parse_sample_sheet(BufReader::new(file))
+            .with_context(|| format!("Invalid sample sheet: {:?}", path))?;
+        Ok(Self {
+            assigned: vec![0; samples.len()],
+            samples,
+            max_mismatches,
+            cache: HashMap::new(),
+            n_unassigned: 0,
+        })
+    }
+
+    /// Add one `@RG` line per sample, failing if the input already uses its ID.
+    pub fn add_read_groups(&self, header: &mut bam::Header) -> Result<()> {
+        let existing: HashSet<String> = header
+            .to_hashmap()
+            .get("RG")
+            .into_iter()
+            .flatten()
+            .filter_map(|rg| rg.get("ID").cloned())
+            .collect();
+        for sample in &self.samples {
+            if existing.contains(&sample.name) {
+                anyhow::bail!(
+                    "Input header already has a read group with ID '{}'",
+                    sample.name
+                );
+            }
+            let mut rg = HeaderRecord::new(b"RG");
+            rg.push_tag(b"ID", &sample.name)
+                .push_tag(b"SM", &sample.name);
+            if let Some(library) = sample.library.as_ref() {
+                rg.push_tag(b"LB", library);
+            }
+            header.push_record(&rg);
+        }
+        Ok(())
+    }
+
+    /// Read group of the sample matching `i7`/`i5`, or `None` if no sample
+    /// matches or the best match is ambiguous.
+    pub fn assign(&mut self, i7: &str, i5: &str) -> Option<&str> {
+        let key = (i7.to_string(), i5.to_string());
+        let index = match self.cache.get(&key) {
+            Some(&index) => index,
+            None => {
+                let index = best_match(&self.samples, i7, i5, self.max_mismatches);
+                self.cache.insert(key, index);
+                index
+            }
+        };
+        match index {
+            Some(index) => {
+                self.assigned[index] += 1;
+                Some(&self.samples[index].name)
+            }
+            None => {
+                self.n_unassigned += 1;
+                None
+            }
+        }
+    }
+
+    /// Records assigned per sample, in sheet order.
+    pub fn assigned(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.samples
+            .iter()
+            .zip(&self.assigned)
+            .map(|(sample, &n)| (sample.name.as_str(), n))
+    }
+
+    /// Records whose indices matched no sample.
+    pub fn n_unassigned(&self) -> u64 {
+        self.n_unassigned
+    }
+}
+
+fn parse_sample_sheet<R: BufRead>(reader: R) -> Result<Vec<Sample>> {
+    let mut lines = reader.lines().enumerate().filter(|(_, line)| {
+        line.as_ref()
+            .map_or(true, |l| !l.trim().is_empty() && !l.starts_with('#'))
+    });
+
+    let (_, header) = lines.next().context("Sample sheet is empty")?;
+    let header = header.context("Failed to read sample sheet header")?;
+    let columns: Vec<String> = header
+        .split(',')
+        .map(|c| c.trim().to_ascii_lowercase())
+        .collect();
+    let column = |name: &str| columns.iter().position(|c| c == name);
+    let sample_col = column("sample").context("Sample sheet has no 'sample' column")?;
+    let i7_col = column("i7").context("Sample sheet has no 'i7' column")?;
+    let i5_col = column("i5");
+    let library_col = column("library");
+
+    let mut samples: Vec<Sample> = Vec::new();
+    for (line_no, line) in lines {
+        let line = line.context("Failed to read sample sheet")?;
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let field = |col: usize| fields.get(col).copied().unwrap_or("");
+
+        let sample = Sample {
+            name: field(sample_col).to_string(),
+            library: library_col
+                .map(field)
+                .filter(|l| !l.is_empty())
+                .map(str::to_string),
+            i7: field(i7_col).to_ascii_uppercase(),
+            i5: i5_col.map(field).unwrap_or("").to_ascii_uppercase(),
+        };
+        if sample.name.is_empty() || sample.name.contains(char::is_whitespace) {
+            anyhow::bail!(
+                "Line {}: sample name must be non-empty without whitespace",
+                line_no + 1
+            );
+        }
+        if sample.i7.is_empty() {
+            anyhow::bail!(
+                "Line {}: sample '{}' has no i7 index",
+                line_no + 1,
+                sample.name
+            );
+        }
+        if let Some(other) = samples.iter().find(|s| s.name == sample.name) {
+            anyhow::bail!("Line {}: duplicate sample '{}'", line_no + 1, other.name);
+        }
+        if let Some(other) = samples
+            .iter()
+            .find(|s| s.i7 == sample.i7 && s.i5 == sample.i5)
+        {
+            anyhow::bail!(
+                "Line {}: sample '{}' has the same indices as '{}'",
+                line_no + 1,
+                sample.name,
+                other.name
+            );
+        }
+        samples.push(sample);
+    }
+
+    if samples.is_empty() {
+        anyhow::bail!("Sample sheet has no samples");
+    }
+    Ok(samples)
+}
+
+/// Index of the unique sample with the fewest total mismatches, each index
+/// within `max_mismatches`.
+fn best_match(samples: &[Sample], i7: &str, i5: &str, max_mismatches: usize) -> Option<usize> {
+    let mut best: Option<(usize, usize)> = None;
+    let mut tied = false;
+    for (index, sample) in samples.iter().enumerate() {
+        let Some(i7_mm) = mismatches(&sample.i7, i7).filter(|&mm| mm <= max_mismatches) else {
+            continue;
+        };
+        let i5_mm = if sample.i5.is_empty() {
+            0
+        } else {
+            match mismatches(&sample.i5, i5).filter(|&mm| mm <= max_mismatches) {
+                Some(mm) => mm,
+                None => continue,
+            }
+        };
+        let total = i7_mm + i5_mm;
+        match best {
+            Some((_, best_total)) if total > best_total => {}
+            Some((_, best_total)) if total == best_total => tied = true,
+            _ => {
+                best = Some((index, total));
+                tied = false;
+            }
+        }
+    }
+    if tied {
+        return None;
+    }
+    best.map(|(index, _)| index)
+}
+
+/// Hamming distance between an expected and an observed index; `N` always mismatches.
+fn mismatches(expected: &str, observed: &str) -> Option<usize> {
+    if expected.len() != observed.len() {
+        return None;
+    }
+    Some(
+        expected
+            .bytes()
+            .zip(observed.bytes())
+            .filter(|&(e, o)| e != o.to_ascii_uppercase() || o == b'N')
+            .count(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(csv: &str) -> Vec<Sample> {
+        parse_sample_sheet(csv.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parses_columns_in_any_order() {
+        let samples = sheet("# pool 1\ni5,Sample,i7,library\nGGTT,s1,aacc,lib1\n\n,s2,TTGG,\n");
+        assert_eq!(
+            samples,
+            [
+                Sample {
+                    name: "s1".into(),
+                    library: Some("lib1".into()),
+                    i7: "AACC".into(),
+                    i5: "GGTT".into(),
+                },
+                Sample {
+                    name: "s2".into(),
+                    library: None,
+                    i7: "TTGG".into(),
+                    i5: String::new(),
+                },
+            ]
+        );
+        assert!(parse_sample_sheet("sample,i7\ns1,AA\ns2,AA\n".as_bytes()).is_err());
+        assert!(parse_sample_sheet("name,i7\ns1,AA\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn best_match_tolerates_mismatches_but_not_ties() {
+        let samples = sheet("sample,i7,i5\ns1,AAAA,CCCC\ns2,AATT,CCCC\ns3,GGGG,\n");
+        assert_eq!(best_match(&samples, "AAAA", "CCCC", 1), Some(0));
+        assert_eq!(best_match(&samples, "AAAA", "CCCG", 1), Some(0));
+        assert_eq!(best_match(&samples, "AAAA", "CCGG", 1), None);
+        // One mismatch from both s1 and s2
+        assert_eq!(best_match(&samples, "AATA", "CCCC", 1), None);
+        assert_eq!(best_match(&samples, "GGGN", "ACGT", 1), Some(2));
+        assert_eq!(best_match(&samples, "GGGN", "ACGT", 0), None);
+    }
+}
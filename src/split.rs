@@ -0,0 +1,446 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use rust_htslib::tpool::ThreadPool;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::tag::parse_read_name;
+use crate::umi::aux_string;
+
+/// Bucket for records without a (allowed) split key.
+const OTHER_BUCKET: &str = "other";
+
+/// Bytes of records held in memory for buckets without an open writer before
+/// the largest such bucket is spilled to disk.
+const BUFFER_LIMIT: usize = 256 << 20;
+
+/// Which barcode decides the output file of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SplitBy {
+    /// Cell barcode (CB tag)
+    Cb,
+    /// i7 sample index from the read name
+    I7,
+    /// i5 sample index from the read name
+    I5,
+    /// i7 and i5 sample indices from the read name, as `{i7}-{i5}`
+    #[value(name = "i7+i5")]
+    I7I5,
+}
+
+impl SplitBy {
+    /// Split key of `record`, if it has one.
+    fn key(self, record: &bam::Record) -> Option<String> {
+        if self == SplitBy::Cb {
+            return aux_string(record, b"CB").map(|cb| String::from_utf8_lossy(cb).into_owned());
+        }
+        let qname = std::str::from_utf8(record.qname()).ok()?;
+        let components = parse_read_name(qname).ok()?;
+        Some(match self {
+            SplitBy::I7 => components.i7.to_string(),
+            SplitBy::I5 => components.i5.to_string(),
+            _ => format!("{}-{}", components.i7, components.i5),
+        })
+    }
+}
+
+/// Records of a bucket without an open writer, kept until [`SplitWriter::finish`].
+#[derive(Default)]
+struct Overflow {
+    buffered: Vec<bam::Record>,
+    bytes: usize,
+    /// Whether earlier records were appended to the bucket's spill file
+    spilled: bool,
+}
+
+/// Writes records into one BAM per split key under a directory.
+///
+/// The first `max_open` buckets get a writer that stays open. Records of later
+/// buckets are buffered in memory, the largest buffer being appended to a
+/// hidden headerless spill file when the buffers outgrow [`BUFFER_LIMIT`], and
+/// each such bucket's BAM is written once by [`SplitWriter::finish`].
+pub struct SplitWriter {
+    dir: PathBuf,
+    header: bam::Header,
+    by: SplitBy,
+    allowlist: Option<HashSet<String>>,
+    max_open: usize,
+    open: HashMap<String, bam::Writer>,
+    overflow: HashMap<String, Overflow>,
+    buffered_bytes: usize,
+    buffer_limit: usize,
+    counts: HashMap<String, u64>,
+    // Declared last so open writers are dropped before the pool they use
+    tpool: ThreadPool,
+}
+
+impl SplitWriter {
+    pub fn new(
+        dir: &Path,
+        header: &bam::Header,
+        by: SplitBy,
+        allowlist: Option<&Path>,
+        max_open: usize,
+        threads: usize,
+    ) -> Result<Self> {
+        if max_open == 0 {
+            anyhow::bail!("--max-open-files must be at least 1");
+        }
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create split directory: {:?}", dir))?;
+        let allowlist = allowlist.map(load_allowlist).transpose()?;
+        let thread_count = u32::try_from(threads.max(1)).context("Thread count exceeds u32")?;
+        let tpool = ThreadPool::new(thread_count).context("Failed to create split thread pool")?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            header: header.clone(),
+            by,
+            allowlist,
+            max_open,
+            open: HashMap::new(),
+            overflow: HashMap::new(),
+            buffered_bytes: 0,
+            buffer_limit: BUFFER_LIMIT,
+            counts: HashMap::new(),
+            tpool,
+        })
+    }
+
+    /// Write `record` to the BAM of its split key (or the "other" bucket).
+    pub fn write(&mut self, record: &bam::Record) -> Result<()> {
+        self.write_to(self.bucket(record), record)
+    }
+
+    /// Bucket of `record`: its split key, or "other" if it has none or the key
+    /// is not allowed.
+    pub fn bucket(&self, record: &bam::Record) -> String {
+        match self.by.key(record) {
+            Some(key)
+                if is_safe_file_stem(&key)
+                    && self
+                        .allowlist
+                        .as_ref()
+                        .is_none_or(|allow| allow.contains(&key)) =>
+            {
+                key
+            }
+            _ => OTHER_BUCKET.to_string(),
+        }
+    }
+
+    /// Write `record` to the BAM of `key`, a bucket from [`SplitWriter::bucket`].
+    pub fn write_to(&mut self, key: String, record: &bam::Record) -> Result<()> {
+        *self.counts.entry(key.clone()).or_insert(0) += 1;
+
+        if !self.open.contains_key(&key)
+            && !self.overflow.contains_key(&key)
+            && self.open.len() < self.max_open
+        {
+            let writer = self.create_writer(&key)?;
+            self.open.insert(key.clone(), writer);
+        }
+        if let Some(writer) = self.open.get_mut(&key) {
+            return writer
+                .write(record)
+                .with_context(|| format!("Failed to write split BAM record for '{}'", key));
+        }
+
+        let size = record_size(record);
+        let overflow = self.overflow.entry(key).or_default();
+        overflow.buffered.push(record.clone());
+        overflow.bytes += size;
+        self.buffered_bytes += size;
+        if self.buffered_bytes > self.buffer_limit {
+            self.spill_largest()?;
+        }
+        Ok(())
+    }
+
+    /// Close every writer and write the buffered buckets; returns records written per bucket.
+    pub fn finish(mut self) -> Result<HashMap<String, u64>> {
+        self.open.clear();
+
+        let mut keys: Vec<String> = self.overflow.keys().cloned().collect();
+        keys.sort();
+        for key in keys {
+            let overflow = self.overflow.remove(&key).expect("key exists");
+            let mut writer = self.create_writer(&key)?;
+            if overflow.spilled {
+                let spill_path = self.spill_path(&key);
+                let file = File::open(&spill_path).with_context(|| {
+                    format!("Failed to open split spill file: {:?}", spill_path)
+                })?;
+                let mut reader = BufReader::new(file);
+                while let Some(record) = read_raw_record(&mut reader)
+                    .with_context(|| format!("Failed to read split spill file: {:?}", spill_path))?
+                {
+                    writer
+                        .write(&record)
+                        .context("Failed to write spilled split record")?;
+                }
+                std::fs::remove_file(&spill_path).with_context(|| {
+                    format!("Failed to remove split spill file: {:?}", spill_path)
+                })?;
+            }
+            for record in &overflow.buffered {
+                writer
+                    .write(record)
+                    .context("Failed to write buffered split record")?;
+            }
+        }
+
+        Ok(self.counts)
+    }
+
+    fn create_writer(&self, key: &str) -> Result<bam::Writer> {
+        let path = bucket_file(&self.dir, key);
+        let mut writer = bam::Writer::from_path(&path, &self.header, bam::Format::Bam)
+            .with_context(|| format!("Failed to create split BAM: {:?}", path))?;
+        writer.set_thread_pool(&self.tpool)?;
+        Ok(writer)
+    }
+
+    /// Append the largest buffer to its bucket's spill file.
+    fn spill_largest(&mut self) -> Result<()> {
+        let Some(key) = self
+            .overflow
+            .iter()
+            .max_by_key(|(_, overflow)| overflow.bytes)
+            .map(|(key, _)| key.clone())
+        else {
+            return Ok(());
+        };
+        let spill_path = self.spill_path(&key);
+        let overflow = self.overflow.get_mut(&key).expect("key exists");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&spill_path)
+            .with_context(|| format!("Failed to open split spill file: {:?}", spill_path))?;
+        let mut writer = BufWriter::new(file);
+        for record in overflow.buffered.drain(..) {
+            write_raw_record(&mut writer, &record)?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("Failed to write split spill file: {:?}", spill_path))?;
+        self.buffered_bytes -= overflow.bytes;
+        overflow.bytes = 0;
+        overflow.spilled = true;
+        Ok(())
+    }
+
+    fn spill_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!(".{}.spill", key))
+    }
+}
+
+/// Approximate memory held by a buffered record.
+fn record_size(record: &bam::Record) -> usize {
+    std::mem::size_of::<bam::Record>() + record.inner().l_data as usize
+}
+
+/// Write the fixed fields and variable-length data of `record`, as in a BAM
+/// file but without BGZF compression.
+fn write_raw_record<W: Write>(writer: &mut W, record: &bam::Record) -> Result<()> {
+    let inner = record.inner();
+    let core = &inner.core;
+    // SAFETY: `data` holds `l_data` initialised bytes owned by the record
+    let data = unsafe { std::slice::from_raw_parts(inner.data, inner.l_data as usize) };
+    let mut fixed = Vec::with_capacity(48);
+    fixed.extend_from_slice(&core.pos.to_le_bytes());
+    fixed.extend_from_slice(&core.tid.to_le_bytes());
+    fixed.extend_from_slice(&core.bin.to_le_bytes());
+    fixed.push(core.qual);
+    fixed.push(core.l_extranul);
+    fixed.extend_from_slice(&core.flag.to_le_bytes());
+    fixed.extend_from_slice(&core.l_qname.to_le_bytes());
+    fixed.extend_from_slice(&core.n_cigar.to_le_bytes());
+    fixed.extend_from_slice(&core.l_qseq.to_le_bytes());
+    fixed.extend_from_slice(&core.mtid.to_le_bytes());
+    fixed.extend_from_slice(&core.mpos.to_le_bytes());
+    fixed.extend_from_slice(&core.isize_.to_le_bytes());
+    fixed.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    writer.write_all(&fixed)?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+/// Read a record written by [`write_raw_record`], or `None` at end of input.
+fn read_raw_record<R: Read>(reader: &mut R) -> Result<Option<bam::Record>> {
+    let mut fixed = [0u8; RAW_FIXED_LEN];
+    match reader.read_exact(&mut fixed) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut fields = FixedFields(&fixed);
+    let mut record = bam::Record::new();
+    let core = &mut record.inner_mut().core;
+    core.pos = i64::from_le_bytes(fields.take());
+    core.tid = i32::from_le_bytes(fields.take());
+    core.bin = u16::from_le_bytes(fields.take());
+    core.qual = fields.take::<1>()[0];
+    core.l_extranul = fields.take::<1>()[0];
+    core.flag = u16::from_le_bytes(fields.take());
+    core.l_qname = u16::from_le_bytes(fields.take());
+    core.n_cigar = u32::from_le_bytes(fields.take());
+    core.l_qseq = i32::from_le_bytes(fields.take());
+    core.mtid = i32::from_le_bytes(fields.take());
+    core.mpos = i64::from_le_bytes(fields.take());
+    core.isize_ = i64::from_le_bytes(fields.take());
+    let mut data = vec![0u8; u32::from_le_bytes(fields.take()) as usize];
+    reader
+        .read_exact(&mut data)
+        .context("Truncated split spill record")?;
+    record.set_data(&data);
+    Ok(Some(record))
+}
+
+/// Length of the fixed fields written by [`write_raw_record`].
+const RAW_FIXED_LEN: usize = 8 + 4 + 2 + 1 + 1 + 2 + 2 + 4 + 4 + 4 + 8 + 8 + 4;
+
+/// Cursor over the fixed fields of a raw record.
+struct FixedFields<'a>(&'a [u8]);
+
+impl FixedFields<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let (field, rest) = self.0.split_at(N);
+        self.0 = rest;
+        field.try_into().expect("split at N")
+    }
+}
+
+/// Final BAM of the `key` bucket under `dir`.
+pub fn bucket_file(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.bam", key))
+}
+
+/// Keys become file names, so only allow characters that cannot escape the directory.
+fn is_safe_file_stem(key: &str) -> bool {
+    !key.is_empty()
+        && key != OTHER_BUCKET
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'+'))
+}
+
+/// Read one barcode per line, ignoring blank lines and `#` comments.
+fn load_allowlist(path: &Path) -> Result<HashSet<String>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open split allowlist: {:?}", path))?;
+    let mut allow = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.context("Failed to read split allowlist")?;
+        let barcode = line.trim();
+        if barcode.is_empty() || barcode.starts_with('#') {
+            continue;
+        }
+        if barcode == OTHER_BUCKET {
+            anyhow::bail!(
+                "'{}' is reserved and cannot be in the allowlist",
+                OTHER_BUCKET
+            );
+        }
+        allow.insert(barcode.to_string());
+    }
+    Ok(allow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+    use rust_htslib::bam::Read as _;
+    use tempfile::TempDir;
+
+    fn record(name: &str, cb: &str, pos: i64) -> bam::Record {
+        let cigar = CigarString(vec![Cigar::SoftClip(1), Cigar::Match(3)]);
+        let mut record = bam::Record::new();
+        record.set(name.as_bytes(), Some(&cigar), b"ACGT", &[30, 31, 32, 33]);
+        record.set_flags(0x1 | 0x10 | 0x40);
+        record.set_tid(0);
+        record.set_pos(pos);
+        record.set_mapq(60);
+        record.set_mtid(0);
+        record.set_mpos(pos + 100);
+        record.set_insert_size(-104);
+        record.push_aux(b"CB", Aux::String(cb)).unwrap();
+        record
+    }
+
+    #[test]
+    fn raw_records_round_trip() {
+        let original = record("r1", "AACC", 42);
+        let mut bytes = Vec::new();
+        write_raw_record(&mut bytes, &original).unwrap();
+        write_raw_record(&mut bytes, &original).unwrap();
+
+        let mut reader = bytes.as_slice();
+        for _ in 0..2 {
+            let copy = read_raw_record(&mut reader).unwrap().unwrap();
+            assert_eq!(copy.qname(), b"r1");
+            assert_eq!(copy.cigar().to_string(), "1S3M");
+            assert_eq!(copy.seq().as_bytes(), b"ACGT");
+            assert_eq!(copy.qual(), &[30, 31, 32, 33]);
+            assert_eq!(copy.aux(b"CB").unwrap(), Aux::String("AACC"));
+            assert_eq!(
+                (copy.flags(), copy.tid(), copy.pos(), copy.mapq()),
+                (0x1 | 0x10 | 0x40, 0, 42, 60)
+            );
+            assert_eq!(
+                (copy.mtid(), copy.mpos(), copy.insert_size()),
+                (0, 142, -104)
+            );
+        }
+        assert!(read_raw_record(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn buckets_beyond_open_limit_spill_to_one_file_each() {
+        let dir = TempDir::new().unwrap();
+        let mut header = bam::Header::new();
+        let mut sq = bam::header::HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1").push_tag(b"LN", 100000);
+        header.push_record(&sq);
+
+        let mut split = SplitWriter::new(dir.path(), &header, SplitBy::Cb, None, 2, 1).unwrap();
+        // Spill on every record, the worst case for the number of files
+        split.buffer_limit = 1;
+        let cells = ["AA", "CC", "GG", "TT", "AC"];
+        for i in 0..20 {
+            for cell in cells {
+                split
+                    .write(&record(&format!("{}{}", cell, i), cell, i))
+                    .unwrap();
+            }
+        }
+
+        let mut files: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [".AC.spill", ".GG.spill", ".TT.spill", "AA.bam", "CC.bam"]
+        );
+
+        let counts = split.finish().unwrap();
+        assert!(cells.iter().all(|cell| counts[*cell] == 20));
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, cells.len());
+        for cell in cells {
+            let mut reader = bam::Reader::from_path(bucket_file(dir.path(), cell)).unwrap();
+            let names: Vec<String> = reader
+                .records()
+                .map(|r| String::from_utf8(r.unwrap().qname().to_vec()).unwrap())
+                .collect();
+            let expected: Vec<String> = (0..20).map(|i| format!("{}{}", cell, i)).collect();
+            assert_eq!(names, expected);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+
+use crate::bq::BqMap;
+use crate::tag::parse_read_name;
+
+/// Read name segments, in report order.
+const SEGMENTS: [&str; 4] = ["i7", "i5", "CBC", "UMI"];
+
+/// Bases counted per position; anything else counts as N.
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+#[derive(Debug, Default, Clone, Copy)]
+struct PositionCounts {
+    bases: [u64; 5],
+    quality_sum: u64,
+    n_quality: u64,
+}
+
+/// Lengths, base composition and qualities of one segment.
+#[derive(Debug, Default)]
+struct SegmentStats {
+    lengths: BTreeMap<usize, u64>,
+    positions: Vec<PositionCounts>,
+}
+
+impl SegmentStats {
+    fn add(&mut self, seq: &str, qual: Option<&[u8]>) {
+        *self.lengths.entry(seq.len()).or_insert(0) += 1;
+        if self.positions.len() < seq.len() {
+            self.positions.resize(seq.len(), PositionCounts::default());
+        }
+        for (i, base) in seq.bytes().enumerate() {
+            let position = &mut self.positions[i];
+            let index = BASES
+                .iter()
+                .position(|&b| b == base.to_ascii_uppercase())
+                .unwrap_or(4);
+            position.bases[index] += 1;
+            if let Some(&q) = qual.and_then(|qual| qual.get(i)) {
+                position.quality_sum += u64::from(q.saturating_sub(33));
+                position.n_quality += 1;
+            }
+        }
+    }
+}
+
+/// Barcode/UMI statistics gathered from read names without tagging.
+///
+/// Each template counts once: secondary, supplementary and second-mate
+/// records are left to the caller to skip.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub n_reads: u64,
+    pub n_parsed: u64,
+    /// Parsed reads looked up in, and found in, the BQ source
+    pub n_bq_looked_up: u64,
+    pub n_bq_found: u64,
+    segments: [SegmentStats; 4],
+    barcodes: HashMap<String, u64>,
+    /// Reads per cell barcode and UMI
+    molecules: HashMap<(String, String), u64>,
+}
+
+impl Stats {
+    pub fn add(&mut self, qname: &str, bq_map: Option<&BqMap>) {
+        self.n_reads += 1;
+        let Ok(name) = parse_read_name(qname) else {
+            return;
+        };
+        self.n_parsed += 1;
+
+        let quals = bq_map.and_then(|map| map.get(qname));
+        if bq_map.is_some() {
+            self.n_bq_looked_up += 1;
+            self.n_bq_found += u64::from(quals.is_some());
+        }
+        // CY concatenates i7, i5 and CBC, so split it at the name's segment lengths
+        let cb_qual = quals.map(|q| q.cb.as_bytes());
+        let (i7_end, i5_end) = (name.i7.len(), name.i7.len() + name.i5.len());
+        let segment_qual = |start: usize, end: usize| {
+            cb_qual
+                .and_then(|qual| (qual.len() == i5_end + name.cbc.len()).then(|| &qual[start..end]))
+        };
+        let umi_qual = quals
+            .and_then(|q| q.umi.as_deref())
+            .map(str::as_bytes)
+            .filter(|qual| qual.len() == name.umi.len());
+
+        let [i7, i5, cbc, umi] = &mut self.segments;
+        i7.add(name.i7, segment_qual(0, i7_end));
+        i5.add(name.i5, segment_qual(i7_end, i5_end));
+        cbc.add(name.cbc, segment_qual(i5_end, i5_end + name.cbc.len()));
+        umi.add(name.umi, umi_qual);
+
+        let barcode = [name.i7, name.i5, name.cbc].concat();
+        *self
+            .molecules
+            .entry((barcode.clone(), name.umi.to_string()))
+            .or_insert(0) += 1;
+        *self.barcodes.entry(barcode).or_insert(0) += 1;
+    }
+
+    /// Write the report as `#`-headed TSV sections.
+    pub fn write<W: Write>(&self, out: &mut W, top_n: usize) -> Result<()> {
+        let fraction = |n: u64, d: u64| {
+            if d == 0 {
+                "NA".to_string()
+            } else {
+                format!("{:.4}", n as f64 / d as f64)
+            }
+        };
+
+        writeln!(out, "# summary")?;
+        writeln!(out, "reads\t{}", self.n_reads)?;
+        writeln!(out, "parsed\t{}", self.n_parsed)?;
+        writeln!(out, "unparseable\t{}", self.n_reads - self.n_parsed)?;
+        writeln!(out, "parse_rate\t{}", fraction(self.n_parsed, self.n_reads))?;
+        if self.n_bq_looked_up > 0 {
+            writeln!(out, "bq_found\t{}", self.n_bq_found)?;
+            writeln!(
+                out,
+                "bq_coverage\t{}",
+                fraction(self.n_bq_found, self.n_bq_looked_up)
+            )?;
+        }
+
+        writeln!(out, "# lengths")?;
+        writeln!(out, "segment\tlength\treads")?;
+        for (segment, stats) in SEGMENTS.iter().zip(&self.segments) {
+            for (length, n) in &stats.lengths {
+                writeln!(out, "{}\t{}\t{}", segment, length, n)?;
+            }
+        }
+
+        writeln!(out, "# composition")?;
+        writeln!(out, "segment\tposition\tA\tC\tG\tT\tN\tmean_quality")?;
+        for (segment, stats) in SEGMENTS.iter().zip(&self.segments) {
+            for (i, position) in stats.positions.iter().enumerate() {
+                let [a, c, g, t, n] = position.bases;
+                let quality = match position.n_quality {
+                    0 => "NA".to_string(),
+                    count => format!("{:.2}", position.quality_sum as f64 / count as f64),
+                };
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    segment,
+                    i + 1,
+                    a,
+                    c,
+                    g,
+                    t,
+                    n,
+                    quality
+                )?;
+            }
+        }
+
+        writeln!(out, "# top_barcodes")?;
+        writeln!(out, "barcode\treads\tumis")?;
+        let mut umis_per_cell: HashMap<&str, u64> = HashMap::new();
+        for (barcode, _) in self.molecules.keys() {
+            *umis_per_cell.entry(barcode).or_insert(0) += 1;
+        }
+        let mut barcodes: Vec<(&String, &u64)> = self.barcodes.iter().collect();
+        barcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (barcode, n) in barcodes.into_iter().take(top_n) {
+            writeln!(
+                out,
+                "{}\t{}\t{}",
+                barcode,
+                n,
+                umis_per_cell[barcode.as_str()]
+            )?;
+        }
+
+        writeln!(out, "# umis")?;
+        let mut cells_per_umi: HashMap<&str, u64> = HashMap::new();
+        for (_, umi) in self.molecules.keys() {
+            *cells_per_umi.entry(umi).or_insert(0) += 1;
+        }
+        let shared = cells_per_umi.values().filter(|&&n| n > 1).count() as u64;
+        writeln!(out, "cell_umi_pairs\t{}", self.molecules.len())?;
+        writeln!(
+            out,
+            "reads_per_cell_umi\t{}",
+            fraction(self.n_parsed, self.molecules.len() as u64)
+        )?;
+        writeln!(out, "distinct_umis\t{}", cells_per_umi.len())?;
+        writeln!(
+            out,
+            "umis_in_multiple_cells\t{}",
+            fraction(shared, cells_per_umi.len() as u64)
+        )?;
+        writeln!(
+            out,
+            "expected_collision_rate\t{}",
+            self.expected_collision_rate(&umis_per_cell)
+                .map_or("NA".to_string(), |rate| format!("{:.6}", rate))
+        )?;
+        Ok(())
+    }
+
+    /// Expected fraction of molecules sharing their UMI with another molecule
+    /// of the same cell, if UMIs were uniformly random: for a cell with `n`
+    /// UMIs of length `L`, `1 - (1 - 4^-L)^(n - 1)`, weighted by `n`.
+    fn expected_collision_rate(&self, umis_per_cell: &HashMap<&str, u64>) -> Option<f64> {
+        let lengths: HashSet<usize> = self.molecules.keys().map(|(_, umi)| umi.len()).collect();
+        let &[length] = lengths.iter().collect::<Vec<_>>().as_slice() else {
+            return None;
+        };
+        let p = 0.25f64.powi(i32::try_from(*length).ok()?);
+        let total: u64 = umis_per_cell.values().sum();
+        let colliding: f64 = umis_per_cell
+            .values()
+            .map(|&n| n as f64 * (1.0 - (1.0 - p).powf(n as f64 - 1.0)))
+            .sum();
+        Some(colliding / total as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(stats: &Stats) -> String {
+        let mut out = Vec::new();
+        stats.write(&mut out, 2).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn summarises_segments_and_barcodes() {
+        let mut stats = Stats::default();
+        for name in [
+            "u1_AC-GT-AAA_TT",
+            "u2_AC-GT-AAA_TT",
+            "u3_AC-GT-AAA_GG",
+            "u4_AC-GA-CCCC_TT",
+            "bad",
+        ] {
+            stats.add(name, None);
+        }
+        let report = report(&stats);
+        assert!(report.contains("reads\t5\nparsed\t4\nunparseable\t1\nparse_rate\t0.8000\n"));
+        assert!(report.contains("CBC\t3\t3\nCBC\t4\t1\n"));
+        assert!(report.contains("i5\t2\t1\t0\t0\t3\t0\tNA\n"));
+        assert!(report.contains("ACGTAAA\t3\t2\nACGACCCC\t1\t1\n"));
+        assert!(report.contains("cell_umi_pairs\t3\n"));
+        assert!(report.contains("umis_in_multiple_cells\t0.5000\n"));
+        assert!(!report.contains("bq_coverage"));
+    }
+
+    #[test]
+    fn collision_rate_grows_with_umis_per_cell() {
+        let mut stats = Stats::default();
+        for umi in ["A", "C", "G", "T"] {
+            stats.add(&format!("u_A-C-G_{}", umi), None);
+        }
+        let cells: HashMap<&str, u64> = [("ACG", 4)].into_iter().collect();
+        let rate = stats.expected_collision_rate(&cells).unwrap();
+        assert!((rate - (1.0 - 0.75f64.powi(3))).abs() < 1e-9);
+    }
+}
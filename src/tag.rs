@@ -0,0 +1,475 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use rust_htslib::bam::record::Aux;
+use std::str;
+
+use crate::bq::BqMap;
+use crate::read_structure::{self, InReadBarcode, ReadStarts, ReadStructure, TrimMode};
+
+/// 'I' (Phred Q40, ASCII 73) repeated, sliced to build perfect-quality tags.
+const PERFECT_QUALITY: &str = "IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII";
+
+/// Parsed components from read name: {uuid}_{i7}-{i5}-{CBC}_{UMI}
+///
+/// Fields borrow from the read name, so parsing does not allocate.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ReadName<'a> {
+    pub i7: &'a str,
+    pub i5: &'a str,
+    pub cbc: &'a str,
+    pub umi: &'a str,
+}
+
+/// Parse read name in format: {uuid}_{i7}-{i5}-{CBC}_{UMI}
+///
+/// Example: 2efc6b85-aa0d-4c1d-ab33-bf5f442fe47c_TTGGCTCC-GGTCGGCG-ACTTGA_GAAGCAGT
+/// Returns: ReadName { i7: "TTGGCTCC", i5: "GGTCGGCG", cbc: "ACTTGA", umi: "GAAGCAGT" }
+pub fn parse_read_name(name: &str) -> Result<ReadName<'_>> {
+    let mut parts = name.split('_');
+    // parts[0] = uuid (ignored), parts[1] = i7-i5-CBC, parts[2] = UMI
+    let (Some(_), Some(barcodes), Some(umi), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!(
+            "Expected 3 underscore-separated parts in read name, found {}: '{}'",
+            name.split('_').count(),
+            name
+        );
+    };
+
+    let mut barcode_parts = barcodes.split('-');
+    let (Some(i7), Some(i5), Some(cbc), None) = (
+        barcode_parts.next(),
+        barcode_parts.next(),
+        barcode_parts.next(),
+        barcode_parts.next(),
+    ) else {
+        anyhow::bail!(
+            "Expected 3 hyphen-separated barcode parts, found {}: '{}'",
+            barcodes.split('-').count(),
+            barcodes
+        );
+    };
+
+    Ok(ReadName { i7, i5, cbc, umi })
+}
+
+/// Build a read name `{uuid}_{i7}-{i5}-{CBC}_{UMI}`, the inverse of [`parse_read_name`].
+pub fn format_read_name(uuid: &str, name: &ReadName) -> String {
+    format!("{}_{}-{}-{}_{}", uuid, name.i7, name.i5, name.cbc, name.umi)
+}
+
+/// Append `length` perfect qualities ('I') to `buf`.
+fn push_perfect_quality(buf: &mut String, length: usize) {
+    let mut remaining = length;
+    while remaining > 0 {
+        let n = remaining.min(PERFECT_QUALITY.len());
+        buf.push_str(&PERFECT_QUALITY[..n]);
+        remaining -= n;
+    }
+}
+
+/// Append Phred+33 qualities, which are always ASCII, to `buf`.
+fn push_ascii(buf: &mut String, qual: &[u8]) {
+    buf.extend(qual.iter().map(|&q| char::from(q)));
+}
+
+/// Replace the CBC and UMI portions of the qualities with those sliced from the read itself.
+///
+/// i7/i5 qualities are kept since index reads are never part of read 1.
+pub fn apply_in_read_qualities(
+    in_read: &InReadBarcode,
+    name: &ReadName,
+    cell_barcode_qual: &mut String,
+    umi_qual: &mut String,
+) -> Result<()> {
+    if !in_read.cb_qual.is_empty() {
+        if in_read.cb_qual.len() != name.cbc.len() {
+            anyhow::bail!(
+                "Read structure yields {} cell barcode bases but the read name has a {}-base CBC",
+                in_read.cb_qual.len(),
+                name.cbc.len()
+            );
+        }
+        cell_barcode_qual.truncate(name.i7.len() + name.i5.len());
+        push_ascii(cell_barcode_qual, &in_read.cb_qual);
+    }
+    if !in_read.umi_qual.is_empty() {
+        if in_read.umi_qual.len() != name.umi.len() {
+            anyhow::bail!(
+                "Read structure yields {} UMI bases but the read name has a {}-base UMI",
+                in_read.umi_qual.len(),
+                name.umi.len()
+            );
+        }
+        umi_qual.clear();
+        push_ascii(umi_qual, &in_read.umi_qual);
+    }
+    Ok(())
+}
+
+/// Parse a two-character BAM tag name.
+pub fn parse_tag_name(s: &str) -> Result<[u8; 2], String> {
+    match s.as_bytes() {
+        &[a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphanumeric() => Ok([a, b]),
+        _ => Err(format!("'{}' is not a valid two-character BAM tag", s)),
+    }
+}
+
+/// Write a `Z` tag, replacing any existing value.
+fn set_string_tag(record: &mut bam::Record, tag: &[u8; 2], value: &str) -> Result<()> {
+    if record.aux(tag).is_ok() {
+        record
+            .remove_aux(tag)
+            .with_context(|| format!("Failed to remove {} tag", String::from_utf8_lossy(tag)))?;
+    }
+    record
+        .push_aux(tag, Aux::String(value))
+        .with_context(|| format!("Failed to add {} tag", String::from_utf8_lossy(tag)))
+}
+
+/// How a quality string is summarised before comparing against a minimum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum QualMetric {
+    /// Every base must reach the minimum
+    Min,
+    /// The mean quality must reach the minimum
+    Mean,
+}
+
+/// Handling of reads whose barcode/UMI qualities fall below the minimum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum QualFailAction {
+    /// Write the read without CB/CY/UB/UY tags
+    Untag,
+    /// Tag the read and set the QC-fail flag (0x200)
+    QcFail,
+    /// Tag the read and write it to --rejected instead of the output
+    Reject,
+}
+
+/// Check a Phred+33 quality string against `min`; empty strings always pass.
+pub fn passes_min_qual(qual: &[u8], min: u8, metric: QualMetric) -> bool {
+    if qual.is_empty() {
+        return true;
+    }
+    let phred = qual.iter().map(|&q| q.saturating_sub(33));
+    match metric {
+        QualMetric::Min => phred.min().unwrap_or(0) >= min,
+        QualMetric::Mean => {
+            let sum: u64 = phred.map(u64::from).sum();
+            sum >= u64::from(min) * qual.len() as u64
+        }
+    }
+}
+
+/// What happened to a record's tags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagStatus {
+    /// CB/CY/UB/UY were added
+    Tagged,
+    /// Left untouched because it already has tags
+    Skipped,
+    /// Left untouched because its name could not be parsed
+    Unparseable,
+    /// Parsed, but tags withheld by --qual-fail untag
+    Untagged,
+}
+
+/// Result of tagging one record, applied in input order by the caller.
+#[derive(Debug)]
+pub struct Tagged {
+    pub status: TagStatus,
+    /// Whether in-read qualities were used
+    pub in_read: bool,
+    pub qual_failed: bool,
+    /// Route the record to --rejected
+    pub reject: bool,
+    /// Whether the read name was found in the BQ map, if one was searched
+    pub bq_found: Option<bool>,
+    pub warning: Option<String>,
+}
+
+/// Scratch space reused across records so tagging does not allocate.
+#[derive(Debug, Default)]
+pub struct TagBuffers {
+    cell_barcode: String,
+    cell_barcode_qual: String,
+    umi: String,
+    umi_qual: String,
+    in_read: InReadBarcode,
+}
+
+/// Per-record tagging that only reads shared state, so it can run on worker threads.
+#[derive(Debug, Clone, Copy)]
+pub struct Tagger<'a> {
+    pub bq_map: Option<&'a BqMap>,
+    pub read_structure: Option<&'a ReadStructure>,
+    /// Read 1 qualities and trim shifts for the other records of paired templates
+    pub read_starts: Option<&'a ReadStarts>,
+    pub trim: Option<(TrimMode, usize)>,
+    pub min_cb_qual: Option<u8>,
+    pub min_umi_qual: Option<u8>,
+    pub qual_metric: QualMetric,
+    pub qual_fail: QualFailAction,
+    pub skip_unparseable: bool,
+}
+
+impl Tagger<'_> {
+    /// Parse the read name of `record` and add CB/CY/UB/UY.
+    pub fn tag(&self, record: &mut bam::Record, buffers: &mut TagBuffers) -> Result<Tagged> {
+        let mut tagged = Tagged {
+            status: TagStatus::Skipped,
+            in_read: false,
+            qual_failed: false,
+            reject: false,
+            bq_found: None,
+            warning: None,
+        };
+
+        let qname = str::from_utf8(record.qname()).context("Read name is not valid UTF-8")?;
+        let name = match parse_read_name(qname) {
+            Ok(name) => name,
+            Err(e) if self.skip_unparseable => {
+                tagged.status = TagStatus::Unparseable;
+                tagged.warning = Some(format!("Skipping unparseable read name '{}': {}", qname, e));
+                return Ok(tagged);
+            }
+            Err(e) => return Err(e).context(format!("Failed to parse read name '{}'", qname)),
+        };
+
+        // Check if any of our tags already exist
+        let has_existing_tags = record.aux(b"CB").is_ok()
+            || record.aux(b"CY").is_ok()
+            || record.aux(b"UB").is_ok()
+            || record.aux(b"UY").is_ok();
+
+        if has_existing_tags {
+            tagged.warning = Some(format!(
+                "Read '{}' already has CB/CY/UB/UY tags, skipping",
+                qname
+            ));
+            return Ok(tagged);
+        }
+
+        let TagBuffers {
+            cell_barcode,
+            cell_barcode_qual,
+            umi,
+            umi_qual,
+            in_read,
+        } = buffers;
+
+        // Concatenate cell barcode: i7 + i5 + CBC. Copies end the borrow of the
+        // read name so the record can be modified below.
+        cell_barcode.clear();
+        cell_barcode.push_str(name.i7);
+        cell_barcode.push_str(name.i5);
+        cell_barcode.push_str(name.cbc);
+        umi.clear();
+        umi.push_str(name.umi);
+
+        cell_barcode_qual.clear();
+        umi_qual.clear();
+        let bq_quals = self.bq_map.and_then(|bq_map| bq_map.get(qname));
+        if self.bq_map.is_some() {
+            tagged.bq_found = Some(bq_quals.is_some());
+        }
+        match bq_quals {
+            Some(quals) => {
+                cell_barcode_qual.push_str(&quals.cb);
+                match quals.umi.as_deref() {
+                    Some(umi) => umi_qual.push_str(umi),
+                    None => push_perfect_quality(umi_qual, name.umi.len()),
+                }
+            }
+            None => {
+                push_perfect_quality(cell_barcode_qual, cell_barcode.len());
+                push_perfect_quality(umi_qual, name.umi.len());
+            }
+        }
+
+        // In-read barcode/UMI bases take precedence over FASTQ or perfect qualities;
+        // records without the start of read 1 take those of their template's read 1
+        let read_start = self
+            .read_starts
+            .and_then(|starts| starts.get(record.qname()));
+        if let Some(structure) = self.read_structure {
+            let found = structure.extract_from_record(record, in_read)
+                || read_start.is_some_and(|start| {
+                    in_read.clone_from(&start.barcode);
+                    true
+                });
+            if found {
+                apply_in_read_qualities(in_read, &name, cell_barcode_qual, umi_qual)
+                    .with_context(|| format!("Read '{}'", qname))?;
+                tagged.in_read = true;
+            }
+        }
+
+        let qual_ok = self
+            .min_cb_qual
+            .is_none_or(|min| passes_min_qual(cell_barcode_qual.as_bytes(), min, self.qual_metric))
+            && self
+                .min_umi_qual
+                .is_none_or(|min| passes_min_qual(umi_qual.as_bytes(), min, self.qual_metric));
+        let mut untag = false;
+        if !qual_ok {
+            tagged.qual_failed = true;
+            match self.qual_fail {
+                QualFailAction::Untag => untag = true,
+                QualFailAction::QcFail => record.set_quality_check_failed(),
+                QualFailAction::Reject => tagged.reject = true,
+            }
+        }
+
+        if untag {
+            tagged.status = TagStatus::Untagged;
+            return Ok(tagged);
+        }
+
+        // Add tags to BAM record
+        record.push_aux(b"CB", Aux::String(cell_barcode))?;
+        record.push_aux(b"CY", Aux::String(cell_barcode_qual))?;
+        record.push_aux(b"UB", Aux::String(umi))?;
+        record.push_aux(b"UY", Aux::String(umi_qual))?;
+        if let (Some(bq_map), Some(quals)) = (self.bq_map, bq_quals) {
+            for (tag, qual) in bq_map.extra_tags().zip(quals.extra.iter()) {
+                if let Some(qual) = qual {
+                    set_string_tag(record, &tag, qual)?;
+                }
+            }
+        }
+
+        if let Some((mode, n)) = self.trim {
+            read_structure::trim_read_start(record, n, mode)?;
+            if let Some(start) = read_start {
+                read_structure::fix_mate_fields(record, start)?;
+            }
+        }
+
+        tagged.status = TagStatus::Tagged;
+        Ok(tagged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_read_name() {
+        let name = "2efc6b85-aa0d-4c1d-ab33-bf5f442fe47c_TTGGCTCC-GGTCGGCG-ACTTGA_GAAGCAGT";
+        let result = parse_read_name(name).unwrap();
+
+        assert_eq!(
+            result,
+            ReadName {
+                i7: "TTGGCTCC",
+                i5: "GGTCGGCG",
+                cbc: "ACTTGA",
+                umi: "GAAGCAGT",
+            }
+        );
+    }
+
+    #[test]
+    fn parse_different_lengths() {
+        let name = "uuid_AAA-BB-CCCCCC_UUUU";
+        let result = parse_read_name(name).unwrap();
+
+        assert_eq!(result.i7, "AAA");
+        assert_eq!(result.i5, "BB");
+        assert_eq!(result.cbc, "CCCCCC");
+        assert_eq!(result.umi, "UUUU");
+    }
+
+    #[test]
+    fn format_inverts_parse() {
+        let name = "2efc6b85-aa0d-4c1d-ab33-bf5f442fe47c_TTGGCTCC-GGTCGGCG-ACTTGA_GAAGCAGT";
+        let parsed = parse_read_name(name).unwrap();
+        assert_eq!(
+            format_read_name("2efc6b85-aa0d-4c1d-ab33-bf5f442fe47c", &parsed),
+            name
+        );
+    }
+
+    #[test]
+    fn parse_missing_underscore() {
+        let name = "uuid_TTGGCTCC-GGTCGGCG-ACTTGAGAAGCAGT"; // Missing underscore before UMI
+        assert!(parse_read_name(name).is_err());
+    }
+
+    #[test]
+    fn parse_missing_hyphen() {
+        let name = "uuid_TTGGCTCC-GGTCGGCGACTTGA_GAAGCAGT"; // Missing hyphen in barcodes
+        assert!(parse_read_name(name).is_err());
+    }
+
+    #[test]
+    fn parse_extra_parts() {
+        assert!(parse_read_name("uuid_AA-CC-GG_UUU_extra").is_err());
+        assert!(parse_read_name("uuid_AA-CC-GG-TT_UUU").is_err());
+    }
+
+    #[test]
+    fn perfect_quality_length() {
+        let mut qual = String::new();
+        push_perfect_quality(&mut qual, 8);
+        assert_eq!(qual.len(), 8);
+        assert!(qual.bytes().all(|b| b == b'I'));
+
+        // Longer than the precomputed string
+        qual.clear();
+        push_perfect_quality(&mut qual, PERFECT_QUALITY.len() * 2 + 3);
+        assert_eq!(qual.len(), PERFECT_QUALITY.len() * 2 + 3);
+    }
+
+    #[test]
+    fn perfect_quality_ascii() {
+        let mut qual = String::new();
+        push_perfect_quality(&mut qual, 5);
+        assert_eq!(qual, "IIIII");
+    }
+
+    #[test]
+    fn min_qual_metrics() {
+        // '5' = Q20, 'I' = Q40
+        assert!(passes_min_qual(b"III", 30, QualMetric::Min));
+        assert!(!passes_min_qual(b"I5I", 30, QualMetric::Min));
+        assert!(passes_min_qual(b"I5I", 30, QualMetric::Mean));
+        assert!(!passes_min_qual(b"55I", 30, QualMetric::Mean));
+        assert!(passes_min_qual(b"", 30, QualMetric::Min));
+    }
+
+    #[test]
+    fn tagging_reuses_buffers_across_records() {
+        let tagger = Tagger {
+            bq_map: None,
+            read_structure: None,
+            read_starts: None,
+            trim: None,
+            min_cb_qual: None,
+            min_umi_qual: None,
+            qual_metric: QualMetric::Min,
+            qual_fail: QualFailAction::Untag,
+            skip_unparseable: false,
+        };
+        let mut buffers = TagBuffers::default();
+        for (name, cb, umi) in [
+            ("r1_AAAA-CC-GGGG_TTTTTT", "AAAACCGGGG", "TTTTTT"),
+            ("r2_A-C-G_T", "ACG", "T"),
+        ] {
+            let mut record = bam::Record::new();
+            record.set(name.as_bytes(), None, b"ACGT", &[30; 4]);
+            let tagged = tagger.tag(&mut record, &mut buffers).unwrap();
+            assert_eq!(tagged.status, TagStatus::Tagged);
+            assert_eq!(record.aux(b"CB").unwrap(), Aux::String(cb));
+            assert_eq!(record.aux(b"UB").unwrap(), Aux::String(umi));
+            assert_eq!(
+                record.aux(b"CY").unwrap(),
+                Aux::String(&PERFECT_QUALITY[..cb.len()])
+            );
+        }
+    }
+}
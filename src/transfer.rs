@@ -0,0 +1,575 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::Read;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read as IoRead, Write as IoWrite};
+use std::path::Path;
+use std::str;
+
+use crate::cache::{read_bytes, read_string, read_u64, read_u8, write_bytes, write_u64, write_u8};
+
+pub(crate) const TAG_CACHE_MAGIC: &[u8; 8] = b"TBQTAG01";
+
+/// An owned aux field value, so tags can outlive the record they came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagValue {
+    Char(u8),
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    Float(f32),
+    Double(f64),
+    String(String),
+    HexByteArray(String),
+    ArrayI8(Vec<i8>),
+    ArrayU8(Vec<u8>),
+    ArrayI16(Vec<i16>),
+    ArrayU16(Vec<u16>),
+    ArrayI32(Vec<i32>),
+    ArrayU32(Vec<u32>),
+    ArrayFloat(Vec<f32>),
+}
+
+impl TagValue {
+    fn from_aux(aux: &Aux) -> Self {
+        match aux {
+            Aux::Char(v) => Self::Char(*v),
+            Aux::I8(v) => Self::I8(*v),
+            Aux::U8(v) => Self::U8(*v),
+            Aux::I16(v) => Self::I16(*v),
+            Aux::U16(v) => Self::U16(*v),
+            Aux::I32(v) => Self::I32(*v),
+            Aux::U32(v) => Self::U32(*v),
+            Aux::Float(v) => Self::Float(*v),
+            Aux::Double(v) => Self::Double(*v),
+            Aux::String(v) => Self::String(v.to_string()),
+            Aux::HexByteArray(v) => Self::HexByteArray(v.to_string()),
+            Aux::ArrayI8(a) => Self::ArrayI8(a.iter().collect()),
+            Aux::ArrayU8(a) => Self::ArrayU8(a.iter().collect()),
+            Aux::ArrayI16(a) => Self::ArrayI16(a.iter().collect()),
+            Aux::ArrayU16(a) => Self::ArrayU16(a.iter().collect()),
+            Aux::ArrayI32(a) => Self::ArrayI32(a.iter().collect()),
+            Aux::ArrayU32(a) => Self::ArrayU32(a.iter().collect()),
+            Aux::ArrayFloat(a) => Self::ArrayFloat(a.iter().collect()),
+        }
+    }
+
+    fn as_aux(&self) -> Aux<'_> {
+        match self {
+            Self::Char(v) => Aux::Char(*v),
+            Self::I8(v) => Aux::I8(*v),
+            Self::U8(v) => Aux::U8(*v),
+            Self::I16(v) => Aux::I16(*v),
+            Self::U16(v) => Aux::U16(*v),
+            Self::I32(v) => Aux::I32(*v),
+            Self::U32(v) => Aux::U32(*v),
+            Self::Float(v) => Aux::Float(*v),
+            Self::Double(v) => Aux::Double(*v),
+            Self::String(v) => Aux::String(v),
+            Self::HexByteArray(v) => Aux::HexByteArray(v),
+            Self::ArrayI8(v) => Aux::ArrayI8(v.into()),
+            Self::ArrayU8(v) => Aux::ArrayU8(v.into()),
+            Self::ArrayI16(v) => Aux::ArrayI16(v.into()),
+            Self::ArrayU16(v) => Aux::ArrayU16(v.into()),
+            Self::ArrayI32(v) => Aux::ArrayI32(v.into()),
+            Self::ArrayU32(v) => Aux::ArrayU32(v.into()),
+            Self::ArrayFloat(v) => Aux::ArrayFloat(v.into()),
+        }
+    }
+
+    /// Write a SAM type code followed by the value; integers are widened to
+    /// 64 bits and arrays are prefixed with their length.
+    fn write<W: IoWrite>(&self, writer: &mut W) -> Result<()> {
+        let ints = |writer: &mut W, code: &[u8], values: &mut dyn Iterator<Item = i64>| {
+            writer.write_all(code)?;
+            let values: Vec<i64> = values.collect();
+            write_u64(writer, values.len() as u64)?;
+            values
+                .into_iter()
+                .try_for_each(|v| write_u64(writer, v as u64))
+        };
+        match self {
+            Self::Char(v) => ints(writer, b"A", &mut std::iter::once(i64::from(*v))),
+            Self::I8(v) => ints(writer, b"c", &mut std::iter::once(i64::from(*v))),
+            Self::U8(v) => ints(writer, b"C", &mut std::iter::once(i64::from(*v))),
+            Self::I16(v) => ints(writer, b"s", &mut std::iter::once(i64::from(*v))),
+            Self::U16(v) => ints(writer, b"S", &mut std::iter::once(i64::from(*v))),
+            Self::I32(v) => ints(writer, b"i", &mut std::iter::once(i64::from(*v))),
+            Self::U32(v) => ints(writer, b"I", &mut std::iter::once(i64::from(*v))),
+            Self::Float(v) => ints(writer, b"f", &mut std::iter::once(i64::from(v.to_bits()))),
+            Self::Double(v) => ints(writer, b"d", &mut std::iter::once(v.to_bits() as i64)),
+            Self::String(v) => {
+                writer.write_all(b"Z")?;
+                write_bytes(writer, v.as_bytes())
+            }
+            Self::HexByteArray(v) => {
+                writer.write_all(b"H")?;
+                write_bytes(writer, v.as_bytes())
+            }
+            Self::ArrayI8(v) => ints(writer, b"Bc", &mut v.iter().map(|&x| i64::from(x))),
+            Self::ArrayU8(v) => ints(writer, b"BC", &mut v.iter().map(|&x| i64::from(x))),
+            Self::ArrayI16(v) => ints(writer, b"Bs", &mut v.iter().map(|&x| i64::from(x))),
+            Self::ArrayU16(v) => ints(writer, b"BS", &mut v.iter().map(|&x| i64::from(x))),
+            Self::ArrayI32(v) => ints(writer, b"Bi", &mut v.iter().map(|&x| i64::from(x))),
+            Self::ArrayU32(v) => ints(writer, b"BI", &mut v.iter().map(|&x| i64::from(x))),
+            Self::ArrayFloat(v) => ints(
+                writer,
+                b"Bf",
+                &mut v.iter().map(|&x| i64::from(x.to_bits())),
+            ),
+        }
+    }
+
+    fn read<R: IoRead>(reader: &mut R) -> Result<Self> {
+        fn ints<R: IoRead, T: TryFrom<i64>>(reader: &mut R) -> Result<Vec<T>> {
+            let len = read_u64(reader)?;
+            (0..len)
+                .map(|_| {
+                    T::try_from(read_u64(reader)? as i64)
+                        .ok()
+                        .context("Cache value out of range")
+                })
+                .collect()
+        }
+        fn one<R: IoRead, T: TryFrom<i64>>(reader: &mut R) -> Result<T> {
+            ints(reader)?
+                .pop()
+                .context("Cache holds an empty scalar value")
+        }
+        let float = |bits: u32| f32::from_bits(bits);
+        Ok(match read_u8(reader)? {
+            b'A' => Self::Char(one(reader)?),
+            b'c' => Self::I8(one(reader)?),
+            b'C' => Self::U8(one(reader)?),
+            b's' => Self::I16(one(reader)?),
+            b'S' => Self::U16(one(reader)?),
+            b'i' => Self::I32(one(reader)?),
+            b'I' => Self::U32(one(reader)?),
+            b'f' => Self::Float(float(one(reader)?)),
+            b'd' => Self::Double(f64::from_bits(one::<_, i64>(reader)? as u64)),
+            b'Z' => Self::String(read_string(reader)?),
+            b'H' => Self::HexByteArray(read_string(reader)?),
+            b'B' => match read_u8(reader)? {
+                b'c' => Self::ArrayI8(ints(reader)?),
+                b'C' => Self::ArrayU8(ints(reader)?),
+                b's' => Self::ArrayI16(ints(reader)?),
+                b'S' => Self::ArrayU16(ints(reader)?),
+                b'i' => Self::ArrayI32(ints(reader)?),
+                b'I' => Self::ArrayU32(ints(reader)?),
+                b'f' => Self::ArrayFloat(ints::<_, u32>(reader)?.into_iter().map(float).collect()),
+                code => anyhow::bail!("Unknown array type '{}' in cache", char::from(code)),
+            },
+            code => anyhow::bail!("Unknown tag type '{}' in cache", char::from(code)),
+        })
+    }
+}
+
+/// Copied tags of one record.
+type Tags = Box<[([u8; 2], TagValue)]>;
+
+/// The `wanted` tags present on `record`.
+fn collect_tags(record: &bam::Record, wanted: &[[u8; 2]]) -> Tags {
+    wanted
+        .iter()
+        .filter_map(|tag| {
+            let aux = record.aux(tag).ok()?;
+            Some((*tag, TagValue::from_aux(&aux)))
+        })
+        .collect()
+}
+
+/// Which mate of a template a record is: 0 for read 1 or unpaired, 1 for read 2.
+fn mate_index(record: &bam::Record) -> usize {
+    usize::from(record.is_paired() && record.is_last_in_template())
+}
+
+/// Tags of both mates of a template.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct TemplateTags([Option<Tags>; 2]);
+
+impl TemplateTags {
+    fn add(&mut self, record: &bam::Record, wanted: &[[u8; 2]]) {
+        let slot = &mut self.0[mate_index(record)];
+        if slot.is_none() {
+            *slot = Some(collect_tags(record, wanted));
+        }
+    }
+
+    /// Tags of the record's own mate, or of the other mate if only that was seen.
+    fn for_record(&self, record: &bam::Record) -> Option<&Tags> {
+        let mate = mate_index(record);
+        self.0[mate].as_ref().or(self.0[1 - mate].as_ref())
+    }
+}
+
+/// Primary records only; secondary and supplementary copies carry no extra tags.
+fn is_source_record(record: &bam::Record) -> bool {
+    !record.is_secondary() && !record.is_supplementary()
+}
+
+/// Write `tags` to `record`, replacing existing values.
+fn apply_tags(record: &mut bam::Record, tags: &Tags) -> Result<()> {
+    for (tag, value) in tags.iter() {
+        let name = String::from_utf8_lossy(tag);
+        if record.aux(tag).is_ok() {
+            record
+                .remove_aux(tag)
+                .with_context(|| format!("Failed to remove {} tag", name))?;
+        }
+        record
+            .push_aux(tag, value.as_aux())
+            .with_context(|| format!("Failed to add {} tag", name))?;
+    }
+    Ok(())
+}
+
+/// Tags of every template in an unaligned BAM, keyed by read name.
+#[derive(Debug)]
+pub struct TagMap {
+    wanted: Vec<[u8; 2]>,
+    templates: HashMap<String, TemplateTags>,
+}
+
+impl TagMap {
+    /// Read the `wanted` tags of every primary record in `path`.
+    pub fn load(path: &Path, wanted: &[[u8; 2]], threads: usize) -> Result<Self> {
+        let mut reader = open_source(path, threads)?;
+        let mut templates: HashMap<String, TemplateTags> = HashMap::new();
+        let mut record = bam::Record::new();
+        while let Some(result) = reader.read(&mut record) {
+            result.with_context(|| format!("Failed to read --tags-from BAM: {:?}", path))?;
+            if !is_source_record(&record) {
+                continue;
+            }
+            let name = str::from_utf8(record.qname())
+                .context("--tags-from read name is not valid UTF-8")?;
+            templates
+                .entry(name.to_string())
+                .or_default()
+                .add(&record, wanted);
+        }
+        Ok(Self {
+            wanted: wanted.to_vec(),
+            templates,
+        })
+    }
+
+    /// Load from `cache_path` if it exists, is current and holds the same
+    /// tags; otherwise read `path` and (re)write the cache.
+    pub fn load_with_cache(
+        path: &Path,
+        cache_path: Option<&Path>,
+        wanted: &[[u8; 2]],
+        threads: usize,
+    ) -> Result<Self> {
+        if let Some(cache_path) = cache_path {
+            if cache_path.exists() {
+                let cached = Self::read_cache(cache_path, wanted)
+                    .with_context(|| format!("Failed to read tag cache: {:?}", cache_path))?;
+                match cached {
+                    Some(map) => return Ok(map),
+                    None => eprintln!(
+                        "Warning: Tag cache {:?} is from another tagbam version or holds different tags; rebuilding it",
+                        cache_path
+                    ),
+                }
+            }
+        }
+
+        let map = Self::load(path, wanted, threads)?;
+        if let Some(cache_path) = cache_path {
+            map.write_cache(cache_path)
+                .with_context(|| format!("Failed to write tag cache: {:?}", cache_path))?;
+        }
+        Ok(map)
+    }
+
+    pub fn len(&self) -> usize {
+        self.templates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    fn read_cache(cache_path: &Path, wanted: &[[u8; 2]]) -> Result<Option<Self>> {
+        let file = File::open(cache_path)
+            .with_context(|| format!("Failed to open tag cache: {:?}", cache_path))?;
+        let mut reader = BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        reader
+            .read_exact(&mut magic)
+            .context("Failed to read tag cache header")?;
+        if !magic.starts_with(b"TBQTAG") {
+            anyhow::bail!("Tag cache has invalid header");
+        }
+        if &magic != TAG_CACHE_MAGIC {
+            return Ok(None);
+        }
+        if read_bytes(&mut reader)? != wanted.concat() {
+            return Ok(None);
+        }
+
+        let count = read_u64(&mut reader).context("Failed to read tag cache entry count")?;
+        let mut templates = HashMap::new();
+        for _ in 0..count {
+            let name = read_string(&mut reader).context("Failed to read tag cache name")?;
+            let mut template = TemplateTags::default();
+            for slot in &mut template.0 {
+                if read_u8(&mut reader)? == 1 {
+                    let n_tags = read_u64(&mut reader)?;
+                    let tags = (0..n_tags)
+                        .map(|_| {
+                            let mut tag = [0u8; 2];
+                            reader.read_exact(&mut tag)?;
+                            Ok((tag, TagValue::read(&mut reader)?))
+                        })
+                        .collect::<Result<_>>()
+                        .context("Failed to read tag cache values")?;
+                    *slot = Some(tags);
+                }
+            }
+            templates.insert(name, template);
+        }
+        Ok(Some(Self {
+            wanted: wanted.to_vec(),
+            templates,
+        }))
+    }
+
+    /// Write the map to `cache_path`, replacing any existing file.
+    pub fn write_cache(&self, cache_path: &Path) -> Result<()> {
+        let file = File::create(cache_path)
+            .with_context(|| format!("Failed to create tag cache: {:?}", cache_path))?;
+        let mut writer = BufWriter::new(file);
+
+        writer
+            .write_all(TAG_CACHE_MAGIC)
+            .context("Failed to write tag cache header")?;
+        write_bytes(&mut writer, &self.wanted.concat())?;
+        write_u64(&mut writer, self.templates.len() as u64)?;
+        for (name, template) in &self.templates {
+            write_bytes(&mut writer, name.as_bytes())?;
+            for slot in &template.0 {
+                match slot {
+                    Some(tags) => {
+                        write_u8(&mut writer, 1)?;
+                        write_u64(&mut writer, tags.len() as u64)?;
+                        for (tag, value) in tags.iter() {
+                            writer.write_all(tag)?;
+                            value.write(&mut writer)?;
+                        }
+                    }
+                    None => write_u8(&mut writer, 0)?,
+                }
+            }
+        }
+        writer.flush().context("Failed to flush tag cache")?;
+        Ok(())
+    }
+}
+
+/// Reads an unaligned BAM alongside the input, one template at a time, so no
+/// map is built. Both BAMs must list templates in the same order.
+pub struct TagStream {
+    reader: bam::Reader,
+    wanted: Vec<[u8; 2]>,
+    name: Vec<u8>,
+    template: TemplateTags,
+    /// First record of the next template, read ahead
+    pending: Option<bam::Record>,
+    eof: bool,
+}
+
+impl TagStream {
+    pub fn new(path: &Path, wanted: &[[u8; 2]], threads: usize) -> Result<Self> {
+        Ok(Self {
+            reader: open_source(path, threads)?,
+            wanted: wanted.to_vec(),
+            name: Vec::new(),
+            template: TemplateTags::default(),
+            pending: None,
+            eof: false,
+        })
+    }
+
+    /// Tags of the template named `qname`, reading ahead past templates absent
+    /// from the input.
+    fn template(&mut self, qname: &[u8]) -> Result<&TemplateTags> {
+        while self.name != qname {
+            if !self.advance()? {
+                anyhow::bail!(
+                    "Read '{}' not found in --tags-from; --tags-stream requires both BAMs to list reads in the same order",
+                    String::from_utf8_lossy(qname)
+                );
+            }
+        }
+        Ok(&self.template)
+    }
+
+    /// Read the next template; false at the end of the file.
+    fn advance(&mut self) -> Result<bool> {
+        let first = match self.pending.take() {
+            Some(record) => record,
+            None => match self.next_record()? {
+                Some(record) => record,
+                None => return Ok(false),
+            },
+        };
+        self.name.clear();
+        self.name.extend_from_slice(first.qname());
+        self.template = TemplateTags::default();
+        self.template.add(&first, &self.wanted);
+        while let Some(record) = self.next_record()? {
+            if record.qname() != self.name.as_slice() {
+                self.pending = Some(record);
+                break;
+            }
+            self.template.add(&record, &self.wanted);
+        }
+        Ok(true)
+    }
+
+    fn next_record(&mut self) -> Result<Option<bam::Record>> {
+        while !self.eof {
+            let mut record = bam::Record::new();
+            match self.reader.read(&mut record) {
+                Some(result) => {
+                    result.context("Failed to read --tags-from BAM")?;
+                    if is_source_record(&record) {
+                        return Ok(Some(record));
+                    }
+                }
+                None => self.eof = true,
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn open_source(path: &Path, threads: usize) -> Result<bam::Reader> {
+    let mut reader = bam::Reader::from_path(path)
+        .with_context(|| format!("Failed to open --tags-from BAM: {:?}", path))?;
+    reader.set_threads(threads.max(1))?;
+    Ok(reader)
+}
+
+/// Copies tags from an unaligned BAM onto records with the same read name.
+pub enum TagTransfer {
+    Memory(TagMap),
+    Stream(TagStream),
+}
+
+impl TagTransfer {
+    /// Copy the tags for `record`'s template and mate; returns whether the
+    /// template was found.
+    pub fn apply(&mut self, record: &mut bam::Record) -> Result<bool> {
+        let template = match self {
+            TagTransfer::Memory(map) => {
+                let name =
+                    str::from_utf8(record.qname()).context("Read name is not valid UTF-8")?;
+                match map.templates.get(name) {
+                    Some(template) => template,
+                    None => return Ok(false),
+                }
+            }
+            TagTransfer::Stream(stream) => stream.template(record.qname())?,
+        };
+        if let Some(tags) = template.for_record(record).cloned() {
+            apply_tags(record, &tags)?;
+        }
+        Ok(true)
+    }
+}
+
+/// Add `@RG` lines of `source` whose IDs `header` lacks, so copied RG tags
+/// refer to declared read groups.
+pub fn add_missing_read_groups(header: &mut bam::Header, source: &bam::HeaderView) {
+    let existing: HashSet<String> = header
+        .to_hashmap()
+        .get("RG")
+        .into_iter()
+        .flatten()
+        .filter_map(|fields| fields.get("ID").cloned())
+        .collect();
+    let source = bam::Header::from_template(source).to_hashmap();
+    for fields in source.get("RG").into_iter().flatten() {
+        let Some(id) = fields.get("ID") else {
+            continue;
+        };
+        if existing.contains(id) {
+            continue;
+        }
+        let mut rg = bam::header::HeaderRecord::new(b"RG");
+        rg.push_tag(b"ID", id);
+        for (key, value) in fields.iter().filter(|(key, _)| key.as_str() != "ID") {
+            rg.push_tag(key.as_bytes(), value);
+        }
+        header.push_record(&rg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_values_roundtrip_through_cache_encoding() {
+        let values = [
+            TagValue::Char(b'x'),
+            TagValue::I8(-3),
+            TagValue::U32(u32::MAX),
+            TagValue::Float(1.5),
+            TagValue::Double(-2.25),
+            TagValue::String("ACGT".to_string()),
+            TagValue::HexByteArray("1AE3".to_string()),
+            TagValue::ArrayI16(vec![-1, 2, 300]),
+            TagValue::ArrayFloat(vec![0.5, -0.25]),
+        ];
+        let mut buf = Vec::new();
+        for value in &values {
+            value.write(&mut buf).unwrap();
+        }
+        let mut reader = buf.as_slice();
+        for value in &values {
+            assert_eq!(&TagValue::read(&mut reader).unwrap(), value);
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn copies_tags_of_the_matching_mate() {
+        let mut r1 = bam::Record::new();
+        r1.set(b"t1", None, b"A", b"I");
+        r1.set_paired();
+        r1.set_first_in_template();
+        r1.push_aux(b"CB", Aux::String("AAA")).unwrap();
+        r1.push_aux(b"XN", Aux::I32(1)).unwrap();
+        let mut r2 = r1.clone();
+        r2.unset_first_in_template();
+        r2.set_last_in_template();
+        r2.remove_aux(b"XN").unwrap();
+        r2.push_aux(b"XN", Aux::I32(2)).unwrap();
+
+        let wanted = [*b"CB", *b"XN"];
+        let mut template = TemplateTags::default();
+        template.add(&r1, &wanted);
+        template.add(&r2, &wanted);
+
+        let mut aligned = bam::Record::new();
+        aligned.set(b"t1", None, b"A", b"I");
+        aligned.set_paired();
+        aligned.set_last_in_template();
+        aligned.push_aux(b"CB", Aux::String("old")).unwrap();
+        let tags = template.for_record(&aligned).unwrap();
+        apply_tags(&mut aligned, tags).unwrap();
+        assert_eq!(aligned.aux(b"CB").unwrap(), Aux::String("AAA"));
+        assert_eq!(aligned.aux(b"XN").unwrap(), Aux::I32(2));
+    }
+}
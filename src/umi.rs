@@ -0,0 +1,379 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+use crate::position::{mate_unclipped_five_prime, unclipped_five_prime, CoordinateOrder, Position};
+
+/// Reads whose 5' positions are within this many bases may still join a group
+/// after it was first seen (covers leading soft clips of forward-strand reads).
+const GROUP_WINDOW: i64 = 1000;
+
+/// Templates sharing a cell barcode and the strand and unclipped 5' position
+/// of their first-seen mate.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct GroupKey {
+    tid: i32,
+    pos: i64,
+    reverse: bool,
+    cb: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Group {
+    counts: HashMap<Vec<u8>, u64>,
+    pending: usize,
+    /// Mates of counted templates that have not been seen yet
+    awaiting: usize,
+    corrections: Option<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+/// Buffers coordinate-sorted records until their position group is complete,
+/// then rewrites `UB` with the directional-adjacency representative and keeps
+/// the raw UMI in `UR`.
+///
+/// Each template counts once, in the group of whichever mate is seen first;
+/// the other mate joins that group, so both mates get the same `UB`.
+/// Records are released in input order.
+pub struct UmiCorrector {
+    pending: VecDeque<(bam::Record, Option<GroupKey>)>,
+    groups: HashMap<GroupKey, Group>,
+    /// Group chosen by the first-seen mate, by read name
+    mates: HashMap<Vec<u8>, GroupKey>,
+    /// Read names in `mates` by expected mate position, so mates that never
+    /// arrive (filtered, or outside a region) are forgotten
+    mate_expiry: BinaryHeap<Reverse<(Position, Vec<u8>)>>,
+    order: CoordinateOrder,
+    n_corrected: u64,
+}
+
+impl Default for UmiCorrector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UmiCorrector {
+    pub fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+            groups: HashMap::new(),
+            mates: HashMap::new(),
+            mate_expiry: BinaryHeap::new(),
+            order: CoordinateOrder::default(),
+            n_corrected: 0,
+        }
+    }
+
+    /// Number of records whose `UB` was changed.
+    pub fn n_corrected(&self) -> u64 {
+        self.n_corrected
+    }
+
+    /// Add a record, emitting every buffered record whose group is now complete.
+    pub fn push<F>(&mut self, record: bam::Record, emit: &mut F) -> Result<()>
+    where
+        F: FnMut(bam::Record) -> Result<()>,
+    {
+        self.order.observe(&record, "--correct-umis")?;
+        self.expire_mates();
+
+        let key = self.join_group(&record);
+        self.pending.push_back((record, key));
+
+        self.release(emit, false)
+    }
+
+    /// Add `record` to its template's group, counting its UMI if it is the
+    /// first-seen mate.
+    fn join_group(&mut self, record: &bam::Record) -> Option<GroupKey> {
+        if record.is_secondary() || record.is_supplementary() {
+            return None;
+        }
+        if let Some(key) = self.mates.remove(record.qname()) {
+            let group = self.groups.get_mut(&key).expect("group exists");
+            group.awaiting -= 1;
+            group.pending += 1;
+            return Some(key);
+        }
+
+        let key = group_key(record)?;
+        let group = self.groups.entry(key.clone()).or_default();
+        let umi = aux_string(record, b"UB").unwrap_or_default().to_vec();
+        *group.counts.entry(umi).or_insert(0) += 1;
+        group.pending += 1;
+        if record.is_paired() && record.mtid() >= 0 {
+            group.awaiting += 1;
+            let qname = record.qname().to_vec();
+            self.mate_expiry
+                .push(Reverse(((record.mtid(), record.mpos()), qname.clone())));
+            self.mates.insert(qname, key.clone());
+        }
+        Some(key)
+    }
+
+    /// Stop waiting for mates the sort position has moved well past.
+    fn expire_mates(&mut self) {
+        let Some(last) = self.order.last() else {
+            return;
+        };
+        while let Some(Reverse(((tid, pos), _))) = self.mate_expiry.peek() {
+            if last <= (*tid, pos + GROUP_WINDOW) {
+                break;
+            }
+            let Reverse((_, qname)) = self.mate_expiry.pop().expect("peeked");
+            if let Some(key) = self.mates.remove(&qname) {
+                let group = self.groups.get_mut(&key).expect("group exists");
+                group.awaiting -= 1;
+                if group.pending == 0 && group.awaiting == 0 {
+                    self.groups.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Emit every remaining record.
+    pub fn finish<F>(&mut self, emit: &mut F) -> Result<()>
+    where
+        F: FnMut(bam::Record) -> Result<()>,
+    {
+        self.release(emit, true)
+    }
+
+    fn release<F>(&mut self, emit: &mut F, all: bool) -> Result<()>
+    where
+        F: FnMut(bam::Record) -> Result<()>,
+    {
+        while let Some((_, key)) = self.pending.front() {
+            if let Some(key) = key {
+                let complete = all
+                    || match self.order.last() {
+                        Some((tid, pos)) => tid != key.tid || pos > key.pos + GROUP_WINDOW,
+                        None => true,
+                    };
+                if !complete {
+                    break;
+                }
+            }
+
+            let (mut record, key) = self.pending.pop_front().expect("front exists");
+            if let Some(key) = key {
+                let group = self.groups.get_mut(&key).expect("group exists");
+                let corrections = group
+                    .corrections
+                    .get_or_insert_with(|| directional_clusters(&group.counts));
+                if correct_record(&mut record, corrections)? {
+                    self.n_corrected += 1;
+                }
+                group.pending -= 1;
+                if group.pending == 0 && group.awaiting == 0 {
+                    self.groups.remove(&key);
+                }
+            }
+            emit(record)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rewrite `UB` from `corrections` and record the raw UMI in `UR`.
+///
+/// Returns whether `UB` changed.
+fn correct_record(
+    record: &mut bam::Record,
+    corrections: &HashMap<Vec<u8>, Vec<u8>>,
+) -> Result<bool> {
+    let Some(raw) = aux_string(record, b"UB").map(|u| u.to_vec()) else {
+        return Ok(false);
+    };
+    if record.aux(b"UR").is_err() {
+        record
+            .push_aux(b"UR", bam::record::Aux::String(str_from(&raw)?))
+            .context("Failed to add UR tag")?;
+    }
+    match corrections.get(&raw) {
+        Some(corrected) if *corrected != raw => {
+            record
+                .remove_aux(b"UB")
+                .context("Failed to remove UB tag")?;
+            record
+                .push_aux(b"UB", bam::record::Aux::String(str_from(corrected)?))
+                .context("Failed to add corrected UB tag")?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Group key for records carrying both `CB` and `UB`, from their own position
+/// or, if unmapped, from their mapped mate's.
+fn group_key(record: &bam::Record) -> Option<GroupKey> {
+    let cb = aux_string(record, b"CB")?.to_vec();
+    aux_string(record, b"UB")?;
+
+    if !record.is_unmapped() {
+        Some(GroupKey {
+            tid: record.tid(),
+            pos: unclipped_five_prime(record),
+            reverse: record.is_reverse(),
+            cb,
+        })
+    } else if record.is_paired() && !record.is_mate_unmapped() && record.mtid() >= 0 {
+        Some(GroupKey {
+            tid: record.mtid(),
+            pos: mate_unclipped_five_prime(record),
+            reverse: record.is_mate_reverse(),
+            cb,
+        })
+    } else {
+        None
+    }
+}
+
+/// String value of an aux tag, if present with type `Z`.
+pub fn aux_string<'a>(record: &'a bam::Record, tag: &[u8]) -> Option<&'a [u8]> {
+    match record.aux(tag) {
+        Ok(bam::record::Aux::String(s)) => Some(s.as_bytes()),
+        _ => None,
+    }
+}
+
+fn str_from(bytes: &[u8]) -> Result<&str> {
+    std::str::from_utf8(bytes).context("UMI is not valid UTF-8")
+}
+
+/// Map every UMI to its directional-adjacency representative (UMI-tools `directional`).
+///
+/// UMI `a` absorbs `b` when they differ by one base and `count(a) >= 2 * count(b) - 1`.
+/// UMIs are visited from most to least abundant, so each cluster is represented by
+/// its most abundant member.
+pub fn directional_clusters(counts: &HashMap<Vec<u8>, u64>) -> HashMap<Vec<u8>, Vec<u8>> {
+    let mut umis: Vec<(&Vec<u8>, u64)> = counts.iter().map(|(u, &c)| (u, c)).collect();
+    umis.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    let mut representative: HashMap<Vec<u8>, Vec<u8>> = HashMap::with_capacity(umis.len());
+    for &(root, _) in &umis {
+        if representative.contains_key(root) {
+            continue;
+        }
+        representative.insert(root.clone(), root.clone());
+
+        let mut queue = VecDeque::from([root]);
+        while let Some(node) = queue.pop_front() {
+            let node_count = counts[node];
+            for &(other, other_count) in &umis {
+                if representative.contains_key(other)
+                    || node_count < 2 * other_count - 1
+                    || !within_one_mismatch(node, other)
+                {
+                    continue;
+                }
+                representative.insert(other.clone(), root.clone());
+                queue.push_back(other);
+            }
+        }
+    }
+
+    representative
+}
+
+fn within_one_mismatch(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).filter(|(x, y)| x != y).count() <= 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(entries: &[(&str, u64)]) -> HashMap<Vec<u8>, u64> {
+        entries
+            .iter()
+            .map(|&(u, c)| (u.as_bytes().to_vec(), c))
+            .collect()
+    }
+
+    #[test]
+    fn directional_merges_low_count_neighbours() {
+        let clusters = directional_clusters(&counts(&[
+            ("AAAA", 10),
+            ("AAAT", 2),
+            ("AATT", 1),
+            ("GGGG", 5),
+        ]));
+        assert_eq!(clusters[b"AAAA".as_slice()], b"AAAA");
+        assert_eq!(clusters[b"AAAT".as_slice()], b"AAAA");
+        // Reached through AAAT (2 >= 2 * 1 - 1)
+        assert_eq!(clusters[b"AATT".as_slice()], b"AAAA");
+        assert_eq!(clusters[b"GGGG".as_slice()], b"GGGG");
+    }
+
+    fn paired_record(qname: &str, first: bool, pos: i64, mpos: i64, umi: &str) -> bam::Record {
+        let cigar = bam::record::CigarString(vec![bam::record::Cigar::Match(4)]);
+        let mut record = bam::Record::new();
+        record.set(qname.as_bytes(), Some(&cigar), b"ACGT", &[30; 4]);
+        record.set_flags(if first {
+            0x1 | 0x20 | 0x40
+        } else {
+            0x1 | 0x10 | 0x80
+        });
+        record.set_tid(0);
+        record.set_pos(pos);
+        record.set_mtid(0);
+        record.set_mpos(mpos);
+        record
+            .push_aux(b"CB", bam::record::Aux::String("AACC"))
+            .unwrap();
+        record
+            .push_aux(b"UB", bam::record::Aux::String(umi))
+            .unwrap();
+        record
+    }
+
+    #[test]
+    fn mates_share_corrected_umi() {
+        // Five AAAA templates and one AAAT template share R1 positions, but
+        // the AAAT template's R2 is alone at its position
+        let mut records: Vec<bam::Record> = (0..5)
+            .map(|i| paired_record(&format!("t{}", i), true, 100, 300, "AAAA"))
+            .collect();
+        records.push(paired_record("odd", true, 100, 2000, "AAAT"));
+        records.extend((0..5).map(|i| paired_record(&format!("t{}", i), false, 300, 100, "AAAA")));
+        records.push(paired_record("odd", false, 2000, 100, "AAAT"));
+        // A mate that never arrives must not keep its group alive
+        records.push(paired_record("lost", true, 5000, 5100, "GGGG"));
+        records.push(paired_record("late", true, 9000, 9000, "CCCC"));
+
+        let mut corrector = UmiCorrector::new();
+        let mut out = Vec::new();
+        let mut emit = |record: bam::Record| {
+            out.push(record);
+            Ok(())
+        };
+        for record in records {
+            corrector.push(record, &mut emit).unwrap();
+        }
+        corrector.finish(&mut emit).unwrap();
+
+        let odd: Vec<(&[u8], &[u8])> = out
+            .iter()
+            .filter(|record| record.qname() == b"odd")
+            .map(|record| {
+                (
+                    aux_string(record, b"UB").unwrap(),
+                    aux_string(record, b"UR").unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(odd, [(&b"AAAA"[..], &b"AAAT"[..]); 2]);
+        assert_eq!(corrector.n_corrected(), 2);
+        assert_eq!(out.len(), 14);
+        assert!(!corrector.mates.contains_key(b"lost".as_slice()));
+    }
+
+    #[test]
+    fn directional_keeps_similar_abundance_separate() {
+        let clusters = directional_clusters(&counts(&[("AAAA", 4), ("AAAT", 3)]));
+        assert_eq!(clusters[b"AAAA".as_slice()], b"AAAA");
+        assert_eq!(clusters[b"AAAT".as_slice()], b"AAAT");
+    }
+}
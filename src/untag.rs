@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use rust_htslib::bam::record::Aux;
+use std::str::{self, FromStr};
+
+use crate::tag::{format_read_name, parse_read_name, ReadName};
+
+/// Lengths of the i7 and i5 indices at the start of `CB`; the rest is the CBC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexLengths {
+    pub i7: usize,
+    pub i5: usize,
+}
+
+impl FromStr for IndexLengths {
+    type Err = String;
+
+    /// Parse `I7,I5`, e.g. `8,8`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("'{}' is not I7,I5 index lengths, e.g. 8,8", s);
+        let (i7, i5) = s.split_once(',').ok_or_else(invalid)?;
+        Ok(Self {
+            i7: i7.trim().parse().map_err(|_| invalid())?,
+            i5: i5.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// What happened to one record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Untagged {
+    /// Whether any listed tag was present and removed
+    pub removed: bool,
+    /// Whether the read name was rewritten, if names are restored
+    pub renamed: Option<bool>,
+}
+
+/// Removes tags and optionally moves `CB`/`UB` back into the read name.
+#[derive(Debug, Clone)]
+pub struct Untagger {
+    pub tags: Vec<[u8; 2]>,
+    pub restore_names: Option<IndexLengths>,
+}
+
+impl Untagger {
+    pub fn untag(&self, record: &mut bam::Record) -> Result<Untagged> {
+        let renamed = match self.restore_names {
+            Some(lengths) => Some(restore_name(record, lengths)?),
+            None => None,
+        };
+
+        let mut removed = false;
+        for tag in &self.tags {
+            if record.aux(tag).is_ok() {
+                record.remove_aux(tag).with_context(|| {
+                    format!("Failed to remove {} tag", String::from_utf8_lossy(tag))
+                })?;
+                removed = true;
+            }
+        }
+        Ok(Untagged { removed, renamed })
+    }
+}
+
+/// Longest read name rust-htslib will set (it asserts on longer ones).
+const MAX_QNAME_LEN: usize = 251;
+
+/// Rewrite the read name as `{uuid}_{i7}-{i5}-{CBC}_{UMI}` from `CB` and `UB`;
+/// false if either tag is missing.
+///
+/// The uuid is the first part of a name that already parses, otherwise the whole name.
+fn restore_name(record: &mut bam::Record, lengths: IndexLengths) -> Result<bool> {
+    let qname = str::from_utf8(record.qname()).context("Read name is not valid UTF-8")?;
+    let (Ok(Aux::String(cb)), Ok(Aux::String(umi))) = (record.aux(b"CB"), record.aux(b"UB")) else {
+        return Ok(false);
+    };
+
+    let indices = lengths.i7 + lengths.i5;
+    if cb.len() < indices || !cb.is_char_boundary(lengths.i7) || !cb.is_char_boundary(indices) {
+        anyhow::bail!(
+            "Read '{}': CB '{}' is shorter than the {}+{} index bases",
+            qname,
+            cb,
+            lengths.i7,
+            lengths.i5
+        );
+    }
+    let name = ReadName {
+        i7: &cb[..lengths.i7],
+        i5: &cb[lengths.i7..indices],
+        cbc: &cb[indices..],
+        umi,
+    };
+    let uuid = match parse_read_name(qname) {
+        Ok(_) => qname.split('_').next().unwrap_or(qname),
+        Err(_) => qname,
+    };
+
+    let new_name = format_read_name(uuid, &name);
+    // '_' or '-' in any part would be split differently when parsed back
+    if parse_read_name(&new_name).ok() != Some(name) {
+        anyhow::bail!(
+            "Read '{}': cannot encode CB '{}' and UB '{}' in the read name",
+            qname,
+            cb,
+            umi
+        );
+    }
+    if new_name.len() > MAX_QNAME_LEN {
+        anyhow::bail!(
+            "Read '{}': restored name is {} characters long, over the BAM limit of {}",
+            qname,
+            new_name.len(),
+            MAX_QNAME_LEN
+        );
+    }
+    record.set_qname(new_name.as_bytes());
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(qname: &str, tags: &[(&[u8; 2], &str)]) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(qname.as_bytes(), None, b"ACGT", b"IIII");
+        for (tag, value) in tags {
+            record.push_aux(*tag, Aux::String(value)).unwrap();
+        }
+        record
+    }
+
+    #[test]
+    fn parses_index_lengths() {
+        assert_eq!(
+            "8,6".parse::<IndexLengths>().unwrap(),
+            IndexLengths { i7: 8, i5: 6 }
+        );
+        assert!("8".parse::<IndexLengths>().is_err());
+        assert!("8,x".parse::<IndexLengths>().is_err());
+    }
+
+    #[test]
+    fn restores_names_from_tags() {
+        let untagger = Untagger {
+            tags: vec![*b"CB", *b"CY", *b"UB", *b"UY"],
+            restore_names: Some(IndexLengths { i7: 3, i5: 2 }),
+        };
+
+        let mut plain = record(
+            "read1",
+            &[(b"CB", "AAACCGGGG"), (b"CY", "IIIIIIIII"), (b"UB", "TTT")],
+        );
+        let untagged = untagger.untag(&mut plain).unwrap();
+        assert_eq!(plain.qname(), b"read1_AAA-CC-GGGG_TTT");
+        assert_eq!(
+            untagged,
+            Untagged {
+                removed: true,
+                renamed: Some(true)
+            }
+        );
+        assert!(plain.aux(b"CB").is_err() && plain.aux(b"CY").is_err());
+
+        // An encoded name keeps its uuid
+        let mut encoded = record("u1_A-B-C_D", &[(b"CB", "AAACCGG"), (b"UB", "TT")]);
+        untagger.untag(&mut encoded).unwrap();
+        assert_eq!(encoded.qname(), b"u1_AAA-CC-GG_TT");
+
+        let mut untagged = record("read2", &[(b"CY", "III")]);
+        assert_eq!(untagger.untag(&mut untagged).unwrap().renamed, Some(false));
+        assert_eq!(untagged.qname(), b"read2");
+
+        assert!(untagger
+            .untag(&mut record("read3", &[(b"CB", "AAAC"), (b"UB", "T")]))
+            .is_err());
+        assert!(untagger
+            .untag(&mut record("read4", &[(b"CB", "AAACC-1"), (b"UB", "T")]))
+            .is_err());
+
+        let long_umi = "T".repeat(250);
+        let err = untagger
+            .untag(&mut record(
+                "read5",
+                &[(b"CB", "AAACCG"), (b"UB", &long_umi)],
+            ))
+            .unwrap_err();
+        assert!(err.to_string().contains("over the BAM limit of 251"));
+    }
+}
@@ -0,0 +1,366 @@
+use rust_htslib::bam;
+use std::collections::HashMap;
+
+use crate::tag::parse_read_name;
+use crate::umi::aux_string;
+
+/// A way in which a record's tags are inconsistent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Some but not all of CB/CY/UB/UY are present
+    MissingTags,
+    /// CB differs from i7+i5+CBC in the read name
+    CbMismatch,
+    /// UR (or UB without UR) differs from the UMI in the read name
+    UmiMismatch,
+    /// CY is not as long as CB
+    CyLength,
+    /// UY is not as long as UB
+    UyLength,
+    /// CY or UY has characters outside Phred+33 ('!' to '~')
+    InvalidQuality,
+    /// Mates have different CB or UB, or only one of them is tagged
+    MateMismatch,
+}
+
+impl Violation {
+    pub const ALL: [Violation; 7] = [
+        Violation::MissingTags,
+        Violation::CbMismatch,
+        Violation::UmiMismatch,
+        Violation::CyLength,
+        Violation::UyLength,
+        Violation::InvalidQuality,
+        Violation::MateMismatch,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Violation::MissingTags => "missing_tags",
+            Violation::CbMismatch => "cb_mismatch",
+            Violation::UmiMismatch => "umi_mismatch",
+            Violation::CyLength => "cy_length",
+            Violation::UyLength => "uy_length",
+            Violation::InvalidQuality => "invalid_quality",
+            Violation::MateMismatch => "mate_mismatch",
+        }
+    }
+}
+
+/// First occurrence of a violation, for the report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Example {
+    pub violation: Violation,
+    pub read: String,
+    pub detail: String,
+}
+
+/// CB and UB of a record, or `None` if it is untagged.
+type MateTags = Option<(Vec<u8>, Vec<u8>)>;
+
+/// Checks tagged records one at a time and tallies violations.
+#[derive(Debug)]
+pub struct Validator {
+    check_names: bool,
+    max_examples: usize,
+    /// Tags of primary paired records whose mate has not been seen
+    mates: HashMap<Vec<u8>, MateTags>,
+    counts: [u64; Violation::ALL.len()],
+    examples: Vec<Example>,
+    pub n_records: u64,
+    pub n_tagged: u64,
+    /// Tagged records whose read name could not be parsed, so CB/UB were not compared
+    pub n_unparseable: u64,
+}
+
+impl Validator {
+    /// With `check_names` false, CB/UB are not compared with read names (e.g. after `--rename`).
+    pub fn new(check_names: bool, max_examples: usize) -> Self {
+        Self {
+            check_names,
+            max_examples,
+            mates: HashMap::new(),
+            counts: [0; Violation::ALL.len()],
+            examples: Vec::new(),
+            n_records: 0,
+            n_tagged: 0,
+            n_unparseable: 0,
+        }
+    }
+
+    /// Number of records with `violation`.
+    pub fn count(&self, violation: Violation) -> u64 {
+        self.counts[violation as usize]
+    }
+
+    /// Total violations; a record counts once for each kind it has.
+    pub fn n_violations(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn examples(&self) -> &[Example] {
+        &self.examples
+    }
+
+    pub fn check(&mut self, record: &bam::Record) {
+        self.n_records += 1;
+        let cb = aux_string(record, b"CB");
+        let cy = aux_string(record, b"CY");
+        let ub = aux_string(record, b"UB");
+        let uy = aux_string(record, b"UY");
+        if cb.is_none() && ub.is_none() && cy.is_none() && uy.is_none() {
+            // Still recorded, so a tagged mate is reported
+            self.check_mate(record, None);
+            return;
+        }
+        self.n_tagged += 1;
+        let read = String::from_utf8_lossy(record.qname()).into_owned();
+
+        if cb.is_none() || cy.is_none() || ub.is_none() || uy.is_none() {
+            let missing: Vec<&str> = [("CB", cb), ("CY", cy), ("UB", ub), ("UY", uy)]
+                .iter()
+                .filter(|(_, value)| value.is_none())
+                .map(|(tag, _)| *tag)
+                .collect();
+            self.report(
+                Violation::MissingTags,
+                &read,
+                format!("missing {}", missing.join(", ")),
+            );
+        }
+
+        if self.check_names {
+            match parse_read_name(&read) {
+                Ok(name) => {
+                    let expected_cb = [name.i7, name.i5, name.cbc].concat();
+                    if let Some(cb) = cb.filter(|cb| *cb != expected_cb.as_bytes()) {
+                        self.report(
+                            Violation::CbMismatch,
+                            &read,
+                            format!(
+                                "CB '{}' but read name gives '{}'",
+                                String::from_utf8_lossy(cb),
+                                expected_cb
+                            ),
+                        );
+                    }
+                    // UMI correction keeps the raw UMI in UR
+                    let raw_umi = aux_string(record, b"UR").or(ub);
+                    if let Some(umi) = raw_umi.filter(|umi| *umi != name.umi.as_bytes()) {
+                        self.report(
+                            Violation::UmiMismatch,
+                            &read,
+                            format!(
+                                "UMI '{}' but read name gives '{}'",
+                                String::from_utf8_lossy(umi),
+                                name.umi
+                            ),
+                        );
+                    }
+                }
+                Err(_) => self.n_unparseable += 1,
+            }
+        }
+
+        for (violation, seq_tag, seq, qual_tag, qual) in [
+            (Violation::CyLength, "CB", cb, "CY", cy),
+            (Violation::UyLength, "UB", ub, "UY", uy),
+        ] {
+            if let (Some(seq), Some(qual)) = (seq, qual) {
+                if seq.len() != qual.len() {
+                    self.report(
+                        violation,
+                        &read,
+                        format!(
+                            "{} has {} bases but {} has {}",
+                            seq_tag,
+                            seq.len(),
+                            qual_tag,
+                            qual.len()
+                        ),
+                    );
+                }
+            }
+        }
+        for (tag, qual) in [("CY", cy), ("UY", uy)] {
+            if let Some(&bad) = qual.and_then(|q| q.iter().find(|q| !(33..=126).contains(*q))) {
+                self.report(
+                    Violation::InvalidQuality,
+                    &read,
+                    format!("{} contains byte {} outside Phred+33", tag, bad),
+                );
+            }
+        }
+
+        let tags = (
+            cb.unwrap_or_default().to_vec(),
+            ub.unwrap_or_default().to_vec(),
+        );
+        self.check_mate(record, Some(tags));
+    }
+
+    /// Compare the CB/UB of a primary paired record with its mate's, once both
+    /// have been seen.
+    fn check_mate(&mut self, record: &bam::Record, tags: MateTags) {
+        if !record.is_paired() || record.is_secondary() || record.is_supplementary() {
+            return;
+        }
+        let Some(mate) = self.mates.remove(record.qname()) else {
+            self.mates.insert(record.qname().to_vec(), tags);
+            return;
+        };
+        if mate == tags {
+            return;
+        }
+        let describe = |tags: &MateTags| match tags {
+            Some((cb, ub)) => format!(
+                "CB/UB {}/{}",
+                String::from_utf8_lossy(cb),
+                String::from_utf8_lossy(ub)
+            ),
+            None => "no tags".to_string(),
+        };
+        let read = String::from_utf8_lossy(record.qname()).into_owned();
+        let detail = format!("{} but mate has {}", describe(&tags), describe(&mate));
+        self.report(Violation::MateMismatch, &read, detail);
+    }
+
+    fn report(&mut self, violation: Violation, read: &str, detail: String) {
+        self.counts[violation as usize] += 1;
+        if self.examples.len() < self.max_examples {
+            self.examples.push(Example {
+                violation,
+                read: read.to_string(),
+                detail,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+
+    fn record(qname: &str, flags: u16, tags: &[(&[u8; 2], &str)]) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(qname.as_bytes(), None, b"ACGT", b"IIII");
+        record.set_flags(flags);
+        for (tag, value) in tags {
+            record.push_aux(*tag, Aux::String(value)).unwrap();
+        }
+        record
+    }
+
+    const GOOD: [(&[u8; 2], &str); 4] = [
+        (b"CB", "AACCGG"),
+        (b"CY", "IIIIII"),
+        (b"UB", "TTT"),
+        (b"UY", "III"),
+    ];
+
+    #[test]
+    fn accepts_consistent_records() {
+        let mut validator = Validator::new(true, 10);
+        for record in [
+            record("u1_AA-CC-GG_TTT", 0x1 | 0x40, &GOOD),
+            record("u1_AA-CC-GG_TTT", 0x1 | 0x80, &GOOD),
+            record("untagged", 0, &[]),
+        ] {
+            validator.check(&record);
+        }
+        assert_eq!(validator.n_violations(), 0);
+        assert_eq!((validator.n_records, validator.n_tagged), (3, 2));
+    }
+
+    #[test]
+    fn reports_each_kind_of_violation() {
+        let mut validator = Validator::new(true, 1);
+        let records = [
+            record("u1_AA-CC-GA_TTT", 0, &GOOD),
+            record(
+                "u2_AA-CC-GG_TTT",
+                0,
+                &[(b"CB", "AACCGG"), (b"CY", "IIIII\u{1f}"), (b"UB", "TTT")],
+            ),
+            record(
+                "u3_AA-CC-GG_TTT",
+                0,
+                &[
+                    (b"CB", "AACCGG"),
+                    (b"CY", "III"),
+                    (b"UB", "TTT"),
+                    (b"UY", "II"),
+                ],
+            ),
+            record("u4_AA-CC-GG_TTT", 0x1 | 0x40, &GOOD),
+            record(
+                "u4_AA-CC-GG_TTT",
+                0x1 | 0x80,
+                &[
+                    (b"CB", "AACCGG"),
+                    (b"CY", "IIIIII"),
+                    (b"UB", "TTA"),
+                    (b"UY", "III"),
+                ],
+            ),
+        ];
+        for record in &records {
+            validator.check(record);
+        }
+        let counts: Vec<u64> = Violation::ALL.iter().map(|&v| validator.count(v)).collect();
+        assert_eq!(counts, [1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(
+            validator.examples(),
+            [Example {
+                violation: Violation::CbMismatch,
+                read: "u1_AA-CC-GA_TTT".to_string(),
+                detail: "CB 'AACCGG' but read name gives 'AACCGA'".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_pairs_with_one_untagged_mate() {
+        let mut validator = Validator::new(true, 10);
+        for record in [
+            record("u1_AA-CC-GG_TTT", 0x1 | 0x40, &GOOD),
+            record("u2_AA-CC-GG_TTT", 0x1 | 0x40, &[]),
+            record("u1_AA-CC-GG_TTT", 0x1 | 0x80, &[]),
+            record("u2_AA-CC-GG_TTT", 0x1 | 0x80, &GOOD),
+            record("u3_AA-CC-GG_TTT", 0x1 | 0x40, &[]),
+            record("u3_AA-CC-GG_TTT", 0x1 | 0x80, &[]),
+        ] {
+            validator.check(&record);
+        }
+        assert_eq!(validator.count(Violation::MateMismatch), 2);
+        assert_eq!(validator.n_violations(), 2);
+        assert_eq!(
+            validator.examples()[0],
+            Example {
+                violation: Violation::MateMismatch,
+                read: "u1_AA-CC-GG_TTT".to_string(),
+                detail: "no tags but mate has CB/UB AACCGG/TTT".to_string(),
+            }
+        );
+        assert_eq!(
+            validator.examples()[1].detail,
+            "CB/UB AACCGG/TTT but mate has no tags"
+        );
+    }
+
+    #[test]
+    fn compares_raw_umi_and_skips_names_on_request() {
+        let mut corrected = record("u1_AA-CC-GG_TTA", 0, &GOOD);
+        corrected.push_aux(b"UR", Aux::String("TTA")).unwrap();
+        let mut validator = Validator::new(true, 0);
+        validator.check(&corrected);
+        assert_eq!(validator.n_violations(), 0);
+
+        let mut validator = Validator::new(false, 0);
+        validator.check(&record("renamed", 0, &GOOD));
+        validator.check(&record("u1_GG-CC-AA_AAA", 0, &GOOD));
+        assert_eq!(validator.n_violations(), 0);
+        assert_eq!(validator.n_unparseable, 0);
+    }
+}
@@ -1,4 +1,5 @@
 use assert_cmd::Command;
+use predicates::prelude::PredicateBooleanExt;
 use rust_htslib::bam;
 use rust_htslib::bam::Read;
 use std::fs::File;
@@ -41,6 +42,45 @@ fn create_test_bam(path: &Path, read_names: &[&str]) -> Result<(), Box<dyn std::
     Ok(())
 }
 
+/// Helper to create a BAM file from fully specified records
+fn create_bam_with_records(
+    path: &Path,
+    sort_order: &str,
+    records: &[bam::Record],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut header = bam::Header::new();
+    let mut header_rec = bam::header::HeaderRecord::new(b"HD");
+    header_rec.push_tag(b"VN", "1.6");
+    header_rec.push_tag(b"SO", sort_order);
+    header.push_record(&header_rec);
+
+    let mut ref_rec = bam::header::HeaderRecord::new(b"SQ");
+    ref_rec.push_tag(b"SN", "chr1");
+    ref_rec.push_tag(b"LN", "1000");
+    header.push_record(&ref_rec);
+
+    let mut writer = bam::Writer::from_path(path, &header, bam::Format::Bam)?;
+    for record in records {
+        writer.write(record)?;
+    }
+
+    Ok(())
+}
+
+/// Helper to build a mapped 4-base record on chr1
+fn mapped_record(read_name: &str, pos: i64, reverse: bool) -> bam::Record {
+    let cigar = bam::record::CigarString(vec![bam::record::Cigar::Match(4)]);
+    let mut record = bam::Record::new();
+    record.set(read_name.as_bytes(), Some(&cigar), b"ACGT", &[30; 4]);
+    record.unset_unmapped();
+    record.set_tid(0);
+    record.set_pos(pos);
+    if reverse {
+        record.set_reverse();
+    }
+    record
+}
+
 /// Helper to read BAM tags from a record
 fn get_tag_string(record: &bam::Record, tag: &[u8; 2]) -> Option<String> {
     match record.aux(tag) {
@@ -249,117 +289,2131 @@ fn fastq_bq_overrides_barcode_qualities() {
 }
 
 #[test]
-fn skip_reads_with_existing_tags() {
+fn cache_build_and_inspect_subcommands() {
     let td = TempDir::new().unwrap();
     let input_bam = td.path().join("input.bam");
     let output_bam = td.path().join("output.bam");
+    let tsv_path = td.path().join("quals.tsv");
+    let cache_path = td.path().join("quals.cache");
 
-    // Create a BAM with one read
-    let read_name = "uuid_AAA-BBB-CCC_UUU";
+    let read_name = "uuid1_AAA-BBB-CCC_UUU";
+    create_test_bam(&input_bam, &[read_name]).unwrap();
+    std::fs::write(&tsv_path, format!("{}\t123456789\tXYZ\n", read_name)).unwrap();
 
-    // Create header
-    let mut header = bam::Header::new();
-    let mut header_rec = bam::header::HeaderRecord::new(b"HD");
-    header_rec.push_tag(b"VN", "1.6");
-    header_rec.push_tag(b"SO", "unsorted");
-    header.push_record(&header_rec);
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "cache",
+        "build",
+        "--bq-source",
+        tsv_path.to_str().unwrap(),
+        "--output",
+        cache_path.to_str().unwrap(),
+    ]);
+    cmd.assert().success().stderr(predicates::str::contains(
+        "Cached qualities of 1 reads from TSV",
+    ));
 
-    let mut ref_rec = bam::header::HeaderRecord::new(b"SQ");
-    ref_rec.push_tag(b"SN", "chr1");
-    ref_rec.push_tag(b"LN", "1000");
-    header.push_record(&ref_rec);
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["cache", "inspect", cache_path.to_str().unwrap()]);
+    cmd.assert()
+        .success()
+        .stdout(predicates::str::contains("BQ cache"))
+        .stdout(predicates::str::contains("format=Tsv"))
+        .stdout(predicates::str::contains("reads\t1"));
 
-    let mut writer = bam::Writer::from_path(&input_bam, &header, bam::Format::Bam).unwrap();
+    // The source is not read again once the cache exists
+    std::fs::remove_file(&tsv_path).unwrap();
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "tag",
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--bq-source",
+        tsv_path.to_str().unwrap(),
+        "--bq-format",
+        "tsv",
+        "--bq-cache",
+        cache_path.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let record = reader.records().next().unwrap().unwrap();
+    assert_eq!(get_tag_string(&record, b"UY"), Some("XYZ".to_string()));
 
-    // Create record with existing CB tag
-    let mut record = bam::Record::new();
-    record.set(read_name.as_bytes(), None, b"ACGT", b"IIII");
-    record.set_pos(0);
-    record.set_tid(0);
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["cache", "inspect", input_bam.to_str().unwrap()]);
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("is not a tagbam cache"));
+}
 
-    // Add an existing CB tag
-    record
-        .push_aux(b"CB", bam::record::Aux::String("EXISTING"))
-        .unwrap();
+#[test]
+fn fastq_bq_rejects_truncated_fastq() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let fastq_path = td.path().join("reads.fastq");
 
-    writer.write(&record).unwrap();
-    drop(writer);
+    create_test_bam(&input_bam, &["uuid1_AAA-BBB-CCC_UUU"]).unwrap();
+    std::fs::write(
+        &fastq_path,
+        "@uuid1_AAA-BBB-CCC_UUU |BQ:i7:123;i5:456;CBC:789\nAAAA\n+\nIIII\n@uuid2\nAAAA\n",
+    )
+    .unwrap();
 
-    // Run tagbam
     let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
     cmd.args([
         "--input",
         input_bam.to_str().unwrap(),
         "--output",
         output_bam.to_str().unwrap(),
+        "--fastq-bq",
+        fastq_path.to_str().unwrap(),
     ]);
-    cmd.assert().success();
+    cmd.assert().failure().stderr(predicates::str::contains(
+        "FASTQ is truncated: the record at line 5 is incomplete",
+    ));
+}
 
-    // Read output BAM and verify the existing tag was NOT changed
+#[test]
+fn fastq_bq_skips_malformed_records() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let fastq_path = td.path().join("reads.fastq");
+
+    let names = ["uuid1_AAA-BBB-CCC_UUU", "uuid2_AAA-BBB-CCC_UUU"];
+    create_test_bam(&input_bam, &names).unwrap();
+    std::fs::write(
+        &fastq_path,
+        format!(
+            "@{} |BQ:i7:123;i5:456;CBC:789\nAAAA\n+\nIIIII\n\
+             @{} |BQ:i7:123;i5:456;CBC:789\nAAAA\n+\nIIII\n",
+            names[0], names[1]
+        ),
+    )
+    .unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--fastq-bq",
+        fastq_path.to_str().unwrap(),
+    ]);
+    cmd.assert().success().stderr(predicates::str::contains(
+        "Skipped 1 malformed FASTQ records (first at line 1: quality length 5 differs from sequence length 4)",
+    ));
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let cy: Vec<_> = reader
+        .records()
+        .map(|r| get_tag_string(&r.unwrap(), b"CY").unwrap())
+        .collect();
+    assert_eq!(cy, ["IIIIIIIII", "123456789"]);
+}
+
+#[test]
+fn fastq_bq_matches_mate_suffixed_names_and_reports_hits() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let fastq_path = td.path().join("reads.fastq");
+
+    let names = ["uuid1_AAA-BBB-CCC_UUU", "uuid2_AAA-BBB-CCC_UUU"];
+    create_test_bam(&input_bam, &names).unwrap();
+    // R1 and R2 concatenated, with mate suffixes and Casava comments
+    std::fs::write(
+        &fastq_path,
+        format!(
+            "@{0}/1 1:N:0:ACGT|BQ:i7:111;i5:111;CBC:111\nAAAA\n+\nIIII\n\
+             @{0}/2 2:N:0:ACGT|BQ:i7:222;i5:222;CBC:222\nAAAA\n+\nIIII\n",
+            names[0]
+        ),
+    )
+    .unwrap();
+
+    let run = |policy: &str| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+        cmd.args([
+            "--input",
+            input_bam.to_str().unwrap(),
+            "--output",
+            output_bam.to_str().unwrap(),
+            "--fastq-bq",
+            fastq_path.to_str().unwrap(),
+            "--bq-duplicates",
+            policy,
+        ]);
+        cmd.assert()
+    };
+
+    run("first")
+        .success()
+        .stderr(predicates::str::contains(
+            "1 FASTQ records repeated an earlier read name",
+        ))
+        .stderr(predicates::str::contains(
+            "1 of 2 reads found qualities in the BQ source",
+        ));
     let mut reader = bam::Reader::from_path(&output_bam).unwrap();
     let record = reader.records().next().unwrap().unwrap();
+    assert_eq!(
+        get_tag_string(&record, b"CY"),
+        Some("111111111".to_string())
+    );
 
-    // CB tag should still be "EXISTING", not the parsed value
+    run("error").failure().stderr(predicates::str::contains(
+        "Read name 'uuid1_AAA-BBB-CCC_UUU' occurs more than once in the BQ FASTQ (lines 1 and 5)",
+    ));
+}
+
+#[test]
+fn fastq_bq_schema_selects_labels_and_extra_tags() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let fastq_path = td.path().join("reads.fastq");
+    let cache_path = td.path().join("reads.bq.cache");
+
+    let names = ["uuid1_AAA-BBB-CCC_UUU", "uuid2_AAA-BBB-CCC_UUU"];
+    create_test_bam(&input_bam, &names).unwrap();
+    std::fs::write(
+        &fastq_path,
+        format!(
+            "@{} |BQ:S1:111;CBC:789;R2BC:XYZ;UMI:UUU;TSO:+++\nAAAA\n+\nIIII\n\
+             @{} |BQ:CBC:456;R2BC:ABC\nAAAA\n+\nIIII\n",
+            names[0], names[1]
+        ),
+    )
+    .unwrap();
+
+    let run = |extra: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+        cmd.args([
+            "--input",
+            input_bam.to_str().unwrap(),
+            "--output",
+            output_bam.to_str().unwrap(),
+            "--fastq-bq",
+            fastq_path.to_str().unwrap(),
+            "--fastq-bq-cache",
+            cache_path.to_str().unwrap(),
+            "--bq-cy-labels",
+            "CBC,S1",
+            "--bq-umi-label",
+            "R2BC",
+            "--bq-tag",
+            "TSO:QT",
+        ]);
+        cmd.args(extra);
+        cmd.assert().success()
+    };
+    let tags = |tag: &[u8; 2]| -> Vec<Option<String>> {
+        let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+        reader
+            .records()
+            .map(|r| get_tag_string(&r.unwrap(), tag))
+            .collect()
+    };
+
+    run(&[]);
     assert_eq!(
-        get_tag_string(&record, b"CB"),
-        Some("EXISTING".to_string()),
-        "Existing CB tag should be preserved"
+        tags(b"CY"),
+        [Some("789111".to_string()), Some("IIIIIIIII".to_string())]
+    );
+    assert_eq!(
+        tags(b"UY"),
+        [Some("XYZ".to_string()), Some("III".to_string())]
     );
+    assert_eq!(tags(b"QT"), [Some("+++".to_string()), None]);
 
-    // No other tags should have been added either
-    assert_eq!(get_tag_string(&record, b"UB"), None);
+    // A different schema must not reuse the cache built above
+    run(&["--bq-partial"]).stderr(predicates::str::contains("rebuilding it"));
+    assert_eq!(
+        tags(b"CY"),
+        [Some("789111".to_string()), Some("456".to_string())]
+    );
+    assert_eq!(
+        tags(b"UY"),
+        [Some("XYZ".to_string()), Some("ABC".to_string())]
+    );
 }
 
 #[test]
-fn in_place_mode() {
+fn bq_source_reads_tsv_and_bam() {
     let td = TempDir::new().unwrap();
-    let bam_file = td.path().join("test.bam");
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let tsv_path = td.path().join("quals.tsv");
+    let ubam_path = td.path().join("unaligned.bam");
 
-    // Create test BAM
-    let read_name = "uuid_AAA-BBB-CCC_UUU";
-    create_test_bam(&bam_file, &[read_name]).unwrap();
+    let names = ["uuid1_AAA-BBB-CCC_UUU", "uuid2_AAA-BBB-CCC_UUU"];
+    create_test_bam(&input_bam, &names).unwrap();
+    std::fs::write(
+        &tsv_path,
+        format!(
+            "read_name\tcb_qual\tumi_qual\n{}\t123456789\tXYZ\n",
+            names[0]
+        ),
+    )
+    .unwrap();
 
-    // Get original modification time to verify file was replaced
-    let original_metadata = std::fs::metadata(&bam_file).unwrap();
+    // One record with CY/UY, the other with its UMI qualities only in BZ
+    let mut records = Vec::new();
+    for (name, tags) in [
+        (names[0], [(b"CY", "987654321"), (b"UY", "ZYX")]),
+        (names[1], [(b"CY", "AAAAAAAAA"), (b"BZ", "BBB")]),
+    ] {
+        let mut record = bam::Record::new();
+        record.set(name.as_bytes(), None, b"ACGT", b"IIII");
+        for (tag, value) in tags {
+            record
+                .push_aux(tag, bam::record::Aux::String(value))
+                .unwrap();
+        }
+        records.push(record);
+    }
+    create_bam_with_records(&ubam_path, "unsorted", &records).unwrap();
 
-    // Run tagbam with --in-place
+    let run = |source: &Path| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+        cmd.args([
+            "--input",
+            input_bam.to_str().unwrap(),
+            "--output",
+            output_bam.to_str().unwrap(),
+            "--bq-source",
+            source.to_str().unwrap(),
+        ]);
+        cmd.assert().success();
+        let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+        reader
+            .records()
+            .map(|r| {
+                let r = r.unwrap();
+                (
+                    get_tag_string(&r, b"CY").unwrap(),
+                    get_tag_string(&r, b"UY").unwrap(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        run(&tsv_path),
+        [
+            ("123456789".to_string(), "XYZ".to_string()),
+            ("IIIIIIIII".to_string(), "III".to_string()),
+        ]
+    );
+    assert_eq!(
+        run(&ubam_path),
+        [
+            ("987654321".to_string(), "ZYX".to_string()),
+            ("AAAAAAAAA".to_string(), "BBB".to_string()),
+        ]
+    );
+
+    // Labels only exist in FASTQ tokens
     let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
-    cmd.args(["--input", bam_file.to_str().unwrap(), "--in-place"]);
-    cmd.assert().success();
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--bq-source",
+        tsv_path.to_str().unwrap(),
+        "--bq-tag",
+        "R2BC:QX",
+    ]);
+    cmd.assert().failure().stderr(predicates::str::contains(
+        "only apply to FASTQ BQ sources, not TSV",
+    ));
+}
 
-    // Read the modified BAM (same file)
-    let mut reader = bam::Reader::from_path(&bam_file).unwrap();
-    let record = reader.records().next().unwrap().unwrap();
+#[test]
+fn tags_from_copies_tags_by_read_name() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let ubam_path = td.path().join("unaligned.bam");
+    let cache_path = td.path().join("tags.cache");
 
-    // Verify tags were added
+    // Unaligned BAM: a read pair with per-mate UR and a single read with an integer tag
+    let mut header = bam::Header::new();
+    let mut rg = bam::header::HeaderRecord::new(b"RG");
+    rg.push_tag(b"ID", "lib1").push_tag(b"SM", "sample1");
+    header.push_record(&rg);
+    let mut writer = bam::Writer::from_path(&ubam_path, &header, bam::Format::Bam).unwrap();
+    for (name, mate, ur) in [
+        ("r1", Some(true), "AAAA"),
+        ("r1", Some(false), "CCCC"),
+        ("r2", None, "GGGG"),
+    ] {
+        let mut record = bam::Record::new();
+        record.set(name.as_bytes(), None, b"ACGT", b"IIII");
+        record.set_unmapped();
+        if let Some(first) = mate {
+            record.set_paired();
+            if first {
+                record.set_first_in_template();
+            } else {
+                record.set_last_in_template();
+            }
+        }
+        for (tag, value) in [(b"CB", "CELL"), (b"UR", ur), (b"RG", "lib1")] {
+            record
+                .push_aux(tag, bam::record::Aux::String(value))
+                .unwrap();
+        }
+        record.push_aux(b"XI", bam::record::Aux::I32(7)).unwrap();
+        writer.write(&record).unwrap();
+    }
+    drop(writer);
+
+    let aligned = |name: &str, mate: Option<bool>| {
+        let mut record = mapped_record(name, 100, false);
+        if let Some(first) = mate {
+            record.set_paired();
+            if first {
+                record.set_first_in_template();
+            } else {
+                record.set_last_in_template();
+            }
+        }
+        record
+            .push_aux(b"CB", bam::record::Aux::String("stale"))
+            .unwrap();
+        record
+    };
+    create_bam_with_records(
+        &input_bam,
+        "unsorted",
+        &[
+            aligned("r1", Some(false)),
+            aligned("r1", Some(true)),
+            aligned("r2", None),
+            aligned("r3", None),
+        ],
+    )
+    .unwrap();
+
+    let run = |extra: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+        cmd.args([
+            "--input",
+            input_bam.to_str().unwrap(),
+            "--output",
+            output_bam.to_str().unwrap(),
+            "--tags-from",
+            ubam_path.to_str().unwrap(),
+            "--copy-tags",
+            "CB,UR,RG,XI",
+        ])
+        .args(extra);
+        cmd.assert()
+    };
+    let check = || {
+        let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+        let header = bam::Header::from_template(reader.header()).to_hashmap();
+        assert_eq!(header["RG"][0]["ID"], "lib1");
+        assert_eq!(header["RG"][0]["SM"], "sample1");
+        let records: Vec<bam::Record> = reader.records().map(|r| r.unwrap()).collect();
+        let tags = |r: &bam::Record| (get_tag_string(r, b"CB"), get_tag_string(r, b"UR"));
+        assert_eq!(
+            tags(&records[0]),
+            (Some("CELL".into()), Some("CCCC".into()))
+        );
+        assert_eq!(
+            tags(&records[1]),
+            (Some("CELL".into()), Some("AAAA".into()))
+        );
+        assert_eq!(
+            tags(&records[2]),
+            (Some("CELL".into()), Some("GGGG".into()))
+        );
+        assert_eq!(records[2].aux(b"XI").unwrap(), bam::record::Aux::I32(7));
+        assert_eq!(get_tag_string(&records[2], b"RG").as_deref(), Some("lib1"));
+        // Unmatched reads keep their own tags and are not parsed for barcodes
+        assert_eq!(tags(&records[3]), (Some("stale".into()), None));
+    };
+
+    run(&[])
+        .success()
+        .stderr(predicates::str::contains("3 received tags from"));
+    check();
+
+    run(&["--tags-cache", cache_path.to_str().unwrap()]).success();
+    assert!(cache_path.exists());
+    std::fs::remove_file(&output_bam).unwrap();
+    run(&["--tags-cache", cache_path.to_str().unwrap()])
+        .success()
+        .stderr(predicates::str::contains("1 had no match"));
+    check();
+
+    // A cache written by another version is rebuilt, not rejected
+    let mut cache = std::fs::read(&cache_path).unwrap();
+    cache[..8].copy_from_slice(b"TBQTAG00");
+    std::fs::write(&cache_path, cache).unwrap();
+    std::fs::remove_file(&output_bam).unwrap();
+    run(&["--tags-cache", cache_path.to_str().unwrap()])
+        .success()
+        .stderr(predicates::str::contains("is from another tagbam version"));
+    check();
+    assert_eq!(&std::fs::read(&cache_path).unwrap()[..8], b"TBQTAG01");
+
+    // Streaming needs every input read in --tags-from, in the same order
+    run(&["--tags-stream"])
+        .failure()
+        .stderr(predicates::str::contains(
+            "Read 'r3' not found in --tags-from",
+        ));
+    create_bam_with_records(
+        &input_bam,
+        "unsorted",
+        &[
+            aligned("r1", Some(false)),
+            aligned("r1", Some(true)),
+            aligned("r2", None),
+        ],
+    )
+    .unwrap();
+    run(&["--tags-stream"]).success();
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let urs: Vec<Option<String>> = reader
+        .records()
+        .map(|r| get_tag_string(&r.unwrap(), b"UR"))
+        .collect();
     assert_eq!(
-        get_tag_string(&record, b"CB"),
-        Some("AAABBBCCC".to_string())
+        urs,
+        [
+            Some("CCCC".into()),
+            Some("AAAA".into()),
+            Some("GGGG".into())
+        ]
     );
-    assert_eq!(get_tag_string(&record, b"UB"), Some("UUU".to_string()));
+}
 
-    // Verify the file was actually modified (metadata changed)
-    let new_metadata = std::fs::metadata(&bam_file).unwrap();
-    // On some systems modification time might be the same if the operation is very fast,
-    // but the file size should be different due to added tags
-    assert!(
-        new_metadata.len() >= original_metadata.len(),
-        "File should have been modified in-place"
+#[test]
+fn untag_restores_read_names_that_tag_parses_back() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let untagged_bam = td.path().join("untagged.bam");
+    let retagged_bam = td.path().join("retagged.bam");
+
+    // Tags as left by an aligner run with --tags-from, under plain read names
+    let mut records = Vec::new();
+    for (name, cb, umi) in [
+        ("2efc6b85-aa0d", "TTGGCTCCGGTCGGCGACTTGA", "GAAGCAGT"),
+        ("3bd1c0a7-11e2", "AAAAAAAACCCCCCCCGGGGGG", "TTTTTTTT"),
+    ] {
+        let mut record = bam::Record::new();
+        record.set(name.as_bytes(), None, b"ACGT", b"IIII");
+        for (tag, value) in [(b"CB", cb), (b"CY", "I"), (b"UB", umi), (b"XX", "keep")] {
+            record
+                .push_aux(tag, bam::record::Aux::String(value))
+                .unwrap();
+        }
+        records.push(record);
+    }
+    create_bam_with_records(&input_bam, "unsorted", &records).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "untag",
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        untagged_bam.to_str().unwrap(),
+        "--restore-names",
+        "8,8",
+    ]);
+    cmd.assert()
+        .success()
+        .stderr(predicates::str::contains("2 read names restored"));
+
+    let mut reader = bam::Reader::from_path(&untagged_bam).unwrap();
+    let untagged: Vec<bam::Record> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(
+        untagged[0].qname(),
+        b"2efc6b85-aa0d_TTGGCTCC-GGTCGGCG-ACTTGA_GAAGCAGT"
     );
+    for record in &untagged {
+        for tag in [b"CB", b"CY", b"UB", b"UY"] {
+            assert!(record.aux(tag).is_err());
+        }
+        assert_eq!(get_tag_string(record, b"XX").as_deref(), Some("keep"));
+    }
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        untagged_bam.to_str().unwrap(),
+        "--output",
+        retagged_bam.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+    let mut reader = bam::Reader::from_path(&retagged_bam).unwrap();
+    for (retagged, original) in reader.records().zip(&records) {
+        let retagged = retagged.unwrap();
+        for tag in [b"CB", b"UB"] {
+            assert_eq!(
+                get_tag_string(&retagged, tag),
+                get_tag_string(original, tag)
+            );
+        }
+    }
 }
 
 #[test]
-fn requires_output_or_in_place() {
+fn stats_reports_barcodes_without_writing_a_bam() {
     let td = TempDir::new().unwrap();
     let input_bam = td.path().join("input.bam");
+    let tsv_path = td.path().join("quals.tsv");
+    let report_path = td.path().join("stats.tsv");
 
-    // Create test BAM
-    create_test_bam(&input_bam, &["uuid_AAA-BBB-CCC_UUU"]).unwrap();
+    create_test_bam(
+        &input_bam,
+        &[
+            "uuid1_AA-CC-GGG_TTT",
+            "uuid2_AA-CC-GGG_TTA",
+            "uuid3_AA-CC-GGG_TTT",
+            "not_a_barcoded_name_x",
+        ],
+    )
+    .unwrap();
+    std::fs::write(&tsv_path, "uuid1_AA-CC-GGG_TTT\t+++++++\t555\n").unwrap();
 
-    // Run tagbam without --output or --in-place
     let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
-    cmd.args(["--input", input_bam.to_str().unwrap()]);
-    cmd.assert()
-        .failure()
-        .stderr(predicates::str::contains("Either --output or --in-place"));
+    cmd.args([
+        "stats",
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        report_path.to_str().unwrap(),
+        "--bq-source",
+        tsv_path.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert!(report.contains("reads\t4\nparsed\t3\nunparseable\t1\nparse_rate\t0.7500\n"));
+    assert!(report.contains("bq_coverage\t0.3333\n"));
+    assert!(report.contains("UMI\t3\t3\n"));
+    // Qualities come from the one read in the BQ source ('+' = Q10, '5' = Q20)
+    assert!(report.contains("i7\t1\t3\t0\t0\t0\t0\t10.00\n"));
+    assert!(report.contains("UMI\t3\t1\t0\t0\t2\t0\t20.00\n"));
+    assert!(report.contains("AACCGGG\t3\t2\n"));
+    assert!(report.contains("cell_umi_pairs\t2\n"));
+}
+
+#[test]
+fn validate_reports_inconsistent_tags() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let tagged_bam = td.path().join("tagged.bam");
+    let corrupt_bam = td.path().join("corrupt.bam");
+
+    create_test_bam(
+        &input_bam,
+        &["uuid1_AAA-CCC-GGG_TTT", "uuid2_AAA-CCC-GGG_TTA"],
+    )
+    .unwrap();
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        tagged_bam.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["validate", "--input", tagged_bam.to_str().unwrap()]);
+    cmd.assert()
+        .success()
+        .stdout(predicates::str::contains("tagged\t2"))
+        .stdout(predicates::str::contains("cb_mismatch\t0"));
+
+    // A later step swapped the cell barcode of the second read
+    let mut reader = bam::Reader::from_path(&tagged_bam).unwrap();
+    let mut records: Vec<bam::Record> = reader.records().map(|r| r.unwrap()).collect();
+    records[1].remove_aux(b"CB").unwrap();
+    records[1]
+        .push_aux(b"CB", bam::record::Aux::String("TTTTTTTTT"))
+        .unwrap();
+    create_bam_with_records(&corrupt_bam, "unsorted", &records).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["validate", "--input", corrupt_bam.to_str().unwrap()]);
+    cmd.assert()
+        .failure()
+        .stdout(predicates::str::contains("cb_mismatch\t1"))
+        .stdout(predicates::str::contains(
+            "example\tcb_mismatch\tuuid2_AAA-CCC-GGG_TTA\tCB 'TTTTTTTTT' but read name gives 'AAACCCGGG'",
+        ))
+        .stderr(predicates::str::contains("Found 1 tag violations"));
+}
+
+#[test]
+fn skip_reads_with_existing_tags() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    // Create a BAM with one read
+    let read_name = "uuid_AAA-BBB-CCC_UUU";
+
+    // Create header
+    let mut header = bam::Header::new();
+    let mut header_rec = bam::header::HeaderRecord::new(b"HD");
+    header_rec.push_tag(b"VN", "1.6");
+    header_rec.push_tag(b"SO", "unsorted");
+    header.push_record(&header_rec);
+
+    let mut ref_rec = bam::header::HeaderRecord::new(b"SQ");
+    ref_rec.push_tag(b"SN", "chr1");
+    ref_rec.push_tag(b"LN", "1000");
+    header.push_record(&ref_rec);
+
+    let mut writer = bam::Writer::from_path(&input_bam, &header, bam::Format::Bam).unwrap();
+
+    // Create record with existing CB tag
+    let mut record = bam::Record::new();
+    record.set(read_name.as_bytes(), None, b"ACGT", b"IIII");
+    record.set_pos(0);
+    record.set_tid(0);
+
+    // Add an existing CB tag
+    record
+        .push_aux(b"CB", bam::record::Aux::String("EXISTING"))
+        .unwrap();
+
+    writer.write(&record).unwrap();
+    drop(writer);
+
+    // Run tagbam
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+
+    // Read output BAM and verify the existing tag was NOT changed
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let record = reader.records().next().unwrap().unwrap();
+
+    // CB tag should still be "EXISTING", not the parsed value
+    assert_eq!(
+        get_tag_string(&record, b"CB"),
+        Some("EXISTING".to_string()),
+        "Existing CB tag should be preserved"
+    );
+
+    // No other tags should have been added either
+    assert_eq!(get_tag_string(&record, b"UB"), None);
+}
+
+#[test]
+fn dry_run_previews_first_reads_without_writing() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let tsv_path = td.path().join("quals.tsv");
+
+    create_test_bam(
+        &input_bam,
+        &[
+            "uuid1_AA-CC-GG_TTT",
+            "not_parseable",
+            "uuid3_AA-CC-GG_TTA",
+            "uuid4_AA-CC-GG_TTA",
+        ],
+    )
+    .unwrap();
+    std::fs::write(&tsv_path, "uuid1_AA-CC-GG_TTT\t123456\tXYZ\n").unwrap();
+    let before = std::fs::read(&input_bam).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--in-place",
+        "--bq-source",
+        tsv_path.to_str().unwrap(),
+        "--dry-run",
+        "--head",
+        "3",
+    ]);
+    cmd.assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "uuid1_AA-CC-GG_TTT\ttagged\tAACCGG\t123456\tTTT\tXYZ\thit\n",
+        ))
+        .stdout(predicates::str::contains(
+            "not_parseable\tunparseable\t-\t-\t-\t-\t-\n",
+        ))
+        .stdout(predicates::str::contains(
+            "uuid3_AA-CC-GG_TTA\ttagged\tAACCGG\tIIIIII\tTTA\tIII\tmiss\n",
+        ))
+        .stdout(predicates::str::contains("uuid4").not())
+        .stderr(predicates::str::contains(
+            "2 of 3 previewed reads (66.7%) have parseable names",
+        ));
+
+    assert_eq!(std::fs::read(&input_bam).unwrap(), before);
+    assert_eq!(std::fs::read_dir(td.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn in_place_mode() {
+    let td = TempDir::new().unwrap();
+    let bam_file = td.path().join("test.bam");
+
+    // Create test BAM
+    let read_name = "uuid_AAA-BBB-CCC_UUU";
+    create_test_bam(&bam_file, &[read_name]).unwrap();
+
+    // Get original modification time to verify file was replaced
+    let original_metadata = std::fs::metadata(&bam_file).unwrap();
+
+    // Run tagbam with --in-place
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["--input", bam_file.to_str().unwrap(), "--in-place"]);
+    cmd.assert().success();
+
+    // Read the modified BAM (same file)
+    let mut reader = bam::Reader::from_path(&bam_file).unwrap();
+    let record = reader.records().next().unwrap().unwrap();
+
+    // Verify tags were added
+    assert_eq!(
+        get_tag_string(&record, b"CB"),
+        Some("AAABBBCCC".to_string())
+    );
+    assert_eq!(get_tag_string(&record, b"UB"), Some("UUU".to_string()));
+
+    // Verify the file was actually modified (metadata changed)
+    let new_metadata = std::fs::metadata(&bam_file).unwrap();
+    // On some systems modification time might be the same if the operation is very fast,
+    // but the file size should be different due to added tags
+    assert!(
+        new_metadata.len() >= original_metadata.len(),
+        "File should have been modified in-place"
+    );
+}
+
+#[test]
+fn in_place_keeps_backup() {
+    let td = TempDir::new().unwrap();
+    let bam_file = td.path().join("test.bam");
+    create_test_bam(&bam_file, &["uuid_AAA-BBB-CCC_UUU"]).unwrap();
+    let original = std::fs::read(&bam_file).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        bam_file.to_str().unwrap(),
+        "--in-place",
+        "--backup",
+        ".orig",
+    ]);
+    cmd.assert().success();
+
+    let backup = td.path().join("test.bam.orig");
+    assert_eq!(std::fs::read(&backup).unwrap(), original);
+    let mut reader = bam::Reader::from_path(&bam_file).unwrap();
+    let record = reader.records().next().unwrap().unwrap();
+    assert_eq!(
+        get_tag_string(&record, b"CB"),
+        Some("AAABBBCCC".to_string())
+    );
+
+    // An existing backup is never overwritten
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        bam_file.to_str().unwrap(),
+        "--in-place",
+        "--backup",
+        ".orig",
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("already exists"));
+    assert_eq!(std::fs::read(&backup).unwrap(), original);
+}
+
+#[test]
+fn in_place_refuses_symlink_unless_forced() {
+    let td = TempDir::new().unwrap();
+    let target = td.path().join("target.bam");
+    create_test_bam(&target, &["uuid_AAA-BBB-CCC_UUU"]).unwrap();
+    let link = td.path().join("link.bam");
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["--input", link.to_str().unwrap(), "--in-place"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("is a symlink"));
+    assert!(std::fs::symlink_metadata(&link)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["--input", link.to_str().unwrap(), "--in-place", "--force"]);
+    cmd.assert().success();
+    let mut reader = bam::Reader::from_path(&link).unwrap();
+    let record = reader.records().next().unwrap().unwrap();
+    assert_eq!(get_tag_string(&record, b"UB"), Some("UUU".to_string()));
+}
+
+#[test]
+fn failed_in_place_run_removes_temp_file() {
+    let td = TempDir::new().unwrap();
+    let bam_file = td.path().join("test.bam");
+    create_test_bam(&bam_file, &["uuid_AAA-BBB-CCC_UUU", "not_parseable"]).unwrap();
+    let original = std::fs::read(&bam_file).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["--input", bam_file.to_str().unwrap(), "--in-place"]);
+    cmd.assert().failure();
+
+    assert!(!td.path().join(".test.bam.tmp").exists());
+    assert_eq!(std::fs::read(&bam_file).unwrap(), original);
+}
+
+#[test]
+fn requires_output_or_in_place() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+
+    // Create test BAM
+    create_test_bam(&input_bam, &["uuid_AAA-BBB-CCC_UUU"]).unwrap();
+
+    // Run tagbam without --output or --in-place
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["--input", input_bam.to_str().unwrap()]);
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("Either --output or --in-place"));
+}
+
+#[test]
+fn read_structure_uses_record_qualities() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    let mut record = bam::Record::new();
+    record.set(
+        b"uuid_AA-CC-ACG_TT",
+        None,
+        b"ACGTTTGGGG",
+        &[40, 30, 20, 0, 10, 11, 2, 2, 2, 2],
+    );
+    record.set_tid(0);
+    record.set_pos(0);
+    create_bam_with_records(&input_bam, "unsorted", &[record]).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--read-structure",
+        "3C1S2M+T",
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let record = reader.records().next().unwrap().unwrap();
+    assert_eq!(get_tag_string(&record, b"CB"), Some("AACCACG".to_string()));
+    assert_eq!(
+        get_tag_string(&record, b"CY"),
+        Some("IIIII?5".to_string()),
+        "i7/i5 keep perfect quality, CBC comes from the read"
+    );
+    assert_eq!(get_tag_string(&record, b"UY"), Some("+,".to_string()));
+    assert_eq!(
+        record.seq_len(),
+        10,
+        "Bases are kept without --trim-in-read"
+    );
+}
+
+#[test]
+fn trim_in_read_soft_clips_aligned_records() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    let cigar = bam::record::CigarString(vec![bam::record::Cigar::Match(10)]);
+    let mut record = bam::Record::new();
+    record.set(b"uuid_AA-CC-ACG_TT", Some(&cigar), b"ACGTTTGGGG", &[30; 10]);
+    record.unset_unmapped();
+    record.set_tid(0);
+    record.set_pos(100);
+    create_bam_with_records(&input_bam, "unsorted", &[record]).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--read-structure",
+        "3C1S2M+T",
+        "--trim-in-read",
+        "soft-clip",
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let record = reader.records().next().unwrap().unwrap();
+    assert_eq!(record.cigar().to_string(), "6S4M");
+    assert_eq!(record.pos(), 106);
+    assert_eq!(record.seq_len(), 10);
+    assert_eq!(get_tag_string(&record, b"UB"), Some("TT".to_string()));
+}
+
+#[test]
+fn trim_in_read_soft_clip_drops_coordinate_sort() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    // Clipping moves the forward read past the reverse one
+    let cigar = bam::record::CigarString(vec![bam::record::Cigar::Match(10)]);
+    let records: Vec<bam::Record> = [(100, false), (103, true)]
+        .iter()
+        .map(|&(pos, reverse)| {
+            let mut record = bam::Record::new();
+            let name = format!("r{}_AA-CC-ACG_TT", pos);
+            record.set(name.as_bytes(), Some(&cigar), b"ACGTTTGGGG", &[30; 10]);
+            record.unset_unmapped();
+            record.set_tid(0);
+            record.set_pos(pos);
+            if reverse {
+                record.set_reverse();
+            }
+            record
+        })
+        .collect();
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+    bam::index::build(&input_bam, None, bam::index::Type::Bai, 1).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--read-structure",
+        "3C1S2M+T",
+        "--trim-in-read",
+        "soft-clip",
+        "--index",
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("not coordinate-sorted"));
+    assert!(!output_bam.exists());
+
+    // In place, the header no longer claims SO:coordinate and the input's index is dropped
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--in-place",
+        "--read-structure",
+        "3C1S2M+T",
+        "--trim-in-read",
+        "soft-clip",
+    ]);
+    cmd.assert()
+        .success()
+        .stderr(predicates::str::contains("Removed stale index"));
+    assert!(!td.path().join("input.bam.bai").exists());
+
+    let mut reader = bam::Reader::from_path(&input_bam).unwrap();
+    let header = String::from_utf8(reader.header().as_bytes().to_vec()).unwrap();
+    assert!(header.starts_with("@HD\tVN:1.6\tSO:unsorted\n"));
+    let positions: Vec<i64> = reader.records().map(|r| r.unwrap().pos()).collect();
+    assert_eq!(positions, [106, 103]);
+}
+
+#[test]
+fn read_structure_gives_mates_read_one_qualities_and_fixes_mate_fields() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    // Read 2 comes first, as read 1 may be far away in sorted input
+    let cigar = bam::record::CigarString(vec![bam::record::Cigar::Match(10)]);
+    let name = b"uuid_AA-CC-ACG_TT";
+    let mut read2 = bam::Record::new();
+    read2.set(name, Some(&cigar), b"ACGTTTGGGG", &[2; 10]);
+    read2.set_flags(0x1 | 0x10 | 0x80);
+    read2.set_tid(0);
+    read2.set_pos(200);
+    read2.set_mtid(0);
+    read2.set_mpos(100);
+    read2.set_insert_size(-110);
+    read2
+        .push_aux(b"MC", bam::record::Aux::String("10M"))
+        .unwrap();
+    let mut read1 = bam::Record::new();
+    read1.set(
+        name,
+        Some(&cigar),
+        b"ACGTTTGGGG",
+        &[40, 30, 20, 0, 10, 11, 2, 2, 2, 2],
+    );
+    read1.set_flags(0x1 | 0x20 | 0x40);
+    read1.set_tid(0);
+    read1.set_pos(100);
+    read1.set_mtid(0);
+    read1.set_mpos(200);
+    read1.set_insert_size(110);
+    create_bam_with_records(&input_bam, "unsorted", &[read2, read1]).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--read-structure",
+        "3C1S2M+T",
+        "--trim-in-read",
+        "soft-clip",
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let records: Vec<bam::Record> = reader.records().map(|r| r.unwrap()).collect();
+    for record in &records {
+        assert_eq!(get_tag_string(record, b"CY"), Some("IIIII?5".to_string()));
+        assert_eq!(get_tag_string(record, b"UY"), Some("+,".to_string()));
+    }
+    let (read2, read1) = (&records[0], &records[1]);
+    assert_eq!(read1.cigar().to_string(), "6S4M");
+    assert_eq!((read1.pos(), read1.insert_size()), (106, 104));
+    assert_eq!(
+        (read2.pos(), read2.cigar().to_string()),
+        (200, "10M".to_string())
+    );
+    assert_eq!((read2.mpos(), read2.insert_size()), (106, -104));
+    assert_eq!(get_tag_string(read2, b"MC"), Some("6S4M".to_string()));
+}
+
+#[test]
+fn trim_in_read_removes_bases_from_unaligned_records() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    let mut record = bam::Record::new();
+    record.set(b"uuid_AA-CC-ACG_TT", None, b"ACGTTTGGGG", &[30; 10]);
+    create_bam_with_records(&input_bam, "unsorted", &[record]).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--read-structure",
+        "3C1S2M+T",
+        "--trim-in-read",
+        "remove",
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let record = reader.records().next().unwrap().unwrap();
+    assert_eq!(record.seq().as_bytes(), b"GGGG".to_vec());
+    assert_eq!(record.qual(), &[30; 4]);
+    assert_eq!(get_tag_string(&record, b"CB"), Some("AACCACG".to_string()));
+}
+
+/// Helper to write a single-record FASTQ whose header carries BQ qualities
+fn write_bq_fastq(path: &Path, read_name: &str, cb_quals: [&str; 3], umi_qual: &str) {
+    let mut fq = File::create(path).unwrap();
+    writeln!(
+        fq,
+        "@{read_name} cell|BQ:i7:{};i5:{};CBC:{};UMI:{umi_qual}\nAAAA\n+\nIIII",
+        cb_quals[0], cb_quals[1], cb_quals[2]
+    )
+    .unwrap();
+}
+
+#[test]
+fn min_cb_qual_sets_qc_fail_flag() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let fastq_path = td.path().join("reads.fastq");
+
+    create_test_bam(
+        &input_bam,
+        &["uuid1_AAA-BBB-CCC_UUU", "uuid2_AAA-BBB-CCC_UUU"],
+    )
+    .unwrap();
+    let mut fq = File::create(&fastq_path).unwrap();
+    writeln!(
+        fq,
+        "@uuid1_AAA-BBB-CCC_UUU cell|BQ:i7:III;i5:III;CBC:I#I;UMI:III\nAAAA\n+\nIIII\n\
+         @uuid2_AAA-BBB-CCC_UUU cell|BQ:i7:III;i5:III;CBC:III;UMI:III\nAAAA\n+\nIIII"
+    )
+    .unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--fastq-bq",
+        fastq_path.to_str().unwrap(),
+        "--min-cb-qual",
+        "20",
+        "--qual-fail",
+        "qc-fail",
+    ]);
+    cmd.assert()
+        .success()
+        .stderr(predicates::str::contains("1 reads failed"));
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let records: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+    assert!(records[0].is_quality_check_failed());
+    assert_eq!(
+        get_tag_string(&records[0], b"CY"),
+        Some("IIIIIII#I".to_string())
+    );
+    assert!(!records[1].is_quality_check_failed());
+}
+
+#[test]
+fn min_umi_qual_rejects_to_separate_bam() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let rejected_bam = td.path().join("rejected.bam");
+    let fastq_path = td.path().join("reads.fastq");
+
+    let read_name = "uuid1_AAA-BBB-CCC_UUU";
+    create_test_bam(&input_bam, &[read_name, "uuid2_AAA-BBB-CCC_UUU"]).unwrap();
+    write_bq_fastq(&fastq_path, read_name, ["III", "III", "III"], "+++");
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--fastq-bq",
+        fastq_path.to_str().unwrap(),
+        "--min-umi-qual",
+        "20",
+        "--qual-metric",
+        "mean",
+        "--qual-fail",
+        "reject",
+        "--rejected",
+        rejected_bam.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let kept: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept[0].qname(), b"uuid2_AAA-BBB-CCC_UUU");
+
+    let mut reader = bam::Reader::from_path(&rejected_bam).unwrap();
+    let rejected: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(rejected.len(), 1);
+    assert_eq!(rejected[0].qname(), read_name.as_bytes());
+    assert_eq!(get_tag_string(&rejected[0], b"UY"), Some("+++".to_string()));
+}
+
+#[test]
+fn quality_filter_treats_mates_alike() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let rejected_bam = td.path().join("rejected.bam");
+
+    // Only read 1 carries the barcode bases; t1's are low quality
+    let mate = |name: &str, first: bool, qual: u8| {
+        let mut record = bam::Record::new();
+        record.set(name.as_bytes(), None, b"ACGTTTGGGG", &[qual; 10]);
+        record.set_flags(0x1 | 0x4 | 0x8 | if first { 0x40 } else { 0x80 });
+        record
+    };
+    let (t1, t2) = ("t1_AA-CC-ACG_TT", "t2_AA-CC-ACG_TT");
+    create_bam_with_records(
+        &input_bam,
+        "unsorted",
+        &[
+            mate(t1, false, 40),
+            mate(t2, true, 40),
+            mate(t2, false, 40),
+            mate(t1, true, 5),
+        ],
+    )
+    .unwrap();
+
+    let run = |action: &[&str]| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+        cmd.args([
+            "--input",
+            input_bam.to_str().unwrap(),
+            "--output",
+            output_bam.to_str().unwrap(),
+            "--read-structure",
+            "3C1S2M+T",
+            "--min-cb-qual",
+            "20",
+            "--qual-fail",
+        ]);
+        cmd.args(action);
+        cmd.assert().success();
+    };
+
+    run(&["reject", "--rejected", rejected_bam.to_str().unwrap()]);
+    assert_eq!(read_names(&output_bam), [t2, t2]);
+    assert_eq!(read_names(&rejected_bam), [t1, t1]);
+
+    run(&["untag"]);
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let tagged: Vec<_> = reader
+        .records()
+        .map(|r| {
+            let r = r.unwrap();
+            (
+                String::from_utf8(r.qname().to_vec()).unwrap(),
+                r.aux(b"CB").is_ok(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        tagged,
+        [
+            (t1.to_string(), false),
+            (t2.to_string(), true),
+            (t2.to_string(), true),
+            (t1.to_string(), false),
+        ]
+    );
+}
+
+#[test]
+fn correct_umis_within_cell_and_position() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    let records = [
+        mapped_record("r1_AA-CC-GG_AAAA", 10, false),
+        mapped_record("r2_AA-CC-GG_AAAA", 10, false),
+        mapped_record("r3_AA-CC-GG_AAAT", 10, false),
+        // Same UMI in another cell is a separate group
+        mapped_record("r4_TT-CC-GG_AAAT", 10, false),
+        // Same cell on the other strand is a separate group
+        mapped_record("r5_AA-CC-GG_AAAT", 10, true),
+        mapped_record("r6_AA-CC-GG_AAAT", 5000, false),
+    ];
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--correct-umis",
+    ]);
+    cmd.assert()
+        .success()
+        .stderr(predicates::str::contains("1 reads had their UMI corrected"));
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let records: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+    let names: Vec<_> = records.iter().map(|r| r.qname().to_vec()).collect();
+    assert_eq!(names[0], b"r1_AA-CC-GG_AAAA", "Record order is preserved");
+    assert_eq!(names[5], b"r6_AA-CC-GG_AAAT");
+
+    let umis: Vec<_> = records
+        .iter()
+        .map(|r| get_tag_string(r, b"UB").unwrap())
+        .collect();
+    assert_eq!(umis, ["AAAA", "AAAA", "AAAA", "AAAT", "AAAT", "AAAT"]);
+    assert_eq!(get_tag_string(&records[2], b"UR"), Some("AAAT".to_string()));
+    assert_eq!(get_tag_string(&records[0], b"UR"), Some("AAAA".to_string()));
+}
+
+#[test]
+fn correct_umis_requires_coordinate_sorted_input() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    create_test_bam(&input_bam, &["uuid_AAA-BBB-CCC_UUU"]).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--correct-umis",
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("coordinate-sorted"));
+}
+
+/// Helper to build one mate of a properly paired template on chr1
+fn paired_record(read_name: &str, pos: i64, mate_pos: i64, first: bool) -> bam::Record {
+    let mut record = mapped_record(read_name, pos, !first);
+    record.set_paired();
+    record.set_mtid(0);
+    record.set_mpos(mate_pos);
+    if first {
+        record.set_first_in_template();
+        record.set_mate_reverse();
+    } else {
+        record.set_last_in_template();
+    }
+    record
+}
+
+#[test]
+fn mark_duplicates_by_cell_umi_and_position() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    let records = [
+        mapped_record("s1_AA-CC-GG_AAAA", 10, false),
+        paired_record("p1_AA-CC-GG_TTTT", 10, 100, true),
+        mapped_record("s2_AA-CC-GG_AAAA", 10, false),
+        paired_record("p2_AA-CC-GG_TTTT", 10, 100, true),
+        mapped_record("s3_AA-CC-GG_CCCC", 10, false),
+        mapped_record("s4_AA-CC-GG_AAAA", 10, true),
+        paired_record("p1_AA-CC-GG_TTTT", 100, 10, false),
+        paired_record("p2_AA-CC-GG_TTTT", 100, 10, false),
+    ];
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--mark-duplicates",
+    ]);
+    cmd.assert()
+        .success()
+        .stderr(predicates::str::contains("3 of 8 reads"));
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let flags: Vec<_> = reader
+        .records()
+        .map(|r| r.unwrap().is_duplicate())
+        .collect();
+    assert_eq!(
+        flags,
+        [false, false, true, true, false, false, false, true],
+        "Second copy of each molecule (and its mate) is marked"
+    );
+}
+
+#[test]
+fn mark_duplicates_clears_stale_flags() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    // Every record arrives flagged by an earlier run
+    let records: Vec<bam::Record> = ["s1_AA-CC-GG_AAAA", "s2_AA-CC-GG_AAAA", "s3_TT-CC-GG_AAAA"]
+        .iter()
+        .map(|name| {
+            let mut record = mapped_record(name, 10, false);
+            record.set_duplicate();
+            record
+        })
+        .collect();
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--mark-duplicates",
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let flags: Vec<_> = reader
+        .records()
+        .map(|r| r.unwrap().is_duplicate())
+        .collect();
+    assert_eq!(flags, [false, true, false]);
+}
+
+#[test]
+fn mark_duplicates_tag_mode_writes_stats() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let stats = td.path().join("dups.tsv");
+
+    let records = [
+        mapped_record("s1_AA-CC-GG_AAAA", 10, false),
+        mapped_record("s2_AA-CC-GG_AAAA", 10, false),
+        mapped_record("s3_TT-CC-GG_AAAA", 10, false),
+    ];
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--mark-duplicates",
+        "tag",
+        "--duplicate-stats",
+        stats.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let records: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+    assert!(records.iter().all(|r| !r.is_duplicate()));
+    let tags: Vec<_> = records
+        .iter()
+        .map(|r| match r.aux(b"xd") {
+            Ok(bam::record::Aux::U8(v)) => v,
+            other => panic!("unexpected xd tag: {:?}", other),
+        })
+        .collect();
+    assert_eq!(tags, [0, 1, 0]);
+
+    let stats = std::fs::read_to_string(&stats).unwrap();
+    assert_eq!(
+        stats,
+        "cell\treads\tduplicates\tduplication_rate\n\
+         AACCGG\t2\t1\t0.5000\n\
+         TTCCGG\t1\t0\t0.0000\n"
+    );
+}
+
+#[test]
+fn assign_mi_groups_templates_and_mates() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    let records = [
+        paired_record("p1_AA-CC-GG_TTTT", 10, 100, true),
+        paired_record("p2_AA-CC-GG_TTTT", 10, 100, true),
+        paired_record("p3_AA-CC-GG_GGGG", 10, 100, true),
+        mapped_record("s1_AA-CC-GG_TTTT", 10, false),
+        paired_record("p1_AA-CC-GG_TTTT", 100, 10, false),
+        paired_record("p2_AA-CC-GG_TTTT", 100, 10, false),
+        paired_record("p3_AA-CC-GG_GGGG", 100, 10, false),
+    ];
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--assign-mi",
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let mi: Vec<_> = reader
+        .records()
+        .map(|r| get_tag_string(&r.unwrap(), b"MI").unwrap())
+        .collect();
+    assert_eq!(mi, ["0", "0", "1", "2", "0", "0", "1"]);
+}
+
+#[test]
+fn assign_mi_covers_unmapped_mates() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    // Unmapped mates sit at their mapped mate's position, before or after it
+    let mapped = |name: &str, pos: i64, first: bool| {
+        let mut record = paired_record(name, pos, pos, first);
+        record.set_mate_unmapped();
+        record.unset_mate_reverse();
+        record
+    };
+    let unmapped = |name: &str, pos: i64, first: bool| {
+        let mut record = bam::Record::new();
+        record.set(name.as_bytes(), None, b"ACGT", &[30; 4]);
+        record.set_flags(if first {
+            0x1 | 0x4 | 0x40
+        } else {
+            0x1 | 0x4 | 0x80
+        });
+        record.set_tid(0);
+        record.set_pos(pos);
+        record.set_mtid(0);
+        record.set_mpos(pos);
+        record
+    };
+    let records = [
+        mapped("p1_AA-CC-GG_TTTT", 10, true),
+        unmapped("p1_AA-CC-GG_TTTT", 10, false),
+        unmapped("p2_AA-CC-GG_GGGG", 50, true),
+        mapped("p2_AA-CC-GG_GGGG", 50, false),
+    ];
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--assign-mi",
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let mi: Vec<_> = reader
+        .records()
+        .map(|r| get_tag_string(&r.unwrap(), b"MI"))
+        .collect();
+    assert_eq!(
+        mi,
+        [Some("0"), Some("0"), Some("1"), Some("1")].map(|mi| mi.map(str::to_string))
+    );
+}
+
+#[test]
+fn rename_gives_mates_identical_names() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+
+    let records = [
+        paired_record("p1_AA-CC-GG_TTTT", 10, 100, true),
+        paired_record("p2_AA-CC-GG_TTTT", 10, 100, true),
+        mapped_record("s1_AA-CC-GG_TTTT", 10, false),
+        paired_record("p1_AA-CC-GG_TTTT", 100, 10, false),
+        paired_record("p2_AA-CC-GG_TTTT", 100, 10, false),
+    ];
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+
+    let run = |mode: &str| {
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+        cmd.args([
+            "--input",
+            input_bam.to_str().unwrap(),
+            "--output",
+            output_bam.to_str().unwrap(),
+            "--assign-mi",
+            "--rename",
+            mode,
+        ]);
+        cmd.assert()
+            .success()
+            .stderr(predicates::str::contains("5 read names rewritten"));
+        let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+        reader
+            .records()
+            .map(|r| {
+                let r = r.unwrap();
+                (
+                    String::from_utf8(r.qname().to_vec()).unwrap(),
+                    get_tag_string(&r, b"MI").unwrap(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Molecules are still grouped by the original names
+    let expected = |names: [&str; 5]| {
+        names
+            .into_iter()
+            .zip(["0", "0", "1", "0", "0"])
+            .map(|(name, mi)| (name.to_string(), mi.to_string()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(run("uuid"), expected(["p1", "p2", "s1", "p1", "p2"]));
+    assert_eq!(run("counter"), expected(["1", "2", "3", "1", "2"]));
+}
+
+/// Helper to read all read names from a BAM
+fn read_names(path: &Path) -> Vec<String> {
+    let mut reader = bam::Reader::from_path(path).unwrap();
+    reader
+        .records()
+        .map(|r| String::from_utf8(r.unwrap().qname().to_vec()).unwrap())
+        .collect()
+}
+
+#[test]
+fn split_by_cell_barcode_with_allowlist() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let split_dir = td.path().join("cells");
+    let allowlist = td.path().join("allow.txt");
+
+    create_test_bam(
+        &input_bam,
+        &[
+            "r1_AA-CC-GG_UUU",
+            "r2_TT-CC-GG_UUU",
+            "r3_AA-CC-GG_UUU",
+            "r4_GG-CC-GG_UUU",
+            "r5_TT-CC-GG_UUU",
+            "r6_AA-CC-GG_UUU",
+        ],
+    )
+    .unwrap();
+    std::fs::write(&allowlist, "AACCGG\nTTCCGG\n").unwrap();
+
+    // A single open writer forces buckets to be reopened and merged
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--split-by",
+        "cb",
+        "--split-dir",
+        split_dir.to_str().unwrap(),
+        "--split-allowlist",
+        allowlist.to_str().unwrap(),
+        "--max-open-files",
+        "1",
+    ]);
+    cmd.assert().success();
+
+    assert_eq!(
+        read_names(&split_dir.join("AACCGG.bam")),
+        ["r1_AA-CC-GG_UUU", "r3_AA-CC-GG_UUU", "r6_AA-CC-GG_UUU"]
+    );
+    assert_eq!(
+        read_names(&split_dir.join("TTCCGG.bam")),
+        ["r2_TT-CC-GG_UUU", "r5_TT-CC-GG_UUU"]
+    );
+    assert_eq!(
+        read_names(&split_dir.join("other.bam")),
+        ["r4_GG-CC-GG_UUU"]
+    );
+
+    let mut files: Vec<_> = std::fs::read_dir(&split_dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        ["AACCGG.bam", "TTCCGG.bam", "other.bam"],
+        "Part files are merged and removed"
+    );
+}
+
+#[test]
+fn split_by_sample_index_pair() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let split_dir = td.path().join("samples");
+
+    create_test_bam(
+        &input_bam,
+        &["r1_AA-CC-GG_UUU", "r2_AA-TT-GG_UUU", "r3_AA-CC-TT_UUU"],
+    )
+    .unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--split-by",
+        "i7+i5",
+        "--split-dir",
+        split_dir.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+
+    assert_eq!(
+        read_names(&split_dir.join("AA-CC.bam")),
+        ["r1_AA-CC-GG_UUU", "r3_AA-CC-TT_UUU"]
+    );
+    assert_eq!(
+        read_names(&split_dir.join("AA-TT.bam")),
+        ["r2_AA-TT-GG_UUU"]
+    );
+}
+
+#[test]
+fn split_by_sample_index_uses_names_before_renaming() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let split_dir = td.path().join("samples");
+
+    create_test_bam(
+        &input_bam,
+        &["r1_AA-CC-GG_UUU", "r2_TT-CC-GG_UUU", "r3_AA-CC-TT_UUU"],
+    )
+    .unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--split-by",
+        "i7",
+        "--split-dir",
+        split_dir.to_str().unwrap(),
+        "--rename",
+        "counter",
+    ]);
+    cmd.assert().success();
+
+    assert_eq!(read_names(&split_dir.join("AA.bam")), ["1", "3"]);
+    assert_eq!(read_names(&split_dir.join("TT.bam")), ["2"]);
+    assert!(!split_dir.join("other.bam").exists());
+}
+
+#[test]
+fn sample_sheet_assigns_read_groups() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let sheet = td.path().join("samples.csv");
+
+    create_test_bam(
+        &input_bam,
+        &[
+            "r1_AACC-GGTT-CCC_UUU",
+            "r2_AACG-GGTT-CCC_UUU",
+            "r3_TTGG-GGTT-CCC_UUU",
+            "r4_CCCC-CCCC-CCC_UUU",
+        ],
+    )
+    .unwrap();
+    std::fs::write(
+        &sheet,
+        "sample,i7,i5,library\npoolA,AACC,GGTT,libA\npoolB,TTGG,GGTT,\n",
+    )
+    .unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--sample-sheet",
+        sheet.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+
+    let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+    let header = String::from_utf8(reader.header().as_bytes().to_vec()).unwrap();
+    assert!(header.contains("@RG\tID:poolA\tSM:poolA\tLB:libA"));
+    assert!(header.contains("@RG\tID:poolB\tSM:poolB"));
+
+    let groups: Vec<Option<String>> = reader
+        .records()
+        .map(|r| get_tag_string(&r.unwrap(), b"RG"))
+        .collect();
+    assert_eq!(
+        groups,
+        [
+            Some("poolA".to_string()),
+            Some("poolA".to_string()),
+            Some("poolB".to_string()),
+            None
+        ]
+    );
+}
+
+#[test]
+fn output_header_records_program_and_comments() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let first_bam = td.path().join("first.bam");
+    let second_bam = td.path().join("second.bam");
+    create_test_bam(&input_bam, &["r1_AA-CC-GG_UUU"]).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        first_bam.to_str().unwrap(),
+    ]);
+    cmd.assert().success();
+
+    // Retagging the output chains a second @PG to the first
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        first_bam.to_str().unwrap(),
+        "--output",
+        second_bam.to_str().unwrap(),
+        "--header-comments",
+    ]);
+    cmd.assert().success();
+
+    let reader = bam::Reader::from_path(&second_bam).unwrap();
+    let header = String::from_utf8(reader.header().as_bytes().to_vec()).unwrap();
+    let pg: Vec<&str> = header.lines().filter(|l| l.starts_with("@PG")).collect();
+    assert_eq!(pg.len(), 2);
+    assert!(pg[0].starts_with("@PG\tID:tagbam\tPN:tagbam\tVN:"));
+    assert!(pg[0].contains(&format!("--output {}", first_bam.to_str().unwrap())));
+    assert!(!pg[0].contains("\tPP:"));
+    assert!(pg[1].starts_with("@PG\tID:tagbam.1\tPN:tagbam"));
+    assert!(pg[1].contains("--header-comments"));
+    assert!(pg[1].ends_with("\tPP:tagbam"));
+    assert!(header.contains("@CO\ttagbam: CB:Z cell barcode"));
+    assert!(header.contains("from constant Q40 ('I')"));
+}
+
+/// Helper to fetch read names and CB tags overlapping `region` through the BAM index
+fn fetch_cell_barcodes(path: &Path, region: &str) -> Vec<(String, Option<String>)> {
+    let mut reader = bam::IndexedReader::from_path(path).unwrap();
+    reader.fetch(region).unwrap();
+    reader
+        .records()
+        .map(|r| {
+            let record = r.unwrap();
+            (
+                String::from_utf8(record.qname().to_vec()).unwrap(),
+                get_tag_string(&record, b"CB"),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn index_builds_bai_and_csi() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    create_bam_with_records(
+        &input_bam,
+        "coordinate",
+        &[
+            mapped_record("r1_AA-CC-GG_UUU", 10, false),
+            mapped_record("r2_TT-CC-GG_UUU", 500, false),
+        ],
+    )
+    .unwrap();
+
+    for (flag, extension) in [("--index", "bai"), ("--csi", "csi")] {
+        let output_bam = td.path().join(format!("{}.bam", extension));
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+        cmd.args([
+            "--input",
+            input_bam.to_str().unwrap(),
+            "--output",
+            output_bam.to_str().unwrap(),
+            flag,
+        ]);
+        cmd.assert().success();
+
+        assert!(td
+            .path()
+            .join(format!("{}.bam.{}", extension, extension))
+            .is_file());
+        assert_eq!(
+            fetch_cell_barcodes(&output_bam, "chr1:400-600"),
+            [("r2_TT-CC-GG_UUU".to_string(), Some("TTCCGG".to_string()))]
+        );
+    }
+}
+
+#[test]
+fn in_place_regenerates_existing_index() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let records: Vec<bam::Record> = (0..200)
+        .map(|i| mapped_record(&format!("r{}_AA-CC-GG_UUU", i), i * 4, false))
+        .collect();
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+    bam::index::build(&input_bam, None, bam::index::Type::Bai, 1).unwrap();
+    let index_path = td.path().join("input.bam.bai");
+    let stale_index = std::fs::read(&index_path).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args(["--input", input_bam.to_str().unwrap(), "--in-place"]);
+    cmd.assert().success();
+
+    // A stale index would point at offsets of the untagged file
+    assert_ne!(std::fs::read(&index_path).unwrap(), stale_index);
+    let fetched = fetch_cell_barcodes(&input_bam, "chr1:700-703");
+    assert_eq!(fetched.len(), 2);
+    assert!(fetched
+        .iter()
+        .all(|(_, cb)| cb.as_deref() == Some("AACCGG")));
+}
+
+#[test]
+fn index_requires_coordinate_sorted_input() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    create_test_bam(&input_bam, &["r1_AA-CC-GG_UUU"]).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--index",
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("coordinate-sorted"));
+}
+
+#[test]
+fn region_restricts_tagging_to_indexed_intervals() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    let bed = td.path().join("regions.bed");
+    let records: Vec<bam::Record> = [10, 100, 198, 300, 500, 900]
+        .iter()
+        .map(|&pos| mapped_record(&format!("r{}_AA-CC-GG_UUU", pos), pos, false))
+        .collect();
+    create_bam_with_records(&input_bam, "coordinate", &records).unwrap();
+    bam::index::build(&input_bam, None, bam::index::Type::Bai, 1).unwrap();
+    std::fs::write(&bed, "# spot check\nchr1\t850\t950\n").unwrap();
+
+    // Overlapping regions must not emit r198 twice
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--region",
+        "chr1:100-199",
+        "--region",
+        "chr1:201-301",
+        "--regions-bed",
+        bed.to_str().unwrap(),
+    ]);
+    cmd.assert()
+        .success()
+        .stderr(predicates::str::contains("Processed 4 reads: 4 tagged"));
+
+    assert_eq!(
+        read_names(&output_bam),
+        [
+            "r100_AA-CC-GG_UUU",
+            "r198_AA-CC-GG_UUU",
+            "r300_AA-CC-GG_UUU",
+            "r900_AA-CC-GG_UUU"
+        ]
+    );
+}
+
+#[test]
+fn region_requires_index() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let output_bam = td.path().join("output.bam");
+    create_test_bam(&input_bam, &["r1_AA-CC-GG_UUU"]).unwrap();
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+    cmd.args([
+        "--input",
+        input_bam.to_str().unwrap(),
+        "--output",
+        output_bam.to_str().unwrap(),
+        "--region",
+        "chr1",
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("require an indexed input BAM"));
+}
+
+#[test]
+fn tag_threads_preserve_record_order() {
+    let td = TempDir::new().unwrap();
+    let input_bam = td.path().join("input.bam");
+    let names: Vec<String> = (0..5000)
+        .map(|i| match i % 7 {
+            0 => format!("bad{}", i),
+            _ => format!("r{}_AA-CC-{:06}_UUU", i, i),
+        })
+        .collect();
+    let name_refs: Vec<&str> = names.iter().map(String::as_str).collect();
+    create_test_bam(&input_bam, &name_refs).unwrap();
+
+    let mut outputs = Vec::new();
+    for threads in ["1", "4"] {
+        let output_bam = td.path().join(format!("threads{}.bam", threads));
+        let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("tagbam"));
+        cmd.args([
+            "--input",
+            input_bam.to_str().unwrap(),
+            "--output",
+            output_bam.to_str().unwrap(),
+            "--skip-unparseable",
+            "--tag-threads",
+            threads,
+        ]);
+        cmd.assert().success().stderr(predicates::str::contains(
+            "Processed 5000 reads: 4285 tagged, 715 skipped",
+        ));
+
+        let mut reader = bam::Reader::from_path(&output_bam).unwrap();
+        let tagged: Vec<(String, Option<String>)> = reader
+            .records()
+            .map(|r| {
+                let record = r.unwrap();
+                (
+                    String::from_utf8(record.qname().to_vec()).unwrap(),
+                    get_tag_string(&record, b"CB"),
+                )
+            })
+            .collect();
+        outputs.push(tagged);
+    }
+
+    assert_eq!(outputs[0].len(), 5000);
+    assert_eq!(outputs[0], outputs[1]);
+    assert_eq!(
+        outputs[1][1],
+        (
+            "r1_AA-CC-000001_UUU".to_string(),
+            Some("AACC000001".to_string())
+        )
+    );
 }